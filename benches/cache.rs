@@ -1,12 +1,21 @@
-use std::cell::Cell;
+use std::{cell::Cell, hint::black_box};
 
-use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use criterion::{criterion_group, criterion_main, Criterion};
 use redu::{
     cache::{tests::*, Migrator},
     restic::Snapshot,
 };
 
 pub fn criterion_benchmark(c: &mut Criterion) {
+    c.bench_function("insert sizetree", |b| {
+        b.iter(|| generate_sizetree(black_box(6), black_box(12)));
+    });
+
+    c.bench_function("iter sizetree", |b| {
+        let sizetree = generate_sizetree(black_box(6), black_box(12));
+        b.iter(|| sizetree.iter().map(|(_, _, size, _)| size).sum::<usize>());
+    });
+
     c.bench_function("merge sizetree", |b| {
         let sizetree0 =
             Cell::new(generate_sizetree(black_box(6), black_box(12)));
@@ -1,9 +1,20 @@
-use clap::{ArgGroup, Parser};
+use std::collections::BTreeMap;
+
+use camino::Utf8PathBuf;
+use chrono::{
+    DateTime, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc,
+};
+use clap::{
+    error::ErrorKind, ArgGroup, CommandFactory, FromArgMatches, Parser,
+    Subcommand, ValueEnum,
+};
 use log::LevelFilter;
-use redu::restic::Repository;
+use redu::restic::{Repository, SnapshotFilter};
 use rpassword::read_password;
 
-use crate::restic::Password;
+use serde::Deserialize;
+
+use crate::{config::Profile, marks::ScriptOptions, restic::Password};
 
 #[derive(Debug)]
 pub struct Args {
@@ -13,20 +24,97 @@ pub struct Args {
     pub log_level: LevelFilter,
     pub no_cache: bool,
     pub non_interactive: bool,
+    pub incremental: bool,
+    pub unique_sizes: bool,
+    pub snapshot_filter: SnapshotFilter,
+    /// Generate the rewrite script instead of the exclude list
+    pub script: bool,
+    pub output_format: OutputFormat,
+    pub script_options: ScriptOptions,
+    pub command: Option<Command>,
 }
 
 impl Args {
     /// Parse arguments from env::args_os(), exit on error.
-    pub fn parse() -> Self {
-        let cli = Cli::parse();
+    /// `keybinds` are the lines of the keybinds section of the help.
+    pub fn parse(
+        keybinds: &[String],
+        profiles: &BTreeMap<String, Profile>,
+    ) -> Self {
+        let help = format!("Keybinds:\n{}", keybinds.join("\n"));
+        Cli::from_arg_matches(
+            &Cli::command().after_long_help(help).get_matches(),
+        )
+        .and_then(|cli| Self::from_cli(cli, profiles))
+        .unwrap_or_else(|e| e.exit())
+    }
+
+    /// The CLI arguments (and env variables) take precedence over the
+    /// values of the profile.
+    fn from_cli(
+        cli: Cli,
+        profiles: &BTreeMap<String, Profile>,
+    ) -> Result<Self, clap::Error> {
+        let error = |kind, msg: String| Cli::command().error(kind, msg);
+        let profile = match &cli.profile {
+            None => Profile::default(),
+            Some(name) => profiles.get(name).cloned().ok_or_else(|| {
+                let mut names =
+                    profiles.keys().map(String::as_str).collect::<Vec<_>>();
+                if names.is_empty() {
+                    names.push("none");
+                }
+                error(
+                    ErrorKind::InvalidValue,
+                    format!(
+                        "unknown profile {name:?} (available: {})",
+                        names.join(", ")
+                    ),
+                )
+            })?,
+        };
+        if profile.repo.is_some() && profile.repository_file.is_some() {
+            return Err(error(
+                ErrorKind::ArgumentConflict,
+                "the profile has both repo and repository_file".into(),
+            ));
+        }
+        let parse_profile_time =
+            |time: Option<String>, parse: fn(&str) -> _| {
+                time.map(|time| parse(&time))
+                    .transpose()
+                    .map_err(|e| error(ErrorKind::InvalidValue, e))
+            };
+        let profile_since = parse_profile_time(profile.since, parse_since)?;
+        let profile_until = parse_profile_time(profile.until, parse_until)?;
+        let or_profile = |cli: Vec<String>, profile: Vec<String>| {
+            if cli.is_empty() {
+                profile
+            } else {
+                cli
+            }
+        };
+        let output_format = cli
+            .output_format
+            .or(profile.output_format)
+            .unwrap_or(OutputFormat::Exclude);
 
-        Args {
+        Ok(Args {
             repository: if let Some(repo) = cli.repo {
                 Repository::Repo(repo)
             } else if let Some(file) = cli.repository_file {
                 Repository::File(file)
+            } else if let Some(repo) = profile.repo {
+                Repository::Repo(repo)
+            } else if let Some(file) = profile.repository_file {
+                Repository::File(file)
             } else {
-                unreachable!("Error in Config: neither repo nor repository_file found. Please open an issue if you see this.")
+                return Err(error(
+                    ErrorKind::MissingRequiredArgument,
+                    "one of --repo or --repository-file (or a --profile \
+                     with a repository) is required"
+                        .into(),
+                ));
             },
             password: if let Some(command) = cli.password_command {
                 Password::Command(command)
@@ -34,18 +122,43 @@ impl Args {
                 Password::File(file)
             } else if let Some(str) = cli.restic_password {
                 Password::Plain(str)
+            } else if let Some(command) = profile.password_command {
+                Password::Command(command)
+            } else if let Some(file) = profile.password_file {
+                Password::File(file)
             } else {
                 Password::Plain(Self::read_password_from_stdin())
             },
-            parallelism: cli.parallelism,
+            parallelism: cli.parallelism.or(profile.parallelism).unwrap_or(4),
             log_level: match cli.verbose {
                 0 => LevelFilter::Info,
                 1 => LevelFilter::Debug,
                 _ => LevelFilter::Trace,
             },
-            no_cache: cli.no_cache,
+            no_cache: cli.no_cache.unwrap_or(profile.no_cache),
             non_interactive: cli.non_interactive,
-        }
+            incremental: cli.incremental,
+            unique_sizes: cli.unique_sizes,
+            snapshot_filter: SnapshotFilter {
+                hosts: or_profile(cli.hosts, profile.hosts),
+                tags: or_profile(cli.tags, profile.tags)
+                    .iter()
+                    .map(|tags| tags.split(',').map(String::from).collect())
+                    .collect(),
+                paths: or_profile(cli.paths, profile.paths),
+                since: cli.since.or(profile_since),
+                until: cli.until.or(profile_until),
+            },
+            script: cli.script,
+            output_format,
+            script_options: ScriptOptions {
+                case_insensitive: output_format == OutputFormat::Iexclude,
+                exclude_file: cli.script_exclude_file,
+                forget: cli.forget,
+                prune: cli.prune,
+            },
+            command: cli.command,
+        })
     }
 
     fn read_password_from_stdin() -> String {
@@ -65,6 +178,8 @@ impl Args {
 /// When you're happy with the marks you can generate
 /// a list to stdout with everything that you marked.
 ///   This list can be used directly as an exclude-file for restic.
+///   Or you can generate a shell script that writes that exclude-file
+/// and runs restic rewrite on the snapshots that need it.
 ///
 /// Redu keeps all messages and UI in stderr,
 /// only the marks list is generated to stdout.
@@ -74,24 +189,24 @@ impl Args {
 /// NOTE: redu will never do any kind of modification to your repo.
 /// It's strictly read-only.
 ///
-/// Keybinds:
-/// Arrows or hjkl: Movement
-/// PgUp/PgDown or C-b/C-f: Page up / Page down
-/// Enter: Details
-/// Escape: Close dialog
-/// m: Mark
-/// u: Unmark
-/// c: Clear all marks
-/// g: Generate
-/// q: Quit
+/// The keybinds are listed at the end, they can be changed in the
+/// [keybindings] section of config.toml in the config directory of redu,
+/// e.g. unmark_all = "ctrl-x" or down = ["down", "j"].
 #[derive(Parser)]
 #[command(version, long_about, verbatim_doc_comment)]
 #[command(group(
     ArgGroup::new("repository")
-        .required(true)
         .args(["repo", "repository_file"]),
 ))]
 struct Cli {
+    /// Take the repository, password and defaults for the other options
+    /// from this profile of the config file.
+    ///
+    /// The options and the RESTIC_* env variables take precedence
+    /// over the profile.
+    #[arg(long, value_name = "NAME")]
+    profile: Option<String>,
+
     #[arg(short = 'r', long, env = "RESTIC_REPOSITORY")]
     repo: Option<String>,
 
@@ -109,9 +224,13 @@ struct Cli {
 
     ///  How many restic subprocesses to spawn concurrently.
     ///
+    /// Each restic process loads the index of the repository, so its memory
+    /// grows with the size of the repository rather than of the snapshot.
     /// If you get ssh-related errors or too much memory use try lowering this.
-    #[arg(short = 'j', value_name = "NUMBER", default_value_t = 4)]
-    parallelism: usize,
+    ///
+    /// Default: 4.
+    #[arg(short = 'j', value_name = "NUMBER")]
+    parallelism: Option<usize>,
 
     /// Log verbosity level. You can pass it multiple times (maxes out at two).
     #[arg(
@@ -121,10 +240,433 @@ struct Cli {
     verbose: u8,
 
     /// Pass the --no-cache option to restic subprocesses.
-    #[arg(long)]
-    no_cache: bool,
+    ///
+    /// --no-cache=false turns it off when the profile turns it on.
+    #[arg(
+        long,
+        value_name = "BOOL",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    no_cache: Option<bool>,
 
     /// Run redu only to update the cache, without any UI and without requiring a terminal. Exits when done.
     #[arg(long)]
     non_interactive: bool,
+
+    /// Fetch new snapshots by walking the restic tree objects and reusing
+    /// the subtrees that are unchanged from an already cached snapshot
+    /// (usually the parent).
+    ///
+    /// Each changed directory is a separate restic process that loads
+    /// the repository index, so this only helps when few directories
+    /// changed. Snapshots without a cached parent are still fetched in full.
+    #[arg(long)]
+    incremental: bool,
+
+    /// Also compute the unique size of every file/directory: how much
+    /// space removing it from all snapshots would actually free in the
+    /// deduplicated repository.
+    ///
+    /// This starts a restic process for every index file and for every
+    /// distinct version of every directory in the repository, and each of
+    /// them loads the repository index. On repositories with millions of
+    /// files and many snapshots this can take many hours.
+    /// It is only recomputed when the snapshots change.
+    #[arg(long)]
+    unique_sizes: bool,
+
+    /// Only show snapshots from this host (can be passed multiple times).
+    ///
+    /// This and the other snapshot filters only change what is shown,
+    /// the cache is always synced with all the snapshots in the repository.
+    #[arg(long = "host", value_name = "HOST")]
+    hosts: Vec<String>,
+
+    /// Only show snapshots that have all of these tags
+    /// (can be passed multiple times).
+    #[arg(long = "tag", value_name = "TAG[,TAG,...]")]
+    tags: Vec<String>,
+
+    /// Only show snapshots that include this (absolute) path
+    /// (can be passed multiple times).
+    #[arg(long = "path", value_name = "PATH")]
+    paths: Vec<String>,
+
+    /// Only show snapshots taken at or after this time
+    /// (YYYY-MM-DD or YYYY-MM-DD HH:MM:SS, in local time).
+    #[arg(long, value_name = "TIME", value_parser = parse_since)]
+    since: Option<DateTime<Utc>>,
+
+    /// Only show snapshots taken at or before this time
+    /// (YYYY-MM-DD or YYYY-MM-DD HH:MM:SS, in local time).
+    #[arg(long, value_name = "TIME", value_parser = parse_until)]
+    until: Option<DateTime<Utc>>,
+
+    /// The format of the generated marks list. Default: exclude.
+    #[arg(long, value_enum)]
+    output_format: Option<OutputFormat>,
+
+    /// Generate a shell script that writes the exclude file and runs
+    /// `restic rewrite` on the snapshots that have something that the marks
+    /// match, instead of the exclude list (the same as pressing G).
+    ///
+    /// The exclude file is a restic (case insensitive with
+    /// --output-format=iexclude) exclude file.
+    ///
+    /// redu doesn't run it, review it and run it yourself.
+    #[arg(long)]
+    script: bool,
+
+    /// Where the rewrite script writes the exclude file.
+    #[arg(long, value_name = "FILE", default_value = "redu-excludes.txt")]
+    script_exclude_file: Utf8PathBuf,
+
+    /// Make the rewrite script pass --forget to restic rewrite,
+    /// so that the original snapshots are forgotten.
+    #[arg(long)]
+    forget: bool,
+
+    /// Make the rewrite script run restic prune at the end.
+    #[arg(long)]
+    prune: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Sync the cache and print the largest files/directories under a path
+    /// to stdout, without any UI (like du).
+    ///
+    /// The snapshot filters apply to the report as well.
+    Report {
+        /// Absolute path to report on. Defaults to the whole repository.
+        path: Option<Utf8PathBuf>,
+
+        /// How many entries to print.
+        #[arg(short = 'n', long, value_name = "NUMBER", default_value_t = 20)]
+        top: usize,
+
+        /// How many levels below the path to consider.
+        /// 1 only looks at its immediate children.
+        #[arg(
+            short = 'd',
+            long,
+            value_name = "NUMBER",
+            default_value_t = 1,
+            value_parser = clap::value_parser!(u32).range(1..),
+        )]
+        depth: u32,
+
+        /// Output format.
+        #[arg(long, value_enum, default_value_t = ReportFormat::Table)]
+        format: ReportFormat,
+
+        /// Only report the files last modified before this time
+        /// (YYYY-MM-DD or YYYY-MM-DD HH:MM:SS, in local time),
+        /// e.g. to find the large files that nobody touched in years.
+        ///
+        /// The files are looked for at any depth, --depth is ignored.
+        /// Directories are left out, and so are the files of the snapshots
+        /// cached before redu kept the modification times.
+        #[arg(long, value_name = "TIME", value_parser = parse_since)]
+        modified_before: Option<DateTime<Utc>>,
+    },
+    /// Sync the cache and print what changed between two snapshots
+    /// to stdout, largest change first.
+    ///
+    /// Only the directories that changed size are looked into.
+    Diff {
+        /// The older snapshot (id, prefix of the id or `latest`).
+        old: String,
+
+        /// The newer snapshot (id, prefix of the id or `latest`).
+        new: String,
+
+        /// Absolute path to compare. Defaults to the whole repository.
+        path: Option<Utf8PathBuf>,
+
+        /// How many entries to print.
+        #[arg(short = 'n', long, value_name = "NUMBER", default_value_t = 20)]
+        top: usize,
+
+        /// How many levels below the path to consider.
+        /// 1 only looks at its immediate children.
+        #[arg(
+            short = 'd',
+            long,
+            value_name = "NUMBER",
+            default_value_t = 1,
+            value_parser = clap::value_parser!(u32).range(1..),
+        )]
+        depth: u32,
+
+        /// Output format.
+        #[arg(long, value_enum, default_value_t = ReportFormat::Table)]
+        format: ReportFormat,
+    },
+    /// Sync the cache and print the largest files/directories anywhere
+    /// in the repository with a given name to stdout.
+    ///
+    /// The snapshot filters apply to the search as well.
+    Search {
+        /// Name to look for, or a glob (*, ?, [...]) matched against
+        /// the names, e.g. '*.iso' or 'node_modules'.
+        pattern: String,
+
+        /// How many entries to print.
+        #[arg(short = 'n', long, value_name = "NUMBER", default_value_t = 20)]
+        top: usize,
+
+        /// Output format.
+        #[arg(long, value_enum, default_value_t = ReportFormat::Table)]
+        format: ReportFormat,
+    },
+    /// Manage the marks without the UI.
+    Marks {
+        #[command(subcommand)]
+        command: MarksCommand,
+    },
+    /// Sync the cache and export it to stdout in the format of another tool.
+    Export {
+        /// Output format.
+        #[arg(long, value_enum, default_value_t = ExportFormat::Ncdu)]
+        format: ExportFormat,
+
+        /// Export this snapshot (or `latest`) instead of the largest size
+        /// of each file across all snapshots.
+        ///
+        /// `latest` takes the snapshot filters into account.
+        #[arg(long, value_name = "ID")]
+        snapshot: Option<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum MarksCommand {
+    /// Add the lines of a restic exclude file (as used with
+    /// `restic backup --exclude-file`) to the marks.
+    ///
+    /// Absolute paths without wildcards become normal marks,
+    /// everything else becomes a pattern mark.
+    /// The lines that match nothing in the cache are reported.
+    Import { file: Utf8PathBuf },
+    /// Check which snapshots still have something that each mark matches
+    /// (and so still need a `restic rewrite`) and which don't.
+    ///
+    /// The snapshots that don't are shown with the exclude they were made
+    /// with that covers the mark, if there is one.
+    /// The snapshot filters apply to the check as well.
+    Check {
+        /// Paths or restic exclude patterns to check instead of the marks,
+        /// absolute paths without wildcards are taken as paths.
+        marks: Vec<String>,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum ReportFormat {
+    /// Human readable, aligned columns
+    Table,
+    /// Tab separated values with a header and sizes in bytes
+    Tsv,
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// restic exclude file (--exclude-file)
+    Exclude,
+    /// restic case insensitive exclude file (--iexclude-file),
+    /// it can match other paths that only differ in case
+    Iexclude,
+    /// rsync exclude file (--exclude-from),
+    /// the absolute paths are anchored at the root of the transfer
+    Rsync,
+    /// borg patterns file (--patterns-from or --exclude-from)
+    Borg,
+    /// gitignore, the absolute paths are anchored at the .gitignore
+    Gitignore,
+    /// The raw paths separated by NULs (for xargs -0), the patterns
+    /// are replaced by the paths that they match in the cache
+    Nul,
+    /// JSON with the sizes of each mark, like in the marks summary
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum ExportFormat {
+    /// ncdu JSON export, can be loaded with `ncdu -f`
+    Ncdu,
+}
+
+fn parse_since(s: &str) -> Result<DateTime<Utc>, String> {
+    parse_time(s, NaiveTime::MIN)
+}
+
+fn parse_until(s: &str) -> Result<DateTime<Utc>, String> {
+    parse_time(s, NaiveTime::from_hms_micro_opt(23, 59, 59, 999_999).unwrap())
+}
+
+/// Parses a date or a datetime in local time.
+/// Plain dates get `time_of_day` as their time.
+fn parse_time(
+    s: &str,
+    time_of_day: NaiveTime,
+) -> Result<DateTime<Utc>, String> {
+    let naive = match NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        Ok(date) => date.and_time(time_of_day),
+        Err(_) => ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M"]
+            .iter()
+            .find_map(|fmt| NaiveDateTime::parse_from_str(s, fmt).ok())
+            .ok_or_else(|| {
+                format!(
+                    "invalid time {s:?}, expected YYYY-MM-DD or YYYY-MM-DD HH:MM:SS"
+                )
+            })?,
+    };
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .map(|datetime| datetime.to_utc())
+        .ok_or_else(|| format!("{s:?} does not exist in the local timezone"))
+}
+
+/// Tests //////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use std::iter;
+
+    use super::*;
+
+    fn parse(
+        cli: &[&str],
+        profiles: &[(&str, Profile)],
+    ) -> Result<Args, clap::Error> {
+        let cli =
+            Cli::try_parse_from(iter::once("redu").chain(cli.iter().copied()))?;
+        let profiles = profiles
+            .iter()
+            .map(|(name, profile)| (name.to_string(), profile.clone()))
+            .collect();
+        Args::from_cli(cli, &profiles)
+    }
+
+    #[test]
+    fn profile_test() {
+        let work = Profile {
+            repo: Some("sftp:work".into()),
+            password_command: Some("pass work".into()),
+            parallelism: Some(2),
+            no_cache: true,
+            hosts: vec!["laptop".into()],
+            tags: vec!["a,b".into()],
+            since: Some("2024-01-01".into()),
+            output_format: Some(OutputFormat::Json),
+            ..Profile::default()
+        };
+        let profiles = [("work", work)];
+
+        let args = parse(&["--profile", "work"], &profiles).unwrap();
+        assert_eq!(args.repository, Repository::Repo("sftp:work".into()));
+        assert_eq!(args.password, Password::Command("pass work".into()));
+        assert_eq!(args.parallelism, 2);
+        assert!(args.no_cache);
+        assert_eq!(args.snapshot_filter.hosts, vec!["laptop"]);
+        assert_eq!(args.snapshot_filter.tags, vec![vec!["a", "b"]]);
+        assert!(args.snapshot_filter.since.is_some());
+        assert_eq!(args.output_format, OutputFormat::Json);
+    }
+
+    #[test]
+    fn profile_override_test() {
+        let work = Profile {
+            repo: Some("sftp:work".into()),
+            password_file: Some("/work".into()),
+            parallelism: Some(2),
+            hosts: vec!["laptop".into()],
+            ..Profile::default()
+        };
+        let profiles = [("work", work)];
+
+        let args = parse(
+            &[
+                "--profile",
+                "work",
+                "--repository-file",
+                "/repo",
+                "--password-command",
+                "pass",
+                "-j",
+                "8",
+                "--host",
+                "server",
+                "--output-format",
+                "rsync",
+            ],
+            &profiles,
+        )
+        .unwrap();
+        assert_eq!(args.repository, Repository::File("/repo".into()));
+        assert_eq!(args.password, Password::Command("pass".into()));
+        assert_eq!(args.parallelism, 8);
+        assert_eq!(args.snapshot_filter.hosts, vec!["server"]);
+        assert_eq!(args.output_format, OutputFormat::Rsync);
+
+        let args = parse(&["-r", "/repo", "--password-file", "/pw"], &profiles)
+            .unwrap();
+        assert_eq!(args.parallelism, 4);
+        assert!(args.snapshot_filter.is_empty());
+        assert_eq!(args.output_format, OutputFormat::Exclude);
+
+        let profiles =
+            [("work", Profile { no_cache: true, ..profiles[0].1.clone() })];
+        let no_cache = |cli: &[&str]| {
+            let cli = [&["--profile", "work"], cli].concat();
+            parse(&cli, &profiles).unwrap().no_cache
+        };
+        assert!(no_cache(&[]));
+        assert!(no_cache(&["--no-cache"]));
+        assert!(!no_cache(&["--no-cache=false"]));
+        assert!(
+            !parse(&["-r", "/repo", "--password-file", "/pw"], &profiles)
+                .unwrap()
+                .no_cache
+        );
+    }
+
+    #[test]
+    fn profile_errors_test() {
+        let both = Profile {
+            repo: Some("a".into()),
+            repository_file: Some("b".into()),
+            password_file: Some("/pw".into()),
+            ..Profile::default()
+        };
+        let bad_time = Profile {
+            repo: Some("a".into()),
+            password_file: Some("/pw".into()),
+            since: Some("yesterday".into()),
+            ..Profile::default()
+        };
+        let profiles = [("both", both), ("bad_time", bad_time)];
+        let kind = |cli: &[&str]| parse(cli, &profiles).err().map(|e| e.kind());
+        assert_eq!(kind(&["--profile", "nope"]), Some(ErrorKind::InvalidValue));
+        assert_eq!(
+            kind(&["--profile", "both"]),
+            Some(ErrorKind::ArgumentConflict)
+        );
+        assert_eq!(
+            kind(&["--profile", "bad_time"]),
+            Some(ErrorKind::InvalidValue)
+        );
+        assert_eq!(
+            kind(&["--password-file", "/pw"]),
+            Some(ErrorKind::MissingRequiredArgument)
+        );
+    }
 }
@@ -1,6 +1,6 @@
 use std::{
-    cmp::{max, Reverse},
-    collections::{HashMap, HashSet},
+    cmp::Reverse,
+    collections::{HashMap, HashSet, VecDeque},
     path::Path,
 };
 
@@ -9,15 +9,26 @@ use chrono::{DateTime, Utc};
 use log::trace;
 use rusqlite::{
     functions::FunctionFlags,
-    params,
+    params, params_from_iter,
     trace::{TraceEvent, TraceEventCodes},
-    types::FromSqlError,
-    Connection, OptionalExtension,
+    types::{FromSqlError, Value},
+    Connection, OptionalExtension, Row, Statement,
 };
 use thiserror::Error;
 
-use crate::{cache::filetree::SizeTree, restic::Snapshot};
+#[cfg(any(test, feature = "bench"))]
+use crate::cache::filetree::SizeTree;
+use crate::{
+    glob::{is_glob, ExcludePattern, Glob},
+    restic::{File, FileMetadata, FileType, Snapshot},
+};
 
+type BoxError = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+pub mod dedup;
+/// The sync writes the fetched files straight into the cache,
+/// this tree is only used by the tests and benchmarks to build snapshots.
+#[cfg(any(test, feature = "bench"))]
 pub mod filetree;
 #[cfg(any(test, feature = "bench"))]
 pub mod tests;
@@ -25,6 +36,9 @@ pub mod tests;
 #[derive(Debug)]
 pub struct Cache {
     conn: Connection,
+    /// The snapshots that `get_entries` and `get_entry_details` look at.
+    /// `None` means all of them.
+    selected_snapshots: Option<Vec<String>>,
 }
 
 #[derive(Error, Debug)]
@@ -99,9 +113,22 @@ impl Cache {
             .optional()
     }
 
+    /// Opens another connection to the same cache file.
+    /// Each thread that wants to read from the cache needs its own.
+    pub fn reopen(&self) -> Result<Cache, rusqlite::Error> {
+        let file = self
+            .conn
+            .path()
+            .expect("the cache connection is not backed by a file");
+        let mut cache = Cache {
+            conn: open_connection(Path::new(file))?,
+            selected_snapshots: None,
+        };
+        cache.select_snapshots(self.selected_snapshots.clone())?;
+        Ok(cache)
+    }
+
     /// This is not very efficient, it does one query per path component.
-    /// Mainly used for testing convenience.
-    #[cfg(any(test, feature = "bench"))]
     pub fn get_path_id_by_path(
         &self,
         path: &Utf8Path,
@@ -124,6 +151,66 @@ impl Cache {
         Ok(path_id)
     }
 
+    /// The full path of `path_id`.
+    pub fn get_path(
+        &self,
+        path_id: PathId,
+    ) -> Result<Utf8PathBuf, rusqlite::Error> {
+        let mut stmt = self.conn.prepare(
+            "WITH RECURSIVE ancestors (id, parent_id, component, depth) AS ( \
+                 SELECT id, parent_id, component, 0 \
+                 FROM paths WHERE id = ? \
+                 UNION ALL \
+                 SELECT paths.id, paths.parent_id, paths.component, depth + 1 \
+                 FROM paths JOIN ancestors ON paths.id = ancestors.parent_id \
+             ) \
+             SELECT component FROM ancestors ORDER BY depth DESC",
+        )?;
+        #[allow(clippy::let_and_return)]
+        let result = stmt
+            .query_map([path_id.0], |row| row.get::<_, String>("component"))?
+            .collect();
+        result
+    }
+
+    /// Looks for the files/directories anywhere in the (selected) snapshots
+    /// whose name is `pattern`, or matches it if it's a glob.
+    /// Returns the `limit` largest ones, largest first.
+    pub fn search(
+        &self,
+        pattern: &str,
+        limit: usize,
+    ) -> Result<Vec<SearchHit>, rusqlite::Error> {
+        let condition = if is_glob(pattern) {
+            "glob_match(?1, component)"
+        } else {
+            "component = ?1"
+        };
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT path_id, parent_id, max_size, is_dir \
+             FROM paths JOIN {} ON path_id = paths.id \
+             WHERE {condition} \
+             ORDER BY max_size DESC \
+             LIMIT ?2",
+            self.aggregate_table()
+        ))?;
+        let mut hits = stmt
+            .query_map(params![pattern, limit], |row| {
+                Ok(SearchHit {
+                    path_id: PathId(row.get("path_id")?),
+                    parent_id: raw_u64_to_o_path_id(row.get("parent_id")?),
+                    path: Utf8PathBuf::new(),
+                    size: row.get("max_size")?,
+                    is_dir: row.get("is_dir")?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        for hit in hits.iter_mut() {
+            hit.path = self.get_path(hit.path_id)?;
+        }
+        Ok(hits)
+    }
+
     fn entries_tables(
         &self,
     ) -> Result<impl Iterator<Item = String>, rusqlite::Error> {
@@ -132,227 +219,531 @@ impl Cache {
             .filter(|name| name.starts_with("entries_")))
     }
 
+    /// Restricts `get_entries`, `get_entry_details` and `search`
+    /// to the given snapshots. `None` goes back to using all snapshots.
+    /// The entries of the selected snapshots are aggregated here once,
+    /// so this needs to be called again if the snapshots change.
+    pub fn select_snapshots(
+        &mut self,
+        hashes: Option<Vec<String>>,
+    ) -> Result<(), rusqlite::Error> {
+        let tx = self.conn.transaction()?;
+        tx.execute("DROP TABLE IF EXISTS temp.selected_aggregate_entries", [])?;
+        if let Some(hashes) = &hashes {
+            tx.execute(
+                "CREATE TEMP TABLE selected_aggregate_entries ( \
+                     path_id INTEGER PRIMARY KEY, \
+                     is_dir INTEGER NOT NULL, \
+                     max_size INTEGER NOT NULL, \
+                     max_size_snapshot_hash TEXT NOT NULL, \
+                     max_size_time INTEGER NOT NULL, \
+                     first_seen INTEGER NOT NULL, \
+                     first_seen_snapshot_hash TEXT NOT NULL, \
+                     last_seen INTEGER NOT NULL, \
+                     last_seen_snapshot_hash TEXT NOT NULL \
+                 )",
+                [],
+            )?;
+            let hashes = hashes.iter().map(String::as_str).collect::<Vec<_>>();
+            aggregate_snapshot_entries(
+                &tx,
+                "selected_aggregate_entries",
+                &hashes,
+                false,
+            )?;
+        }
+        tx.commit()?;
+        self.selected_snapshots = hashes;
+        Ok(())
+    }
+
+    pub fn selected_snapshots(&self) -> Option<&[String]> {
+        self.selected_snapshots.as_deref()
+    }
+
+    /// The table with the aggregated entries of the (selected) snapshots.
+    fn aggregate_table(&self) -> &'static str {
+        if self.selected_snapshots.is_some() {
+            "selected_aggregate_entries"
+        } else {
+            "aggregate_entries"
+        }
+    }
+
     /// This returns the children files/directories of the given path.
     /// Each entry's size is the largest size of that file/directory across
-    /// all snapshots.
+    /// all (selected) snapshots.
     pub fn get_entries(
         &self,
         path_id: Option<PathId>,
     ) -> Result<Vec<Entry>, rusqlite::Error> {
-        let raw_path_id = o_path_id_to_raw_u64(path_id);
-        let mut entries: Vec<Entry> = Vec::new();
-        let mut index: HashMap<PathId, usize> = HashMap::new();
-        for table in self.entries_tables()? {
-            let stmt_str = format!(
-                "SELECT \
-                     path_id, \
-                     component, \
-                     size, \
-                     is_dir \
-                 FROM \"{table}\" JOIN paths ON path_id = paths.id \
-                 WHERE parent_id = {raw_path_id}\n",
-            );
-            let mut stmt = self.conn.prepare(&stmt_str)?;
-            let rows = stmt.query_map([], |row| {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT \
+                 path_id, \
+                 component, \
+                 max_size, \
+                 is_dir, \
+                 unique_sizes.size AS unique_size \
+             FROM {} \
+                 JOIN paths ON path_id = paths.id \
+                 LEFT JOIN unique_sizes USING (path_id) \
+             WHERE parent_id = ? \
+             ORDER BY max_size DESC",
+            self.aggregate_table()
+        ))?;
+        #[allow(clippy::let_and_return)]
+        let result = stmt
+            .query_map([o_path_id_to_raw_u64(path_id)], |row| {
                 Ok(Entry {
                     path_id: PathId(row.get("path_id")?),
                     component: row.get("component")?,
-                    size: row.get("size")?,
+                    size: row.get("max_size")?,
                     is_dir: row.get("is_dir")?,
+                    unique_size: row.get("unique_size")?,
                 })
-            })?;
-            for row in rows {
-                let row = row?;
-                let path_id = row.path_id;
-                match index.get(&path_id) {
-                    None => {
-                        entries.push(row);
-                        index.insert(path_id, entries.len() - 1);
-                    }
-                    Some(i) => {
-                        let entry = &mut entries[*i];
-                        entry.size = max(entry.size, row.size);
-                        entry.is_dir = entry.is_dir || row.is_dir;
-                    }
-                }
-            }
-        }
-        entries.sort_by_key(|e| Reverse(e.size));
-        Ok(entries)
+            })?
+            .collect();
+        result
     }
 
     pub fn get_entry_details(
         &self,
         path_id: PathId,
     ) -> Result<Option<EntryDetails>, Error> {
-        let raw_path_id = path_id.0;
-        let run_query = |table: &str| -> Result<
-            Option<(String, usize, DateTime<Utc>)>,
-            Error,
-        > {
-            let snapshot_hash = table.strip_prefix("entries_").unwrap();
-            let stmt_str = format!(
-                "SELECT \
-                     hash, \
-                     size, \
-                     time \
-                 FROM \"{table}\" \
-                     JOIN paths ON path_id = paths.id \
-                     JOIN snapshots ON hash = '{snapshot_hash}' \
-                 WHERE path_id = {raw_path_id}\n"
-            );
-            let mut stmt = self.conn.prepare(&stmt_str)?;
-            stmt.query_row([], |row| {
-                Ok((row.get("hash")?, row.get("size")?, row.get("time")?))
-            })
+        self.conn
+            .query_row(
+                &format!(
+                    "SELECT \
+                         max_size, \
+                         max_size_snapshot_hash, \
+                         first_seen, \
+                         first_seen_snapshot_hash, \
+                         last_seen, \
+                         last_seen_snapshot_hash, \
+                         unique_sizes.size AS unique_size \
+                     FROM {} LEFT JOIN unique_sizes USING (path_id) \
+                     WHERE path_id = ?",
+                    self.aggregate_table()
+                ),
+                [path_id.0],
+                |row| {
+                    Ok((
+                        row.get("max_size")?,
+                        row.get("max_size_snapshot_hash")?,
+                        row.get("first_seen")?,
+                        row.get("first_seen_snapshot_hash")?,
+                        row.get("last_seen")?,
+                        row.get("last_seen_snapshot_hash")?,
+                        row.get("unique_size")?,
+                    ))
+                },
+            )
             .optional()?
-            .map(|(hash, size, timestamp)| {
-                Ok((hash, size, timestamp_to_datetime(timestamp)?))
-            })
+            .map(
+                |(
+                    max_size,
+                    max_size_snapshot_hash,
+                    first_seen,
+                    first_seen_snapshot_hash,
+                    last_seen,
+                    last_seen_snapshot_hash,
+                    unique_size,
+                ): (_, _, _, _, _, String, _)| {
+                    Ok(EntryDetails {
+                        max_size,
+                        max_size_snapshot_hash,
+                        first_seen: timestamp_to_datetime(first_seen)?,
+                        first_seen_snapshot_hash,
+                        last_seen: timestamp_to_datetime(last_seen)?,
+                        metadata: self
+                            .get_metadata(&last_seen_snapshot_hash, path_id)?,
+                        last_seen_snapshot_hash,
+                        unique_size,
+                    })
+                },
+            )
             .transpose()
-        };
+    }
 
-        let mut entries_tables = self.entries_tables()?;
-        let mut details = loop {
-            match entries_tables.next() {
-                None => return Ok(None),
-                Some(table) => {
-                    if let Some((hash, size, time)) = run_query(&table)? {
-                        break EntryDetails {
-                            max_size: size,
-                            max_size_snapshot_hash: hash.clone(),
-                            first_seen: time,
-                            first_seen_snapshot_hash: hash.clone(),
-                            last_seen: time,
-                            last_seen_snapshot_hash: hash,
-                        };
-                    }
-                }
-            }
+    fn get_metadata(
+        &self,
+        snapshot_hash: &str,
+        path_id: PathId,
+    ) -> Result<FileMetadata, rusqlite::Error> {
+        Ok(self
+            .conn
+            .query_row(
+                &format!(
+                    "SELECT {} FROM \"entries_{snapshot_hash}\" \
+                     WHERE path_id = ?",
+                    metadata_column_names()
+                ),
+                [path_id.0],
+                metadata_from_row,
+            )
+            .optional()?
+            .unwrap_or_default())
+    }
+
+    /// The `limit` largest files anywhere under `path_id` that were last
+    /// modified before `time`, according to the last (selected) snapshot
+    /// that has them. Returns their paths relative to `path_id`, their
+    /// path ids and their sizes, largest first.
+    pub fn get_files_modified_before(
+        &self,
+        path_id: Option<PathId>,
+        time: DateTime<Utc>,
+        limit: usize,
+    ) -> Result<Vec<(Utf8PathBuf, PathId, usize)>, rusqlite::Error> {
+        let hashes = match &self.selected_snapshots {
+            Some(hashes) => hashes.clone(),
+            None => self
+                .conn
+                .prepare("SELECT hash FROM snapshots")?
+                .query_map([], |row| row.get("hash"))?
+                .collect::<Result<Vec<String>, _>>()?,
         };
-        let mut max_size_time = details.first_seen; // Time of the max_size snapshot
-        for table in entries_tables {
-            if let Some((hash, size, time)) = run_query(&table)? {
-                if size > details.max_size
-                    || (size == details.max_size && time > max_size_time)
-                {
-                    details.max_size = size;
-                    details.max_size_snapshot_hash = hash.clone();
-                    max_size_time = time;
-                }
-                if time < details.first_seen {
-                    details.first_seen = time;
-                    details.first_seen_snapshot_hash = hash.clone();
-                }
-                if time > details.last_seen {
-                    details.last_seen = time;
-                    details.last_seen_snapshot_hash = hash;
-                }
+        let aggregate_table = self.aggregate_table();
+        let time = datetime_to_timestamp(time);
+        let mut files = Vec::new();
+        for chunk in hashes.chunks(MAX_COMPOUND_SELECT) {
+            // The last seen snapshot decides the mtime, so each file is
+            // only looked up in the entries table of that snapshot.
+            let old_files = chunk
+                .iter()
+                .enumerate()
+                .map(|(i, hash)| {
+                    format!(
+                        "SELECT subtree.path_id, path, max_size \
+                         FROM subtree JOIN \"entries_{}\" AS e \
+                             ON e.path_id = subtree.path_id \
+                         WHERE NOT subtree.is_dir \
+                             AND last_seen_snapshot_hash = ?{} \
+                             AND mtime < ?2",
+                        hash,
+                        i + 4
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(" UNION ALL ");
+            let mut stmt = self.conn.prepare(&format!(
+                "WITH RECURSIVE subtree \
+                     (path_id, path, is_dir, max_size, last_seen_snapshot_hash) \
+                 AS ( \
+                     SELECT path_id, component, is_dir, max_size, \
+                         last_seen_snapshot_hash \
+                     FROM {aggregate_table} JOIN paths ON path_id = paths.id \
+                     WHERE parent_id = ?1 \
+                     UNION ALL \
+                     SELECT a.path_id, \
+                         rtrim(subtree.path, '/') || '/' || component, \
+                         a.is_dir, a.max_size, a.last_seen_snapshot_hash \
+                     FROM subtree \
+                         JOIN paths ON paths.parent_id = subtree.path_id \
+                         JOIN {aggregate_table} AS a ON a.path_id = paths.id \
+                     WHERE subtree.is_dir \
+                 ) \
+                 SELECT * FROM ({old_files}) \
+                 ORDER BY max_size DESC \
+                 LIMIT ?3"
+            ))?;
+            let params = [
+                Value::from(o_path_id_to_raw_u64(path_id) as i64),
+                Value::from(time),
+                Value::from(limit as i64),
+            ]
+            .into_iter()
+            .chain(chunk.iter().map(|hash| Value::from(hash.clone())));
+            for file in stmt.query_map(params_from_iter(params), |row| {
+                Ok((
+                    row.get::<_, String>("path")?.into(),
+                    PathId(row.get("path_id")?),
+                    row.get("max_size")?,
+                ))
+            })? {
+                files.push(file?);
             }
         }
-        Ok(Some(details))
+        // Each chunk of snapshots has its own top files
+        files.sort_by_key(|(_, _, size)| Reverse(*size));
+        files.truncate(limit);
+        Ok(files)
+    }
+
+    /// Compares the children of `path_id` in two snapshots.
+    /// Only the entries that were added, removed or changed size are returned,
+    /// sorted by the absolute size difference, largest first.
+    pub fn get_diff(
+        &self,
+        old_hash: &str,
+        new_hash: &str,
+        path_id: Option<PathId>,
+    ) -> Result<Vec<DiffEntry>, rusqlite::Error> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT \
+                 paths.id AS path_id, \
+                 component, \
+                 coalesce(new.is_dir, old.is_dir) AS is_dir, \
+                 old.size AS old_size, \
+                 new.size AS new_size \
+             FROM paths \
+                 LEFT JOIN \"entries_{old_hash}\" AS old \
+                     ON old.path_id = paths.id \
+                 LEFT JOIN \"entries_{new_hash}\" AS new \
+                     ON new.path_id = paths.id \
+             WHERE parent_id = ? \
+                 AND (old.path_id IS NOT NULL OR new.path_id IS NOT NULL) \
+                 AND old.size IS NOT new.size"
+        ))?;
+        let mut entries = stmt
+            .query_map([o_path_id_to_raw_u64(path_id)], |row| {
+                Ok(DiffEntry {
+                    path_id: PathId(row.get("path_id")?),
+                    component: row.get("component")?,
+                    is_dir: row.get("is_dir")?,
+                    old_size: row.get("old_size")?,
+                    new_size: row.get("new_size")?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|e| Reverse(e.delta().unsigned_abs()));
+        Ok(entries)
+    }
+
+    /// Returns all the files (not directories) under `path_id`
+    /// in the given snapshot, with their paths relative to `path_id`.
+    /// They are sorted by path so that the order is the same every time.
+    pub fn get_files(
+        &self,
+        snapshot_hash: &str,
+        path_id: PathId,
+    ) -> Result<Vec<(Utf8PathBuf, usize)>, rusqlite::Error> {
+        let mut files = Vec::new();
+        self.for_each_file(snapshot_hash, path_id, |file| {
+            files.push((file.path, file.size));
+            Ok::<(), rusqlite::Error>(())
+        })?;
+        Ok(files)
+    }
+
+    /// Like `get_files` but calls `f` with each file instead of
+    /// collecting them, so that a huge subtree doesn't use a lot of memory.
+    pub fn for_each_file<E: From<rusqlite::Error>>(
+        &self,
+        snapshot_hash: &str,
+        path_id: PathId,
+        mut f: impl FnMut(File) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let entries_table = format!("entries_{snapshot_hash}");
+        let mut stmt = self.conn.prepare(&format!(
+            "WITH RECURSIVE subtree (path_id, path, size, is_dir) AS ( \
+                 SELECT path_id, component, size, is_dir \
+                 FROM \"{entries_table}\" JOIN paths ON path_id = paths.id \
+                 WHERE parent_id = ? \
+                 UNION ALL \
+                 SELECT e.path_id, subtree.path || '/' || component, e.size, e.is_dir \
+                 FROM subtree \
+                     JOIN paths ON paths.parent_id = subtree.path_id \
+                     JOIN \"{entries_table}\" AS e ON e.path_id = paths.id \
+                 WHERE subtree.is_dir \
+             ) \
+             SELECT path, subtree.size, {} \
+             FROM subtree JOIN \"{entries_table}\" USING (path_id) \
+             WHERE NOT subtree.is_dir \
+             ORDER BY path",
+            metadata_column_names()
+        ))?;
+        let mut rows = stmt.query([path_id.0])?;
+        while let Some(row) = rows.next()? {
+            f(File {
+                path: row.get::<&str, String>("path")?.into(),
+                size: row.get("size")?,
+                metadata: metadata_from_row(row)?,
+            })?;
+        }
+        Ok(())
     }
 
+    #[cfg(any(test, feature = "bench"))]
     pub fn save_snapshot(
         &mut self,
         snapshot: &Snapshot,
         tree: SizeTree,
     ) -> Result<usize, rusqlite::Error> {
-        let mut file_count = 0;
         let tx = self.conn.transaction()?;
-        {
-            tx.execute(
-                "INSERT INTO snapshots ( \
-                     hash, \
-                     time, \
-                     parent, \
-                     tree, \
-                     hostname, \
-                     username, \
-                     uid, \
-                     gid, \
-                     original_id, \
-                     program_version \
-                 ) \
-                 VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
-                params![
-                    snapshot.id,
-                    datetime_to_timestamp(snapshot.time),
-                    snapshot.parent,
-                    snapshot.tree,
-                    snapshot.hostname,
-                    snapshot.username,
-                    snapshot.uid,
-                    snapshot.gid,
-                    snapshot.original_id,
-                    snapshot.program_version
-                ],
-            )?;
-            let mut snapshot_paths_stmt = tx.prepare(
-                "INSERT INTO snapshot_paths (hash, path) VALUES (?, ?)",
-            )?;
-            for path in snapshot.paths.iter() {
-                snapshot_paths_stmt.execute([&snapshot.id, path])?;
-            }
-            let mut snapshot_excludes_stmt = tx.prepare(
-                "INSERT INTO snapshot_excludes (hash, path) VALUES (?, ?)",
-            )?;
-            for path in snapshot.excludes.iter() {
-                snapshot_excludes_stmt.execute([&snapshot.id, path])?;
+        let file_count = save_snapshot_in(&tx, snapshot, tree)?;
+        tx.commit()?;
+        Ok(file_count)
+    }
+
+    /// Saves the snapshot with its staged files and removes them
+    /// from the staging tables.
+    ///
+    /// The files are read in order so that only the directories leading
+    /// to the current one are kept in memory, see `EntriesWriter`.
+    pub fn save_staged_snapshot(
+        &mut self,
+        snapshot: &Snapshot,
+    ) -> Result<usize, rusqlite::Error> {
+        let tx = self.conn.transaction()?;
+        insert_snapshot_info(&tx, snapshot)?;
+        let entries_table = format!("entries_{}", &snapshot.id);
+        create_entries_table(&tx, &entries_table)?;
+        let file_count = {
+            let mut stmt = tx.prepare(&format!(
+                "SELECT path, size, {} FROM staged_files \
+                 WHERE hash = ? \
+                 ORDER BY path",
+                metadata_column_names()
+            ))?;
+            let mut rows = stmt.query([&snapshot.id])?;
+            let mut writer = EntriesWriter::new(&tx, &entries_table)?;
+            while let Some(row) = rows.next()? {
+                let path = row.get::<_, String>("path")?;
+                writer.add_file(
+                    Utf8Path::new(&path),
+                    row.get("size")?,
+                    &metadata_from_row(row)?,
+                )?;
             }
-            let mut snapshot_tags_stmt = tx.prepare(
-                "INSERT INTO snapshot_tags (hash, tag) VALUES (?, ?)",
-            )?;
-            for path in snapshot.tags.iter() {
-                snapshot_tags_stmt.execute([&snapshot.id, path])?;
+            writer.finish()?
+        };
+        aggregate_snapshot_entries(
+            &tx,
+            "aggregate_entries",
+            &[&snapshot.id],
+            false,
+        )?;
+        tx.execute("DELETE FROM unique_sizes", [])?;
+        delete_staged_snapshot_in(&tx, &snapshot.id)?;
+        tx.commit()?;
+        Ok(file_count)
+    }
+
+    /// Saves a snapshot with the same tree as the cached snapshot
+    /// `source_hash`, copying its entries instead of fetching them.
+    pub fn clone_snapshot(
+        &mut self,
+        snapshot: &Snapshot,
+        source_hash: &str,
+    ) -> Result<usize, rusqlite::Error> {
+        let tx = self.conn.transaction()?;
+        insert_snapshot_info(&tx, snapshot)?;
+        let entries_table = format!("entries_{}", &snapshot.id);
+        create_entries_table(&tx, &entries_table)?;
+        let columns =
+            format!("path_id, size, is_dir, {}", metadata_column_names());
+        let file_count = tx.execute(
+            &format!(
+                "INSERT INTO \"{entries_table}\" ({columns}) \
+                 SELECT {columns} FROM \"entries_{source_hash}\""
+            ),
+            [],
+        )?;
+        aggregate_snapshot_entries(
+            &tx,
+            "aggregate_entries",
+            &[&snapshot.id],
+            false,
+        )?;
+        tx.execute("DELETE FROM unique_sizes", [])?;
+        delete_staged_snapshot_in(&tx, &snapshot.id)?;
+        tx.commit()?;
+        Ok(file_count)
+    }
+
+    // Staging //////////////////////////////////////////////
+    pub fn get_staged_snapshots(
+        &self,
+    ) -> Result<Vec<StagedSnapshot>, rusqlite::Error> {
+        let mut stmt = self.conn.prepare(
+            "SELECT hash, base, files, last_path, complete \
+             FROM staged_snapshots",
+        )?;
+        #[allow(clippy::let_and_return)]
+        let result = stmt
+            .query_map([], |row| {
+                Ok(StagedSnapshot {
+                    hash: row.get("hash")?,
+                    base: row.get("base")?,
+                    files: row.get("files")?,
+                    last_path: row
+                        .get::<_, Option<String>>("last_path")?
+                        .map(Utf8PathBuf::from),
+                    complete: row.get("complete")?,
+                })
+            })?
+            .collect();
+        result
+    }
+
+    /// Adds `files` after the ones already staged for the snapshot.
+    /// Each call is a checkpoint that a later sync can resume from.
+    pub fn stage_files(
+        &mut self,
+        hash: &str,
+        base: Option<&str>,
+        files: &[File],
+    ) -> Result<(), rusqlite::Error> {
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "INSERT INTO staged_snapshots (hash, base, files, complete) \
+             VALUES (?, ?, 0, 0) \
+             ON CONFLICT (hash) DO NOTHING",
+            params![hash, base],
+        )?;
+        {
+            let mut stmt = tx.prepare(&format!(
+                "INSERT INTO staged_files (hash, path, size, {}) \
+                 VALUES (?, ?, ?, {})",
+                metadata_column_names(),
+                metadata_placeholders(),
+            ))?;
+            for file in files {
+                stmt.execute(params_from_iter(
+                    [
+                        Value::from(hash.to_string()),
+                        Value::from(file.path.to_string()),
+                        Value::from(file.size as i64),
+                    ]
+                    .into_iter()
+                    .chain(metadata_values(&file.metadata)),
+                ))?;
             }
         }
-        {
-            let entries_table = format!("entries_{}", &snapshot.id);
+        if let Some(File { path: last_path, .. }) = files.last() {
             tx.execute(
-                &format!(
-                    "CREATE TABLE \"{entries_table}\" (
-                         path_id INTEGER PRIMARY KEY,
-                         size INTEGER NOT NULL,
-                         is_dir INTEGER NOT NULL,
-                         FOREIGN KEY (path_id) REFERENCES paths (id)
-                     )"
-                ),
-                [],
+                "UPDATE staged_snapshots \
+                 SET files = files + ?, last_path = ? \
+                 WHERE hash = ?",
+                params![files.len(), last_path.as_str(), hash],
             )?;
-            let mut entries_stmt = tx.prepare(&format!(
-                "INSERT INTO \"{entries_table}\" (path_id, size, is_dir) \
-                 VALUES (?, ?, ?)",
-            ))?;
+        }
+        tx.commit()
+    }
 
-            let mut paths_stmt = tx.prepare(
-                "INSERT INTO paths (parent_id, component)
-                 VALUES (?, ?)
-                 ON CONFLICT (parent_id, component) DO NOTHING",
-            )?;
-            let mut paths_query = tx.prepare(
-                "SELECT id FROM paths WHERE parent_id = ? AND component = ?",
-            )?;
+    /// All the files of the snapshot are staged.
+    pub fn complete_staged_snapshot(
+        &mut self,
+        hash: &str,
+        base: Option<&str>,
+    ) -> Result<(), rusqlite::Error> {
+        self.conn.execute(
+            "INSERT INTO staged_snapshots (hash, base, files, complete) \
+             VALUES (?, ?, 0, 1) \
+             ON CONFLICT (hash) DO UPDATE SET complete = 1",
+            params![hash, base],
+        )?;
+        Ok(())
+    }
 
-            tree.0.traverse_with_context(
-                |id_stack, component, size, is_dir| {
-                    let parent_id = id_stack.last().copied();
-                    paths_stmt.execute(params![
-                        o_path_id_to_raw_u64(parent_id),
-                        component,
-                    ])?;
-                    let path_id = paths_query.query_row(
-                        params![o_path_id_to_raw_u64(parent_id), component],
-                        |row| row.get(0).map(PathId),
-                    )?;
-                    entries_stmt.execute(params![path_id.0, size, is_dir])?;
-                    file_count += 1;
-                    Ok::<PathId, rusqlite::Error>(path_id)
-                },
-            )?;
-        }
-        tx.commit()?;
-        Ok(file_count)
+    pub fn delete_staged_snapshot(
+        &mut self,
+        hash: &str,
+    ) -> Result<(), rusqlite::Error> {
+        let tx = self.conn.transaction()?;
+        delete_staged_snapshot_in(&tx, hash)?;
+        tx.commit()
     }
 
     pub fn delete_snapshot(
@@ -360,12 +751,96 @@ impl Cache {
         hash: impl AsRef<str>,
     ) -> Result<(), rusqlite::Error> {
         let hash = hash.as_ref();
+        let tables = self.entries_tables()?.collect::<Vec<_>>();
         let tx = self.conn.transaction()?;
         tx.execute("DELETE FROM snapshots WHERE hash = ?", [hash])?;
         tx.execute("DELETE FROM snapshot_paths WHERE hash = ?", [hash])?;
         tx.execute("DELETE FROM snapshot_excludes WHERE hash = ?", [hash])?;
         tx.execute("DELETE FROM snapshot_tags WHERE hash = ?", [hash])?;
         tx.execute(&format!("DROP TABLE IF EXISTS \"entries_{}\"", hash), [])?;
+        delete_staged_snapshot_in(&tx, hash)?;
+        {
+            // The aggregates that came from this snapshot
+            // need to be recomputed from the remaining ones.
+            tx.execute(
+                "CREATE TEMP TABLE stale_paths (path_id INTEGER PRIMARY KEY)",
+                [],
+            )?;
+            tx.execute(
+                "INSERT INTO stale_paths (path_id) \
+                 SELECT path_id FROM aggregate_entries \
+                 WHERE max_size_snapshot_hash = ?1 \
+                     OR first_seen_snapshot_hash = ?1 \
+                     OR last_seen_snapshot_hash = ?1",
+                [hash],
+            )?;
+            tx.execute(
+                "DELETE FROM aggregate_entries \
+                 WHERE path_id IN (SELECT path_id FROM stale_paths)",
+                [],
+            )?;
+            let other_hashes = tables
+                .iter()
+                .map(|table| table.strip_prefix("entries_").unwrap())
+                .filter(|other_hash| *other_hash != hash)
+                .collect::<Vec<_>>();
+            aggregate_snapshot_entries(
+                &tx,
+                "aggregate_entries",
+                &other_hashes,
+                true,
+            )?;
+            tx.execute("DROP TABLE stale_paths", [])?;
+        }
+        tx.execute("DELETE FROM unique_sizes", [])?;
+        tx.commit()
+    }
+
+    // Unique sizes /////////////////////////////////////////
+    /// The unique sizes are cleared every time the snapshots change.
+    pub fn has_unique_sizes(&self) -> Result<bool, rusqlite::Error> {
+        self.conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM unique_sizes)",
+            [],
+            |row| row.get(0),
+        )
+    }
+
+    /// Replaces all unique sizes.
+    /// `sizes` is `(level, component, size)` in depth first order with
+    /// parents before children, like `UniqueSizes::finish` returns.
+    pub fn save_unique_sizes<C: AsRef<str>>(
+        &mut self,
+        sizes: impl IntoIterator<Item = (usize, C, usize)>,
+    ) -> Result<(), rusqlite::Error> {
+        let tx = self.conn.transaction()?;
+        tx.execute("DELETE FROM unique_sizes", [])?;
+        {
+            let mut sizes_stmt = tx.prepare(
+                "INSERT INTO unique_sizes (path_id, size) VALUES (?, ?)",
+            )?;
+            let mut paths_stmt = tx.prepare(
+                "INSERT INTO paths (parent_id, component)
+                 VALUES (?, ?)
+                 ON CONFLICT (parent_id, component) DO NOTHING",
+            )?;
+            let mut paths_query = tx.prepare(
+                "SELECT id FROM paths WHERE parent_id = ? AND component = ?",
+            )?;
+            let mut id_stack: Vec<PathId> = Vec::new();
+            for (level, component, size) in sizes {
+                id_stack.truncate(level - 1);
+                let parent_id = o_path_id_to_raw_u64(id_stack.last().copied());
+                let component = component.as_ref();
+                paths_stmt.execute(params![parent_id, component])?;
+                let path_id = paths_query
+                    .query_row(params![parent_id, component], |row| {
+                        row.get(0).map(PathId)
+                    })?;
+                sizes_stmt.execute(params![path_id.0, size])?;
+                id_stack.push(path_id);
+            }
+        }
         tx.commit()
     }
 
@@ -379,6 +854,47 @@ impl Cache {
         result
     }
 
+    /// The path marks with where they are and their size in the snapshots.
+    /// The size is the largest across all snapshots, regardless of
+    /// which ones are selected.
+    pub fn get_mark_entries(&self) -> Result<Vec<MarkEntry>, rusqlite::Error> {
+        let mut stmt = self.conn.prepare(
+            "SELECT parent_id, max_size \
+             FROM paths JOIN aggregate_entries ON path_id = paths.id \
+             WHERE paths.id = ?",
+        )?;
+        let mut entries = Vec::new();
+        for path in self.get_marks()? {
+            let found = match self.get_path_id_by_path(&path)? {
+                None => None,
+                Some(path_id) => stmt
+                    .query_row([path_id.0], |row| {
+                        Ok((
+                            path_id,
+                            raw_u64_to_o_path_id(row.get("parent_id")?),
+                            row.get("max_size")?,
+                        ))
+                    })
+                    .optional()?,
+            };
+            entries.push(match found {
+                None => MarkEntry {
+                    path,
+                    path_id: None,
+                    parent_id: None,
+                    size: None,
+                },
+                Some((path_id, parent_id, size)) => MarkEntry {
+                    path,
+                    path_id: Some(path_id),
+                    parent_id,
+                    size: Some(size),
+                },
+            });
+        }
+        Ok(entries)
+    }
+
     pub fn upsert_mark(
         &mut self,
         path: &Utf8Path,
@@ -397,9 +913,688 @@ impl Cache {
         self.conn.execute("DELETE FROM marks WHERE path = ?", [path.as_str()])
     }
 
+    /// Deletes both the path and the pattern marks.
     pub fn delete_all_marks(&mut self) -> Result<usize, rusqlite::Error> {
-        self.conn.execute("DELETE FROM marks", [])
+        let tx = self.conn.transaction()?;
+        let deleted = tx.execute("DELETE FROM marks", [])?
+            + tx.execute("DELETE FROM pattern_marks", [])?;
+        tx.commit()?;
+        Ok(deleted)
+    }
+
+    /// Restic exclude patterns, sorted.
+    pub fn get_pattern_marks(&self) -> Result<Vec<String>, rusqlite::Error> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT pattern FROM pattern_marks ORDER BY pattern")?;
+        #[allow(clippy::let_and_return)]
+        let result = stmt.query_map([], |row| row.get("pattern"))?.collect();
+        result
+    }
+
+    pub fn upsert_pattern_mark(
+        &mut self,
+        pattern: &str,
+    ) -> Result<usize, rusqlite::Error> {
+        self.conn.execute(
+            "INSERT INTO pattern_marks (pattern) VALUES (?) \
+             ON CONFLICT (pattern) DO NOTHING",
+            [pattern],
+        )
+    }
+
+    pub fn delete_pattern_mark(
+        &mut self,
+        pattern: &str,
+    ) -> Result<usize, rusqlite::Error> {
+        self.conn
+            .execute("DELETE FROM pattern_marks WHERE pattern = ?", [pattern])
+    }
+
+    /// The paths in any snapshot that each of the `patterns` matches exactly.
+    /// The patterns can match anywhere so this looks at every path.
+    pub fn find_pattern_matches(
+        &self,
+        patterns: &[ExcludePattern],
+    ) -> Result<Vec<Vec<(PathId, Utf8PathBuf)>>, Error> {
+        let mut matches = vec![Vec::new(); patterns.len()];
+        if patterns.is_empty() {
+            return Ok(matches);
+        }
+        let mut stmt = self.conn.prepare(
+            "WITH RECURSIVE tree (id, path) AS ( \
+                 SELECT id, component \
+                 FROM paths JOIN aggregate_entries ON path_id = paths.id \
+                 WHERE parent_id = 0 \
+                 UNION ALL \
+                 SELECT paths.id, \
+                        CASE tree.path \
+                            WHEN '/' THEN '/' || paths.component \
+                            ELSE tree.path || '/' || paths.component \
+                        END \
+                 FROM paths \
+                     JOIN aggregate_entries ON path_id = paths.id \
+                     JOIN tree ON paths.parent_id = tree.id \
+             ) \
+             SELECT id, path FROM tree",
+        )?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let path = row.get_ref("path")?.as_str()?;
+            for (pattern, matches) in patterns.iter().zip(matches.iter_mut()) {
+                if pattern.matches_exactly(path) {
+                    matches.push((PathId(row.get("id")?), path.into()));
+                }
+            }
+        }
+        Ok(matches)
+    }
+
+    /// How much the marks (paths and patterns) would remove from each of the
+    /// (selected) snapshots.
+    /// Paths inside another marked path are only counted once.
+    pub fn get_marks_summary(&self) -> Result<MarksSummary, Error> {
+        let marks =
+            self.resolve_marks(self.get_marks()?, self.get_pattern_marks()?)?;
+        let snapshots = self.get_selected_snapshots()?;
+        let sizes = self.get_marked_sizes(&snapshots, &marks)?;
+
+        let all_paths = marks
+            .iter()
+            .flat_map(|(_, _, paths)| paths.iter().cloned())
+            .collect();
+        let snapshots = snapshots
+            .into_iter()
+            .zip(removed_sizes(&sizes, all_paths))
+            .map(|(snapshot, size)| (snapshot, size.unwrap_or(0)))
+            .collect();
+        let marks = marks
+            .into_iter()
+            .map(|(mark, is_pattern, paths)| {
+                let removed = removed_sizes(&sizes, paths);
+                MarkSummary {
+                    mark,
+                    is_pattern,
+                    snapshots: removed.iter().flatten().count(),
+                    max_size: removed
+                        .iter()
+                        .flatten()
+                        .copied()
+                        .max()
+                        .unwrap_or(0),
+                    total_size: removed.iter().flatten().sum(),
+                }
+            })
+            .collect();
+        Ok(MarksSummary { marks, snapshots })
+    }
+
+    /// Checks each of the paths and patterns against the (selected)
+    /// snapshots: which ones still have something that it matches
+    /// and which ones don't.
+    /// The paths come first in the result and then the patterns,
+    /// invalid patterns are left out.
+    pub fn check_marks(
+        &self,
+        paths: Vec<Utf8PathBuf>,
+        patterns: Vec<String>,
+    ) -> Result<Vec<MarkCheck>, Error> {
+        let marks = self.resolve_marks(paths, patterns)?;
+        let snapshots = self.get_selected_snapshots()?;
+        let sizes = self.get_marked_sizes(&snapshots, &marks)?;
+        let checks = marks
+            .into_iter()
+            .map(|(mark, is_pattern, paths)| {
+                let mut check = MarkCheck {
+                    mark,
+                    is_pattern,
+                    present: Vec::new(),
+                    absent: Vec::new(),
+                };
+                let removed = removed_sizes(&sizes, paths);
+                for (snapshot, size) in snapshots.iter().zip(removed) {
+                    match size {
+                        Some(size) => {
+                            check.present.push((snapshot.clone(), size))
+                        }
+                        None => {
+                            let excluded_by = snapshot_exclude_for(
+                                snapshot,
+                                &check.mark,
+                                is_pattern,
+                            );
+                            check.absent.push((snapshot.clone(), excluded_by));
+                        }
+                    }
+                }
+                check
+            })
+            .collect();
+        Ok(checks)
+    }
+
+    /// The snapshots in the selection (or all of them),
+    /// latest snapshot first.
+    fn get_selected_snapshots(&self) -> Result<Vec<Snapshot>, Error> {
+        let mut snapshots = self.get_snapshots()?;
+        if let Some(selected) = &self.selected_snapshots {
+            snapshots.retain(|s| selected.contains(&s.id));
+        }
+        snapshots.sort_unstable_by_key(|s| Reverse(s.time));
+        Ok(snapshots)
+    }
+
+    /// `(mark, is_pattern, the paths it marks)` for each of the paths
+    /// and then each of the (valid) patterns.
+    fn resolve_marks(
+        &self,
+        paths: Vec<Utf8PathBuf>,
+        patterns: Vec<String>,
+    ) -> Result<Vec<(String, bool, MarkedPaths)>, Error> {
+        let mut marks: Vec<(String, bool, MarkedPaths)> = Vec::new();
+        for path in paths {
+            let found = self
+                .get_path_id_by_path(&path)?
+                .map(|path_id| (path_id, path.clone()));
+            marks.push((
+                path.into_string(),
+                false,
+                found.into_iter().collect(),
+            ));
+        }
+        let patterns = patterns
+            .into_iter()
+            .filter_map(|p| ExcludePattern::new(&p).ok().map(|e| (p, e)))
+            .collect::<Vec<_>>();
+        if !patterns.is_empty() {
+            let (texts, patterns): (Vec<_>, Vec<_>) =
+                patterns.into_iter().unzip();
+            let matches = self.find_pattern_matches(&patterns)?;
+            marks.extend(
+                texts.into_iter().zip(matches).map(|(p, m)| (p, true, m)),
+            );
+        }
+        Ok(marks)
+    }
+
+    /// The size of every marked path in each of the snapshots.
+    fn get_marked_sizes(
+        &self,
+        snapshots: &[Snapshot],
+        marks: &[(String, bool, MarkedPaths)],
+    ) -> Result<Vec<HashMap<PathId, usize>>, Error> {
+        let marked_ids = marks
+            .iter()
+            .flat_map(|(_, _, paths)| paths.iter().map(|(id, _)| *id))
+            .collect::<HashSet<_>>();
+        self.conn.execute_batch(
+            "DROP TABLE IF EXISTS temp.marked_paths;
+             CREATE TEMP TABLE marked_paths (path_id INTEGER PRIMARY KEY);",
+        )?;
+        {
+            let mut stmt = self
+                .conn
+                .prepare("INSERT INTO marked_paths (path_id) VALUES (?)")?;
+            for path_id in &marked_ids {
+                stmt.execute([path_id.0])?;
+            }
+        }
+        let mut sizes = Vec::with_capacity(snapshots.len());
+        for snapshot in snapshots {
+            let mut stmt = self.conn.prepare(&format!(
+                "SELECT path_id, size \
+                 FROM marked_paths JOIN \"entries_{}\" USING (path_id)",
+                snapshot.id
+            ))?;
+            let snapshot_sizes = stmt
+                .query_map([], |row| {
+                    Ok((PathId(row.get("path_id")?), row.get("size")?))
+                })?
+                .collect::<Result<HashMap<_, _>, _>>()?;
+            sizes.push(snapshot_sizes);
+        }
+        self.conn.execute("DROP TABLE marked_paths", [])?;
+        Ok(sizes)
+    }
+}
+
+/// What the paths remove from each snapshot,
+/// `None` if none of the paths is in it.
+fn removed_sizes(
+    sizes: &[HashMap<PathId, usize>],
+    paths: MarkedPaths,
+) -> Vec<Option<usize>> {
+    let paths = remove_nested(paths);
+    sizes
+        .iter()
+        .map(|snapshot_sizes| {
+            paths
+                .iter()
+                .filter_map(|(id, _)| snapshot_sizes.get(id))
+                .fold(None, |acc, size| Some(acc.unwrap_or(0) + size))
+        })
+        .collect()
+}
+
+/// The exclude the snapshot was made with that covers the mark, if any.
+fn snapshot_exclude_for(
+    snapshot: &Snapshot,
+    mark: &str,
+    is_pattern: bool,
+) -> Option<String> {
+    let mark_pattern =
+        if is_pattern { Some(ExcludePattern::new(mark).ok()?) } else { None };
+    let mut excludes = snapshot.excludes.iter().collect::<Vec<_>>();
+    excludes.sort_unstable();
+    excludes
+        .into_iter()
+        .find(|exclude| {
+            let Ok(exclude) = ExcludePattern::new(exclude) else {
+                return false;
+            };
+            match &mark_pattern {
+                Some(mark_pattern) => exclude.excludes_same_as(mark_pattern),
+                None => exclude.matches(mark),
+            }
+        })
+        .cloned()
+}
+
+/// The part of `Cache::save_snapshot` that runs inside the transaction.
+#[cfg(any(test, feature = "bench"))]
+fn save_snapshot_in(
+    tx: &Connection,
+    snapshot: &Snapshot,
+    tree: SizeTree,
+) -> Result<usize, rusqlite::Error> {
+    let mut file_count = 0;
+    insert_snapshot_info(tx, snapshot)?;
+    {
+        let entries_table = format!("entries_{}", &snapshot.id);
+        create_entries_table(tx, &entries_table)?;
+        let mut entries_stmt = tx.prepare(&format!(
+            "INSERT INTO \"{entries_table}\" (path_id, size, is_dir) \
+             VALUES (?, ?, ?)",
+        ))?;
+
+        let mut paths_stmt = tx.prepare(
+            "INSERT INTO paths (parent_id, component)
+             VALUES (?, ?)
+             ON CONFLICT (parent_id, component) DO NOTHING",
+        )?;
+        let mut paths_query = tx.prepare(
+            "SELECT id FROM paths WHERE parent_id = ? AND component = ?",
+        )?;
+
+        tree.0.traverse_with_context(|id_stack, component, size, is_dir| {
+            let parent_id = id_stack.last().copied();
+            paths_stmt
+                .execute(
+                    params![o_path_id_to_raw_u64(parent_id), component,],
+                )?;
+            let path_id = paths_query.query_row(
+                params![o_path_id_to_raw_u64(parent_id), component],
+                |row| row.get(0).map(PathId),
+            )?;
+            entries_stmt.execute(params![path_id.0, size, is_dir])?;
+            file_count += 1;
+            Ok::<PathId, rusqlite::Error>(path_id)
+        })?;
     }
+
+    aggregate_snapshot_entries(
+        tx,
+        "aggregate_entries",
+        &[&snapshot.id],
+        false,
+    )?;
+    tx.execute("DELETE FROM unique_sizes", [])?;
+    Ok(file_count)
+}
+
+/// The row in `snapshots` and the paths, excludes and tags.
+fn insert_snapshot_info(
+    tx: &Connection,
+    snapshot: &Snapshot,
+) -> Result<(), rusqlite::Error> {
+    tx.execute(
+        "INSERT INTO snapshots ( \
+             hash, \
+             time, \
+             parent, \
+             tree, \
+             hostname, \
+             username, \
+             uid, \
+             gid, \
+             original_id, \
+             program_version \
+         ) \
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        params![
+            snapshot.id,
+            datetime_to_timestamp(snapshot.time),
+            snapshot.parent,
+            snapshot.tree,
+            snapshot.hostname,
+            snapshot.username,
+            snapshot.uid,
+            snapshot.gid,
+            snapshot.original_id,
+            snapshot.program_version
+        ],
+    )?;
+    let mut snapshot_paths_stmt =
+        tx.prepare("INSERT INTO snapshot_paths (hash, path) VALUES (?, ?)")?;
+    for path in snapshot.paths.iter() {
+        snapshot_paths_stmt.execute([&snapshot.id, path])?;
+    }
+    let mut snapshot_excludes_stmt =
+        tx.prepare("INSERT INTO snapshot_excludes (hash, path) VALUES (?, ?)")?;
+    for path in snapshot.excludes.iter() {
+        snapshot_excludes_stmt.execute([&snapshot.id, path])?;
+    }
+    let mut snapshot_tags_stmt =
+        tx.prepare("INSERT INTO snapshot_tags (hash, tag) VALUES (?, ?)")?;
+    for path in snapshot.tags.iter() {
+        snapshot_tags_stmt.execute([&snapshot.id, path])?;
+    }
+    Ok(())
+}
+
+fn create_entries_table(
+    tx: &Connection,
+    entries_table: &str,
+) -> Result<(), rusqlite::Error> {
+    tx.execute(
+        &format!(
+            "CREATE TABLE \"{entries_table}\" (
+                 path_id INTEGER PRIMARY KEY,
+                 size INTEGER NOT NULL,
+                 is_dir INTEGER NOT NULL,
+                 {},
+                 FOREIGN KEY (path_id) REFERENCES paths (id)
+             )",
+            METADATA_COLUMNS
+                .map(|(name, sql_type)| format!("{name} {sql_type}"))
+                .join(", ")
+        ),
+        [],
+    )?;
+    Ok(())
+}
+
+/// Writes the entries of a snapshot from its files sorted by path.
+///
+/// All the paths under a directory are next to each other when sorted,
+/// so the size of a directory is final as soon as a path outside of it
+/// comes up. Only the directories that lead to the current file
+/// are kept in memory.
+struct EntriesWriter<'a> {
+    entries_stmt: Statement<'a>,
+    paths_stmt: Statement<'a>,
+    paths_query: Statement<'a>,
+    /// (component, id, size so far) of the directories of the current file
+    open_dirs: Vec<(String, PathId, usize)>,
+    entry_count: usize,
+}
+
+impl<'a> EntriesWriter<'a> {
+    fn new(
+        conn: &'a Connection,
+        entries_table: &str,
+    ) -> Result<Self, rusqlite::Error> {
+        Ok(EntriesWriter {
+            entries_stmt: conn.prepare(&format!(
+                "INSERT INTO \"{entries_table}\" (path_id, size, is_dir, {}) \
+                 VALUES (?, ?, ?, {})",
+                metadata_column_names(),
+                metadata_placeholders(),
+            ))?,
+            paths_stmt: conn.prepare(
+                "INSERT INTO paths (parent_id, component)
+                 VALUES (?, ?)
+                 ON CONFLICT (parent_id, component) DO NOTHING",
+            )?,
+            paths_query: conn.prepare(
+                "SELECT id FROM paths WHERE parent_id = ? AND component = ?",
+            )?,
+            open_dirs: Vec::new(),
+            entry_count: 0,
+        })
+    }
+
+    /// `path` must come after the previous one in sorted order.
+    fn add_file(
+        &mut self,
+        path: &Utf8Path,
+        size: usize,
+        metadata: &FileMetadata,
+    ) -> Result<(), rusqlite::Error> {
+        let components =
+            path.components().map(|c| c.as_str()).collect::<Vec<_>>();
+        let Some((name, dirs)) = components.split_last() else {
+            return Ok(());
+        };
+        let common = self
+            .open_dirs
+            .iter()
+            .zip(dirs)
+            .take_while(|((open_dir, _, _), dir)| open_dir == *dir)
+            .count();
+        while self.open_dirs.len() > common {
+            self.close_dir()?;
+        }
+        for dir in &dirs[common..] {
+            let path_id = self.path_id(dir)?;
+            self.open_dirs.push((dir.to_string(), path_id, 0));
+        }
+        let path_id = self.path_id(name)?;
+        self.insert_entry(path_id, size, false, metadata)?;
+        self.entry_count += 1;
+        for (_, _, dir_size) in self.open_dirs.iter_mut() {
+            *dir_size += size;
+        }
+        Ok(())
+    }
+
+    /// Returns how many entries were written.
+    fn finish(mut self) -> Result<usize, rusqlite::Error> {
+        while !self.open_dirs.is_empty() {
+            self.close_dir()?;
+        }
+        Ok(self.entry_count)
+    }
+
+    /// The id of `component` inside the innermost open directory.
+    fn path_id(&mut self, component: &str) -> Result<PathId, rusqlite::Error> {
+        let parent_id =
+            o_path_id_to_raw_u64(self.open_dirs.last().map(|(_, id, _)| *id));
+        self.paths_stmt.execute(params![parent_id, component])?;
+        self.paths_query.query_row(params![parent_id, component], |row| {
+            row.get(0).map(PathId)
+        })
+    }
+
+    fn close_dir(&mut self) -> Result<(), rusqlite::Error> {
+        if let Some((_, path_id, size)) = self.open_dirs.pop() {
+            self.insert_entry(path_id, size, true, &FileMetadata::default())?;
+            self.entry_count += 1;
+        }
+        Ok(())
+    }
+
+    fn insert_entry(
+        &mut self,
+        path_id: PathId,
+        size: usize,
+        is_dir: bool,
+        metadata: &FileMetadata,
+    ) -> Result<(), rusqlite::Error> {
+        self.entries_stmt.execute(params_from_iter(
+            [
+                Value::from(path_id.0 as i64),
+                Value::from(size as i64),
+                Value::from(is_dir),
+            ]
+            .into_iter()
+            .chain(metadata_values(metadata)),
+        ))?;
+        Ok(())
+    }
+}
+
+/// The columns with the metadata of the files, in the entries tables
+/// and in `staged_files`. They are NULL for the directories and for the
+/// snapshots that were cached before they were added.
+const METADATA_COLUMNS: [(&str, &str); 7] = [
+    ("file_type", "TEXT"),
+    ("mtime", "INTEGER"),
+    ("mode", "INTEGER"),
+    ("uid", "INTEGER"),
+    ("gid", "INTEGER"),
+    ("inode", "INTEGER"),
+    ("links", "INTEGER"),
+];
+
+fn metadata_column_names() -> String {
+    METADATA_COLUMNS.map(|(name, _)| name).join(", ")
+}
+
+fn metadata_placeholders() -> String {
+    METADATA_COLUMNS.map(|_| "?").join(", ")
+}
+
+/// The values of `METADATA_COLUMNS`, in the same order.
+/// The inode and the number of links are stored as i64,
+/// anything larger wraps around and is read back the same.
+fn metadata_values(metadata: &FileMetadata) -> [Value; 7] {
+    [
+        Value::from(metadata.file_type.map(|t| t.as_str().to_string())),
+        Value::from(metadata.mtime.map(datetime_to_timestamp)),
+        Value::from(metadata.mode),
+        Value::from(metadata.uid),
+        Value::from(metadata.gid),
+        Value::from(metadata.inode.map(|inode| inode as i64)),
+        Value::from(metadata.links.map(|links| links as i64)),
+    ]
+}
+
+/// The inverse of `metadata_values`, from a row with `METADATA_COLUMNS`.
+fn metadata_from_row(row: &Row) -> Result<FileMetadata, rusqlite::Error> {
+    Ok(FileMetadata {
+        file_type: row
+            .get::<_, Option<String>>("file_type")?
+            .map(|t| FileType::parse(&t)),
+        mtime: row
+            .get::<_, Option<i64>>("mtime")?
+            .and_then(DateTime::from_timestamp_micros),
+        mode: row.get("mode")?,
+        uid: row.get("uid")?,
+        gid: row.get("gid")?,
+        inode: row.get::<_, Option<i64>>("inode")?.map(|inode| inode as u64),
+        links: row.get::<_, Option<i64>>("links")?.map(|links| links as u64),
+    })
+}
+
+fn delete_staged_snapshot_in(
+    tx: &Connection,
+    hash: &str,
+) -> Result<(), rusqlite::Error> {
+    tx.execute("DELETE FROM staged_snapshots WHERE hash = ?", [hash])?;
+    tx.execute("DELETE FROM staged_files WHERE hash = ?", [hash])?;
+    Ok(())
+}
+
+/// SQLite's default limit on the number of SELECTs in a compound SELECT.
+const MAX_COMPOUND_SELECT: usize = 500;
+
+/// Merges the entries of the snapshots into `target` (`aggregate_entries`
+/// or a table like it).
+/// If `only_stale` is true only the paths in the `stale_paths` table are used.
+fn aggregate_snapshot_entries(
+    conn: &Connection,
+    target: &str,
+    snapshot_hashes: &[&str],
+    only_stale: bool,
+) -> Result<usize, rusqlite::Error> {
+    let mut count = 0;
+    for chunk in snapshot_hashes.chunks(MAX_COMPOUND_SELECT) {
+        let source = chunk
+            .iter()
+            .map(|hash| {
+                let entries_table = format!("\"entries_{hash}\"");
+                let source = if only_stale {
+                    format!("stale_paths JOIN {entries_table} USING (path_id)")
+                } else {
+                    entries_table
+                };
+                format!(
+                    "SELECT path_id, is_dir, size, hash, time \
+                     FROM {source} JOIN snapshots ON hash = ?"
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(" UNION ALL ");
+        count += aggregate_entries_from(conn, target, &source, chunk)?;
+    }
+    Ok(count)
+}
+
+/// Upserts into `target`, a table like `aggregate_entries`, the rows of
+/// `source`, a query returning `(path_id, is_dir, size, hash, time)`.
+fn aggregate_entries_from(
+    conn: &Connection,
+    target: &str,
+    source: &str,
+    params: &[&str],
+) -> Result<usize, rusqlite::Error> {
+    // Note that the right hand sides in the SET clause
+    // always refer to the old values of the row.
+    conn.execute(
+        &format!(
+            "INSERT INTO {target} ( \
+                 path_id, \
+                 is_dir, \
+                 max_size, \
+                 max_size_snapshot_hash, \
+                 max_size_time, \
+                 first_seen, \
+                 first_seen_snapshot_hash, \
+                 last_seen, \
+                 last_seen_snapshot_hash \
+             ) \
+             SELECT path_id, is_dir, size, hash, time, time, hash, time, hash \
+             FROM ({source}) \
+             WHERE true \
+             ON CONFLICT (path_id) DO UPDATE SET \
+                 is_dir = is_dir OR excluded.is_dir, \
+                 max_size = max(max_size, excluded.max_size), \
+                 max_size_snapshot_hash = CASE \
+                     WHEN excluded.max_size > max_size \
+                         OR (excluded.max_size = max_size \
+                             AND excluded.max_size_time > max_size_time) \
+                     THEN excluded.max_size_snapshot_hash \
+                     ELSE max_size_snapshot_hash END, \
+                 max_size_time = CASE \
+                     WHEN excluded.max_size > max_size \
+                         OR (excluded.max_size = max_size \
+                             AND excluded.max_size_time > max_size_time) \
+                     THEN excluded.max_size_time \
+                     ELSE max_size_time END, \
+                 first_seen = min(first_seen, excluded.first_seen), \
+                 first_seen_snapshot_hash = CASE \
+                     WHEN excluded.first_seen < first_seen \
+                     THEN excluded.first_seen_snapshot_hash \
+                     ELSE first_seen_snapshot_hash END, \
+                 last_seen = max(last_seen, excluded.last_seen), \
+                 last_seen_snapshot_hash = CASE \
+                     WHEN excluded.last_seen > last_seen \
+                     THEN excluded.last_seen_snapshot_hash \
+                     ELSE last_seen_snapshot_hash END"
+        ),
+        params_from_iter(params),
+    )
 }
 
 // A PathId should never be 0.
@@ -426,6 +1621,9 @@ pub struct Entry {
     pub component: String,
     pub size: usize,
     pub is_dir: bool,
+    /// What removing this entry from all snapshots would free,
+    /// only available if it was computed.
+    pub unique_size: Option<usize>,
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -436,6 +1634,112 @@ pub struct EntryDetails {
     pub first_seen_snapshot_hash: String,
     pub last_seen: DateTime<Utc>,
     pub last_seen_snapshot_hash: String,
+    pub unique_size: Option<usize>,
+    /// From the last snapshot that has it
+    pub metadata: FileMetadata,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SearchHit {
+    pub path_id: PathId,
+    pub parent_id: Option<PathId>,
+    pub path: Utf8PathBuf,
+    /// The largest size across all (selected) snapshots
+    pub size: usize,
+    pub is_dir: bool,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DiffEntry {
+    pub path_id: PathId,
+    pub component: String,
+    pub is_dir: bool,
+    /// `None` if it was added
+    pub old_size: Option<usize>,
+    /// `None` if it was removed
+    pub new_size: Option<usize>,
+}
+
+impl DiffEntry {
+    /// How much it grew (negative if it shrank).
+    pub fn delta(&self) -> isize {
+        self.new_size.unwrap_or(0) as isize
+            - self.old_size.unwrap_or(0) as isize
+    }
+}
+
+/// A snapshot with some or all of its files in the staging tables.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StagedSnapshot {
+    pub hash: String,
+    /// The snapshot it is fetched incrementally from
+    pub base: Option<String>,
+    /// How many files are staged
+    pub files: usize,
+    pub last_path: Option<Utf8PathBuf>,
+    /// All the files are staged
+    pub complete: bool,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MarkEntry {
+    pub path: Utf8PathBuf,
+    /// `None` if it's not in any snapshot
+    pub path_id: Option<PathId>,
+    pub parent_id: Option<PathId>,
+    /// `None` if it's not in any snapshot
+    pub size: Option<usize>,
+}
+
+#[derive(Clone, Debug)]
+pub struct MarksSummary {
+    /// The path marks first and then the pattern marks
+    pub marks: Vec<MarkSummary>,
+    /// How much all of the marks together remove from each snapshot,
+    /// latest snapshot first.
+    pub snapshots: Vec<(Snapshot, usize)>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MarkSummary {
+    pub mark: String,
+    pub is_pattern: bool,
+    /// How many snapshots have something that it marks
+    pub snapshots: usize,
+    /// The most it removes from a single snapshot
+    pub max_size: usize,
+    /// What it removes from all the snapshots added up
+    /// (before deduplication)
+    pub total_size: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct MarkCheck {
+    pub mark: String,
+    pub is_pattern: bool,
+    /// The snapshots that still have something that it matches,
+    /// with how much it would remove from each, latest snapshot first.
+    pub present: Vec<(Snapshot, usize)>,
+    /// The snapshots without anything that it matches,
+    /// with the exclude of the snapshot that covers it if there is one,
+    /// latest snapshot first.
+    pub absent: Vec<(Snapshot, Option<String>)>,
+}
+
+type MarkedPaths = Vec<(PathId, Utf8PathBuf)>;
+
+/// Drops the paths that are inside another one of the paths.
+fn remove_nested(mut paths: MarkedPaths) -> MarkedPaths {
+    // Comparing the components puts every path right before its children
+    paths.sort_by(|(_, a), (_, b)| a.components().cmp(b.components()));
+    paths.dedup_by_key(|(id, _)| *id);
+    let mut kept: MarkedPaths = Vec::with_capacity(paths.len());
+    for (id, path) in paths {
+        if !kept.last().is_some_and(|(_, parent)| path.starts_with(parent)) {
+            kept.push((id, path));
+        }
+    }
+    kept
 }
 
 ////////// Migrations //////////////////////////////////////////////////////////
@@ -450,9 +1754,9 @@ struct Migration {
 
 const INTEGER_METADATA_TABLE: &str = "metadata_integer";
 
-pub const LATEST_VERSION: VersionId = 1;
+pub const LATEST_VERSION: VersionId = 6;
 
-const MIGRATIONS: [Migration; 3] = [
+const MIGRATIONS: [Migration; 9] = [
     Migration {
         old: None,
         new: 0,
@@ -465,12 +1769,48 @@ const MIGRATIONS: [Migration; 3] = [
         resync_necessary: false,
         migration_fun: migrate_none_to_v1,
     },
+    Migration {
+        old: None,
+        new: 6,
+        resync_necessary: false,
+        migration_fun: migrate_none_to_v6,
+    },
     Migration {
         old: Some(0),
         new: 1,
         resync_necessary: true,
         migration_fun: migrate_v0_to_v1,
     },
+    Migration {
+        old: Some(1),
+        new: 2,
+        resync_necessary: false,
+        migration_fun: migrate_v1_to_v2,
+    },
+    Migration {
+        old: Some(2),
+        new: 3,
+        resync_necessary: false,
+        migration_fun: migrate_v2_to_v3,
+    },
+    Migration {
+        old: Some(3),
+        new: 4,
+        resync_necessary: false,
+        migration_fun: migrate_v3_to_v4,
+    },
+    Migration {
+        old: Some(4),
+        new: 5,
+        resync_necessary: false,
+        migration_fun: migrate_v4_to_v5,
+    },
+    Migration {
+        old: Some(5),
+        new: 6,
+        resync_necessary: false,
+        migration_fun: migrate_v5_to_v6,
+    },
 ];
 
 #[derive(Debug, Error)]
@@ -485,7 +1825,7 @@ pub enum MigrationError {
 
 pub struct Migrator<'a> {
     conn: Connection,
-    migration: Option<&'a Migration>,
+    migrations: Vec<&'a Migration>,
 }
 
 impl<'a> Migrator<'a> {
@@ -501,68 +1841,96 @@ impl<'a> Migrator<'a> {
         Self::open_(file, target)
     }
 
-    // We don't try to find multi step migrations.
+    // Multi step migrations are found by chaining the single step ones,
+    // we always pick the path with the fewest steps.
     fn open_(file: &Path, target: VersionId) -> Result<Self, MigrationError> {
-        let conn = Connection::open(file)?;
-        conn.pragma_update(None, "journal_mode", "WAL")?;
-        conn.pragma_update(None, "synchronous", "NORMAL")?;
-        // This is only used in V0
-        conn.create_scalar_function(
-            "path_parent",
-            1,
-            FunctionFlags::SQLITE_UTF8
-                | FunctionFlags::SQLITE_DETERMINISTIC
-                | FunctionFlags::SQLITE_INNOCUOUS,
-            |ctx| {
-                let path = Utf8Path::new(ctx.get_raw(0).as_str()?);
-                let parent = path.parent().map(ToOwned::to_owned);
-                Ok(parent.and_then(|p| {
-                    let s = p.to_string();
-                    if s.is_empty() {
-                        None
-                    } else {
-                        Some(s)
-                    }
-                }))
-            },
-        )?;
-        conn.trace_v2(
-            TraceEventCodes::SQLITE_TRACE_PROFILE,
-            Some(|e| {
-                if let TraceEvent::Profile(stmt, duration) = e {
-                    trace!("SQL {} (took {:#?})", stmt.sql(), duration);
-                }
-            }),
-        );
+        let conn = open_connection(file)?;
         let current = determine_version(&conn)?;
-        if current == Some(target) {
-            return Ok(Migrator { conn, migration: None });
-        }
-        if let Some(migration) =
-            MIGRATIONS.iter().find(|m| m.old == current && m.new == target)
-        {
-            Ok(Migrator { conn, migration: Some(migration) })
-        } else {
-            Err(MigrationError::NoMigrationPath { old: current, new: target })
+        let mut queue: VecDeque<(Option<VersionId>, Vec<&Migration>)> =
+            VecDeque::from([(current, vec![])]);
+        let mut visited = HashSet::from([current]);
+        while let Some((version, migrations)) = queue.pop_front() {
+            if version == Some(target) {
+                return Ok(Migrator { conn, migrations });
+            }
+            for migration in MIGRATIONS.iter().filter(|m| m.old == version) {
+                if visited.insert(Some(migration.new)) {
+                    let mut migrations = migrations.clone();
+                    migrations.push(migration);
+                    queue.push_back((Some(migration.new), migrations));
+                }
+            }
         }
+        Err(MigrationError::NoMigrationPath { old: current, new: target })
     }
 
     pub fn migrate(mut self) -> Result<Cache, rusqlite::Error> {
-        if let Some(migration) = self.migration {
+        for migration in self.migrations {
             (migration.migration_fun)(&mut self.conn)?;
         }
-        Ok(Cache { conn: self.conn })
+        Ok(Cache { conn: self.conn, selected_snapshots: None })
     }
 
     pub fn need_to_migrate(&self) -> Option<(Option<VersionId>, VersionId)> {
-        self.migration.map(|m| (m.old, m.new))
+        let first = self.migrations.first()?;
+        let last = self.migrations.last()?;
+        Some((first.old, last.new))
     }
 
     pub fn resync_necessary(&self) -> bool {
-        self.migration.map(|m| m.resync_necessary).unwrap_or(false)
+        self.migrations.iter().any(|m| m.resync_necessary)
     }
 }
 
+fn open_connection(file: &Path) -> Result<Connection, rusqlite::Error> {
+    let conn = Connection::open(file)?;
+    conn.pragma_update(None, "journal_mode", "WAL")?;
+    conn.pragma_update(None, "synchronous", "NORMAL")?;
+    // This is only used in V0
+    conn.create_scalar_function(
+        "path_parent",
+        1,
+        FunctionFlags::SQLITE_UTF8
+            | FunctionFlags::SQLITE_DETERMINISTIC
+            | FunctionFlags::SQLITE_INNOCUOUS,
+        |ctx| {
+            let path = Utf8Path::new(ctx.get_raw(0).as_str()?);
+            let parent = path.parent().map(ToOwned::to_owned);
+            Ok(parent.and_then(|p| {
+                let s = p.to_string();
+                if s.is_empty() {
+                    None
+                } else {
+                    Some(s)
+                }
+            }))
+        },
+    )?;
+    // glob_match(pattern, text), see `Glob`
+    conn.create_scalar_function(
+        "glob_match",
+        2,
+        FunctionFlags::SQLITE_UTF8
+            | FunctionFlags::SQLITE_DETERMINISTIC
+            | FunctionFlags::SQLITE_INNOCUOUS,
+        |ctx| {
+            let glob = ctx.get_or_create_aux(0, |pattern| {
+                Glob::new(pattern.as_str()?).map_err(BoxError::from)
+            })?;
+            Ok(glob.matches(ctx.get_raw(1).as_str()?))
+        },
+    )?;
+    conn.trace_v2(
+        TraceEventCodes::SQLITE_TRACE_PROFILE,
+        Some(|e| {
+            if let TraceEvent::Profile(stmt, duration) = e {
+                trace!("SQL {} (took {:#?})", stmt.sql(), duration);
+            }
+        }),
+    );
+    Ok(conn)
+}
+
 fn migrate_none_to_v0(conn: &mut Connection) -> Result<(), rusqlite::Error> {
     let tx = conn.transaction()?;
     tx.execute_batch(include_str!("cache/sql/none_to_v0.sql"))?;
@@ -575,12 +1943,66 @@ fn migrate_none_to_v1(conn: &mut Connection) -> Result<(), rusqlite::Error> {
     tx.commit()
 }
 
+fn migrate_none_to_v6(conn: &mut Connection) -> Result<(), rusqlite::Error> {
+    let tx = conn.transaction()?;
+    tx.execute_batch(include_str!("cache/sql/none_to_v6.sql"))?;
+    tx.commit()
+}
+
 fn migrate_v0_to_v1(conn: &mut Connection) -> Result<(), rusqlite::Error> {
     let tx = conn.transaction()?;
     tx.execute_batch(include_str!("cache/sql/v0_to_v1.sql"))?;
     tx.commit()
 }
 
+fn migrate_v1_to_v2(conn: &mut Connection) -> Result<(), rusqlite::Error> {
+    let tx = conn.transaction()?;
+    tx.execute_batch(include_str!("cache/sql/v1_to_v2.sql"))?;
+    let tables = get_tables(&tx)?;
+    let hashes = tables
+        .iter()
+        .filter_map(|table| table.strip_prefix("entries_"))
+        .collect::<Vec<_>>();
+    aggregate_snapshot_entries(&tx, "aggregate_entries", &hashes, false)?;
+    tx.commit()
+}
+
+fn migrate_v2_to_v3(conn: &mut Connection) -> Result<(), rusqlite::Error> {
+    let tx = conn.transaction()?;
+    tx.execute_batch(include_str!("cache/sql/v2_to_v3.sql"))?;
+    tx.commit()
+}
+
+fn migrate_v3_to_v4(conn: &mut Connection) -> Result<(), rusqlite::Error> {
+    let tx = conn.transaction()?;
+    tx.execute_batch(include_str!("cache/sql/v3_to_v4.sql"))?;
+    tx.commit()
+}
+
+fn migrate_v4_to_v5(conn: &mut Connection) -> Result<(), rusqlite::Error> {
+    let tx = conn.transaction()?;
+    tx.execute_batch(include_str!("cache/sql/v4_to_v5.sql"))?;
+    tx.commit()
+}
+
+fn migrate_v5_to_v6(conn: &mut Connection) -> Result<(), rusqlite::Error> {
+    let tx = conn.transaction()?;
+    tx.execute_batch(include_str!("cache/sql/v5_to_v6.sql"))?;
+    for table in get_tables(&tx)? {
+        if table.starts_with("entries_") || table == "staged_files" {
+            for (name, sql_type) in METADATA_COLUMNS {
+                tx.execute(
+                    &format!(
+                        "ALTER TABLE \"{table}\" ADD COLUMN {name} {sql_type}"
+                    ),
+                    [],
+                )?;
+            }
+        }
+    }
+    tx.commit()
+}
+
 fn determine_version(
     conn: &Connection,
 ) -> Result<Option<VersionId>, MigrationError> {
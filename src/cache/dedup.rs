@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+
+/// Computes the unique size of paths, that is, how much space would be
+/// freed in the repository by removing a path from every snapshot.
+///
+/// A blob only counts towards a path if all the files that reference it,
+/// across all snapshots, are under that path.
+/// So we keep the lowest common ancestor of all the references to each blob,
+/// and the blob counts towards that node and all of its ancestors.
+#[derive(Debug)]
+pub struct UniqueSizes {
+    /// Node 0 is the root, it is not a path itself.
+    /// Parents always come before their children.
+    nodes: Vec<Node>,
+    children: HashMap<(usize, Box<str>), usize>,
+    /// The lowest common ancestor of all the references to each blob
+    blobs: HashMap<Box<str>, usize>,
+}
+
+#[derive(Debug)]
+struct Node {
+    parent: usize,
+    depth: usize,
+    component: Box<str>,
+}
+
+impl UniqueSizes {
+    pub fn new() -> Self {
+        UniqueSizes {
+            nodes: vec![Node { parent: 0, depth: 0, component: "".into() }],
+            children: HashMap::new(),
+            blobs: HashMap::new(),
+        }
+    }
+
+    /// The root, it is not a path itself.
+    pub const ROOT: usize = 0;
+
+    /// Record that the file at `path` references `blobs`.
+    pub fn add_file<C, B>(
+        &mut self,
+        path: impl IntoIterator<Item = C>,
+        blobs: impl IntoIterator<Item = B>,
+    ) where
+        C: AsRef<str>,
+        B: AsRef<str>,
+    {
+        let node = self.get_or_insert(path);
+        self.add_blobs(node, blobs);
+    }
+
+    /// Record that the file at `node` (see `child`) references `blobs`.
+    pub fn add_blobs<B: AsRef<str>>(
+        &mut self,
+        node: usize,
+        blobs: impl IntoIterator<Item = B>,
+    ) {
+        for blob in blobs {
+            if let Some(ancestor) = self.blobs.get_mut(blob.as_ref()) {
+                *ancestor =
+                    lowest_common_ancestor(&self.nodes, *ancestor, node);
+            } else {
+                self.blobs.insert(Box::from(blob.as_ref()), node);
+            }
+        }
+    }
+
+    /// The node of the path `component` below the path of `node`,
+    /// `ROOT` for the top level paths.
+    pub fn child(&mut self, node: usize, component: &str) -> usize {
+        let key = (node, Box::from(component));
+        match self.children.get(&key) {
+            Some(child) => *child,
+            None => {
+                let child = self.nodes.len();
+                self.nodes.push(Node {
+                    parent: node,
+                    depth: self.nodes[node].depth + 1,
+                    component: key.1.clone(),
+                });
+                self.children.insert(key, child);
+                child
+            }
+        }
+    }
+
+    /// Returns `(level, component, unique size)` for every path,
+    /// depth first with parents before children.
+    /// The top level paths are at level 1.
+    pub fn finish(
+        self,
+        blob_sizes: &HashMap<String, usize>,
+    ) -> Vec<(usize, Box<str>, usize)> {
+        let mut sizes = vec![0; self.nodes.len()];
+        for (blob, node) in self.blobs {
+            sizes[node] += blob_sizes.get(blob.as_ref()).copied().unwrap_or(0);
+        }
+        let mut children = vec![Vec::new(); self.nodes.len()];
+        for i in (1..self.nodes.len()).rev() {
+            let parent = self.nodes[i].parent;
+            sizes[parent] += sizes[i];
+            children[parent].push(i);
+        }
+
+        let mut nodes = self.nodes;
+        let mut result = Vec::with_capacity(nodes.len() - 1);
+        // The children lists are reversed so popping yields them in order
+        let mut stack = children[0].clone();
+        while let Some(i) = stack.pop() {
+            let component = std::mem::take(&mut nodes[i].component);
+            result.push((nodes[i].depth, component, sizes[i]));
+            stack.extend(children[i].iter());
+        }
+        result
+    }
+
+    fn get_or_insert<C: AsRef<str>>(
+        &mut self,
+        path: impl IntoIterator<Item = C>,
+    ) -> usize {
+        path.into_iter().fold(Self::ROOT, |node, component| {
+            self.child(node, component.as_ref())
+        })
+    }
+}
+
+impl Default for UniqueSizes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn lowest_common_ancestor(nodes: &[Node], mut a: usize, mut b: usize) -> usize {
+    while nodes[a].depth > nodes[b].depth {
+        a = nodes[a].parent;
+    }
+    while nodes[b].depth > nodes[a].depth {
+        b = nodes[b].parent;
+    }
+    while a != b {
+        a = nodes[a].parent;
+        b = nodes[b].parent;
+    }
+    a
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::UniqueSizes;
+
+    #[test]
+    fn unique_sizes_test() {
+        let blob_sizes: HashMap<String, usize> =
+            [("b0", 1), ("b1", 10), ("b2", 100), ("b3", 1000)]
+                .into_iter()
+                .map(|(id, size)| (id.to_string(), size))
+                .collect();
+        let mut unique_sizes = UniqueSizes::new();
+        unique_sizes.add_file(["a", "0", "x"], ["b0", "b1"]);
+        unique_sizes.add_file(["a", "0", "y"], ["b1"]);
+        unique_sizes.add_file(["a", "1", "x"], ["b2"]);
+        // Same file in another snapshot, but now sharing b0
+        unique_sizes.add_file(["a", "1", "x"], ["b2", "b0"]);
+        unique_sizes.add_file(["b"], ["b3"]);
+        unique_sizes.add_file(["c"], Vec::<&str>::new());
+        let sizes = unique_sizes
+            .finish(&blob_sizes)
+            .into_iter()
+            .map(|(level, component, size)| (level, component.into(), size))
+            .collect::<Vec<(usize, String, usize)>>();
+        assert_eq!(
+            sizes,
+            vec![
+                (1, "a".into(), 111),
+                (2, "0".into(), 10),
+                (3, "x".into(), 0),
+                (3, "y".into(), 0),
+                (2, "1".into(), 100),
+                (3, "x".into(), 100),
+                (1, "b".into(), 1000),
+                (1, "c".into(), 0),
+            ]
+        );
+    }
+}
@@ -1,7 +1,9 @@
 use std::{
-    cmp::max,
-    collections::{hash_map, HashMap},
+    cmp::{max, Ordering},
+    collections::HashMap,
     iter::Peekable,
+    slice,
+    sync::Arc,
 };
 
 use thiserror::Error;
@@ -44,123 +46,82 @@ impl SizeTree {
         C: AsRef<str>,
         P: IntoIterator<Item = C>,
     {
-        let (mut breadcrumbs, mut remaining) = {
-            let (breadcrumbs, remaining) = self.0.find(path);
-            (breadcrumbs, remaining.peekable())
-        };
+        let (breadcrumbs, mut remaining) = self.0.find(path);
         if remaining.peek().is_none() {
             return Err(InsertError::EntryExists);
         }
 
         // Update existing ancestors
-        for node in breadcrumbs.iter_mut() {
-            unsafe { (**node).data += size };
+        for &node in &breadcrumbs {
+            self.0.nodes[node as usize].data += size;
         }
 
         // Create the rest
-        let mut current_node: &mut Node<usize> = {
-            if let Some(last) = breadcrumbs.pop() {
-                unsafe { &mut *last }
-            } else if let Some(component) = remaining.next() {
-                self.0
-                    .children
-                    .entry(Box::from(component.as_ref()))
-                    .or_insert(Node::new(size))
-            } else {
-                return Err(InsertError::EmptyPath);
-            }
-        };
+        let mut parent = breadcrumbs.last().copied();
         for component in remaining {
-            current_node = current_node
-                .children
-                .entry(Box::from(component.as_ref()))
-                .or_insert(Node::new(0));
-            current_node.data = size;
+            parent = Some(self.0.add_child(parent, component.as_ref(), size));
         }
 
         Ok(())
     }
 }
 
-#[derive(Clone, Debug, Default, Eq, PartialEq)]
+/// Index of a node in `FileTree::nodes`.
+type NodeId = u32;
+
+/// Index of a component in `FileTree::names`.
+type NameId = u32;
+
+/// The nodes live in a single arena and refer to each other by index,
+/// each distinct component is only stored once.
+#[derive(Clone, Debug)]
 pub struct FileTree<T> {
-    children: HashMap<Box<str>, Node<T>>,
+    nodes: Vec<Node<T>>,
+    /// The top level nodes, sorted by name
+    roots: Vec<NodeId>,
+    names: Vec<Arc<str>>,
+    name_ids: HashMap<Arc<str>, NameId>,
 }
 
-#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[derive(Clone, Debug)]
 struct Node<T> {
+    name: NameId,
     data: T,
-    children: HashMap<Box<str>, Node<T>>,
+    /// Sorted by name
+    children: Vec<NodeId>,
 }
 
 impl<T> FileTree<T> {
     pub fn new() -> Self {
-        FileTree { children: HashMap::new() }
+        FileTree {
+            nodes: Vec::new(),
+            roots: Vec::new(),
+            names: Vec::new(),
+            name_ids: HashMap::new(),
+        }
     }
 
     pub fn merge<F>(self, other: Self, mut combine: F) -> Self
     where
         F: FnMut(T, T) -> T,
     {
-        fn merge_children<T, F: FnMut(T, T) -> T>(
-            a: HashMap<Box<str>, Node<T>>,
-            b: HashMap<Box<str>, Node<T>>,
-            f: &mut F,
-        ) -> HashMap<Box<str>, Node<T>> {
-            let mut sorted_a = sorted_hashmap(a).into_iter();
-            let mut sorted_b = sorted_hashmap(b).into_iter();
-            let mut children = HashMap::new();
-            loop {
-                match (sorted_a.next(), sorted_b.next()) {
-                    (Some((name0, tree0)), Some((name1, tree1))) => {
-                        if name0 == name1 {
-                            children.insert(name0, merge_node(tree0, tree1, f));
-                        } else {
-                            children.insert(name0, tree0);
-                            children.insert(name1, tree1);
-                        }
-                    }
-                    (None, Some((name, tree))) => {
-                        children.insert(name, tree);
-                    }
-                    (Some((name, tree)), None) => {
-                        children.insert(name, tree);
-                    }
-                    (None, None) => {
-                        break;
-                    }
-                }
-            }
-            children
-        }
-
-        // This exists to be able to reuse `combine` multiple times in the loop
-        // without being consumed by the recursive calls
-        fn merge_node<T, F: FnMut(T, T) -> T>(
-            a: Node<T>,
-            b: Node<T>,
-            f: &mut F,
-        ) -> Node<T> {
-            Node {
-                data: f(a.data, b.data),
-                children: merge_children(a.children, b.children, f),
-            }
-        }
-
-        FileTree {
-            children: merge_children(
-                self.children,
-                other.children,
-                &mut combine,
-            ),
-        }
+        let mut merged = FileTree::new();
+        let mut a = MergeSource::new(self);
+        let mut b = MergeSource::new(other);
+        let (a_roots, b_roots) = (a.roots.clone(), b.roots.clone());
+        merged.roots = merged.merge_children(
+            &mut a,
+            a_roots,
+            &mut b,
+            b_roots,
+            &mut combine,
+        );
+        merged
     }
 
-    /// Depth first, parent before children
+    /// Depth first, parent before children, siblings sorted by name
     pub fn iter(&self) -> Iter<'_, T> {
-        let breadcrumb =
-            Breadcrumb { level: 1, children: self.children.iter() };
-        Iter { stack: vec![breadcrumb] }
+        Iter { tree: self, stack: vec![(1, self.roots.iter())] }
     }
 
     /// Traverse the tree while keeping a context.
@@ -201,64 +162,202 @@ impl<T> FileTree<T> {
 
     /// Returns the breadcrumbs of the largest prefix of the path.
     /// If the file is in the tree the last breadcrumb will be the file itself.
-    /// Does not modify self at all.
     /// The cdr is the remaining path that did not match, if any.
-    fn find<C, P>(
-        &mut self,
-        path: P,
-    ) -> (Vec<*mut Node<T>>, impl Iterator<Item = C>)
+    fn find<C, P>(&self, path: P) -> (Vec<NodeId>, Peekable<P::IntoIter>)
     where
         C: AsRef<str>,
         P: IntoIterator<Item = C>,
     {
-        let mut iter = path.into_iter().peekable();
-        if let Some(component) = iter.peek() {
-            let component = component.as_ref();
-            if let Some(node) = self.children.get_mut(component) {
-                iter.next();
-                return node.find(iter);
+        let mut path = path.into_iter().peekable();
+        let mut breadcrumbs = Vec::new();
+        while let Some(component) = path.peek() {
+            match self
+                .find_child(breadcrumbs.last().copied(), component.as_ref())
+            {
+                Ok(node) => {
+                    breadcrumbs.push(node);
+                    path.next();
+                }
+                Err(_) => break,
             }
         }
-        (vec![], iter)
+        (breadcrumbs, path)
     }
-}
 
-impl<T> Node<T> {
-    fn new(data: T) -> Self {
-        Node { data, children: HashMap::new() }
+    /// The child of `parent` (`None` is the top level) with that name,
+    /// or the position where it would go.
+    fn find_child(
+        &self,
+        parent: Option<NodeId>,
+        name: &str,
+    ) -> Result<NodeId, usize> {
+        let children = self.children(parent);
+        children
+            .binary_search_by(|&child| self.name(child).cmp(name))
+            .map(|index| children[index])
     }
 
-    fn find<C, P>(
+    fn add_child(
         &mut self,
-        mut path: Peekable<P>,
-    ) -> (Vec<*mut Node<T>>, Peekable<P>)
+        parent: Option<NodeId>,
+        name: &str,
+        data: T,
+    ) -> NodeId {
+        let index = match self.find_child(parent, name) {
+            Ok(_) => panic!("{name:?} is already in the tree"),
+            Err(index) => index,
+        };
+        let node = self.push_node(name, data);
+        match parent {
+            None => &mut self.roots,
+            Some(parent) => &mut self.nodes[parent as usize].children,
+        }
+        .insert(index, node);
+        node
+    }
+
+    /// Adds a node without a parent.
+    fn push_node(&mut self, name: &str, data: T) -> NodeId {
+        let name = match self.name_ids.get(name) {
+            Some(&name) => name,
+            None => {
+                let id = NameId::try_from(self.names.len())
+                    .expect("too many names in the tree");
+                let name = Arc::<str>::from(name);
+                self.names.push(name.clone());
+                self.name_ids.insert(name, id);
+                id
+            }
+        };
+        let node = NodeId::try_from(self.nodes.len())
+            .expect("too many nodes in the tree");
+        self.nodes.push(Node { name, data, children: Vec::new() });
+        node
+    }
+
+    fn children(&self, parent: Option<NodeId>) -> &[NodeId] {
+        match parent {
+            None => &self.roots,
+            Some(parent) => &self.nodes[parent as usize].children,
+        }
+    }
+
+    fn name(&self, node: NodeId) -> &str {
+        &self.names[self.nodes[node as usize].name as usize]
+    }
+
+    /// Both lists are sorted by name, so this goes through them side by side.
+    fn merge_children<F>(
+        &mut self,
+        a: &mut MergeSource<T>,
+        a_children: Vec<NodeId>,
+        b: &mut MergeSource<T>,
+        b_children: Vec<NodeId>,
+        f: &mut F,
+    ) -> Vec<NodeId>
     where
-        C: AsRef<str>,
-        P: Iterator<Item = C>,
+        F: FnMut(T, T) -> T,
     {
-        let mut breadcrumbs: Vec<*mut Node<T>> = vec![self];
-        while let Some(c) = path.peek() {
-            let c = c.as_ref();
-            let current = unsafe { &mut **breadcrumbs.last().unwrap() };
-            match current.children.get_mut(c) {
-                Some(next) => {
-                    breadcrumbs.push(next);
-                    path.next();
+        let mut children =
+            Vec::with_capacity(max(a_children.len(), b_children.len()));
+        let mut a_children = a_children.into_iter().peekable();
+        let mut b_children = b_children.into_iter().peekable();
+        loop {
+            let order = match (a_children.peek(), b_children.peek()) {
+                (Some(&a_node), Some(&b_node)) => {
+                    a.name(a_node).cmp(b.name(b_node))
                 }
-                None => break,
-            }
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => break,
+            };
+            let node = match order {
+                Ordering::Less => {
+                    self.copy_subtree(a, a_children.next().unwrap())
+                }
+                Ordering::Greater => {
+                    self.copy_subtree(b, b_children.next().unwrap())
+                }
+                Ordering::Equal => {
+                    let a_node = a.take(a_children.next().unwrap());
+                    let b_node = b.take(b_children.next().unwrap());
+                    let node = self.push_node(&a_node.0, f(a_node.1, b_node.1));
+                    let grandchildren =
+                        self.merge_children(a, a_node.2, b, b_node.2, f);
+                    self.nodes[node as usize].children = grandchildren;
+                    node
+                }
+            };
+            children.push(node);
         }
-        (breadcrumbs, path)
+        children
+    }
+
+    fn copy_subtree(
+        &mut self,
+        source: &mut MergeSource<T>,
+        node: NodeId,
+    ) -> NodeId {
+        let (name, data, source_children) = source.take(node);
+        let node = self.push_node(&name, data);
+        let children = source_children
+            .into_iter()
+            .map(|child| self.copy_subtree(source, child))
+            .collect();
+        self.nodes[node as usize].children = children;
+        node
     }
 }
 
-pub struct Iter<'a, T> {
-    stack: Vec<Breadcrumb<'a, T>>,
+impl<T> Default for FileTree<T> {
+    fn default() -> Self {
+        FileTree::new()
+    }
 }
 
-struct Breadcrumb<'a, T> {
-    level: usize,
-    children: hash_map::Iter<'a, Box<str>, Node<T>>,
+/// Two trees are equal if they have the same paths with the same data,
+/// no matter how the nodes are laid out in the arena.
+impl<T: PartialEq> PartialEq for FileTree<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.iter().eq(other.iter())
+    }
+}
+
+impl<T: Eq> Eq for FileTree<T> {}
+
+/// The nodes of a tree that is being merged into another one,
+/// each node is taken out exactly once.
+struct MergeSource<T> {
+    nodes: Vec<Option<Node<T>>>,
+    roots: Vec<NodeId>,
+    names: Vec<Arc<str>>,
+}
+
+impl<T> MergeSource<T> {
+    fn new(tree: FileTree<T>) -> Self {
+        MergeSource {
+            nodes: tree.nodes.into_iter().map(Some).collect(),
+            roots: tree.roots,
+            names: tree.names,
+        }
+    }
+
+    fn name(&self, node: NodeId) -> &str {
+        let node = self.nodes[node as usize].as_ref().unwrap();
+        &self.names[node.name as usize]
+    }
+
+    /// (name, data, children)
+    fn take(&mut self, node: NodeId) -> (Arc<str>, T, Vec<NodeId>) {
+        let node = self.nodes[node as usize].take().unwrap();
+        (self.names[node.name as usize].clone(), node.data, node.children)
+    }
+}
+
+pub struct Iter<'a, T> {
+    tree: &'a FileTree<T>,
+    /// The level and the children that are left at each level
+    stack: Vec<(usize, slice::Iter<'a, NodeId>)>,
 }
 
 impl<'a, T> Iterator for Iter<'a, T> {
@@ -268,31 +367,25 @@ impl<'a, T> Iterator for Iter<'a, T> {
     /// Depth first, parent before children
     fn next(&mut self) -> Option<Self::Item> {
         loop {
-            if let Some(mut breadcrumb) = self.stack.pop() {
-                if let Some((component, child)) = breadcrumb.children.next() {
-                    let level = breadcrumb.level + 1;
-                    let item = (
-                        level,
-                        component as &str,
-                        &child.data,
-                        !child.children.is_empty(),
-                    );
-                    self.stack.push(breadcrumb);
-                    self.stack.push(Breadcrumb {
+            let (level, children) = self.stack.last_mut()?;
+            match children.next() {
+                Some(&node) => {
+                    let level = *level + 1;
+                    let tree = self.tree;
+                    let Node { name, data, children } =
+                        &tree.nodes[node as usize];
+                    self.stack.push((level, children.iter()));
+                    break Some((
                         level,
-                        children: child.children.iter(),
-                    });
-                    break Some(item);
+                        &tree.names[*name as usize],
+                        data,
+                        !children.is_empty(),
+                    ));
+                }
+                None => {
+                    self.stack.pop();
                 }
-            } else {
-                break None;
             }
         }
     }
 }
-
-fn sorted_hashmap<K: Ord, V>(m: HashMap<K, V>) -> Vec<(K, V)> {
-    let mut vec = m.into_iter().collect::<Vec<_>>();
-    vec.sort_unstable_by(|(k0, _), (k1, _)| k0.cmp(k1));
-    vec
-}
@@ -0,0 +1,104 @@
+CREATE TABLE metadata_integer (
+    key TEXT PRIMARY KEY,
+    value INTEGER NOT NULL
+) WITHOUT ROWID;
+INSERT INTO metadata_integer (key, value) VALUES ('version', 6);
+
+CREATE TABLE paths (
+    id INTEGER PRIMARY KEY,
+    parent_id INTEGER NOT NULL,
+    component TEXT NOT NULL
+);
+CREATE UNIQUE INDEX paths_parent_component ON paths (parent_id, component);
+
+CREATE TABLE snapshots (
+    hash TEXT PRIMARY KEY,
+	time INTEGER,
+    parent TEXT,
+    tree TEXT NOT NULL,
+    hostname TEXT,
+    username TEXT,
+    uid INTEGER,
+    gid INTEGER,
+    original_id TEXT,
+    program_version TEXT
+) WITHOUT ROWID;
+CREATE TABLE snapshot_paths (
+    hash TEXT,
+    path TEXT,
+    PRIMARY KEY (hash, path)
+) WITHOUT ROWID;
+CREATE TABLE snapshot_excludes (
+    hash TEXT,
+    path TEXT,
+    PRIMARY KEY (hash, path)
+) WITHOUT ROWID;
+CREATE TABLE snapshot_tags (
+    hash TEXT,
+    tag TEXT,
+    PRIMARY KEY (hash, tag)
+) WITHOUT ROWID;
+
+-- The entries tables are sharded per snapshot and created dynamically
+
+CREATE TABLE marks (path TEXT PRIMARY KEY) WITHOUT ROWID;
+
+-- The entries of all snapshots merged together.
+-- This is kept up to date when saving or deleting snapshots.
+CREATE TABLE aggregate_entries (
+    path_id INTEGER PRIMARY KEY,
+    is_dir INTEGER NOT NULL,
+    max_size INTEGER NOT NULL,
+    max_size_snapshot_hash TEXT NOT NULL,
+    max_size_time INTEGER NOT NULL,
+    first_seen INTEGER NOT NULL,
+    first_seen_snapshot_hash TEXT NOT NULL,
+    last_seen INTEGER NOT NULL,
+    last_seen_snapshot_hash TEXT NOT NULL,
+    FOREIGN KEY (path_id) REFERENCES paths (id)
+);
+
+-- How much would be freed by removing each path from all snapshots.
+-- This is expensive to compute so it is only done on demand,
+-- it is cleared whenever the snapshots change.
+CREATE TABLE unique_sizes (
+    path_id INTEGER PRIMARY KEY,
+    size INTEGER NOT NULL,
+    FOREIGN KEY (path_id) REFERENCES paths (id)
+);
+
+-- Marks that are restic exclude patterns (e.g. **/node_modules)
+-- instead of literal paths.
+CREATE TABLE pattern_marks (pattern TEXT PRIMARY KEY) WITHOUT ROWID;
+
+-- The files of the snapshots that are still being fetched.
+-- They are saved in chunks so that an interrupted sync can continue
+-- where it stopped instead of fetching them again from the start.
+CREATE TABLE staged_snapshots (
+    hash TEXT PRIMARY KEY,
+    -- The cached snapshot it is fetched incrementally from, if any
+    base TEXT,
+    -- How many files are staged
+    files INTEGER NOT NULL,
+    -- The path of the last staged file, to check that a resumed fetch
+    -- lists the files in the same order
+    last_path TEXT,
+    -- All the files are staged, it only needs to be saved
+    complete INTEGER NOT NULL
+) WITHOUT ROWID;
+
+-- The metadata columns are the same as in the entries tables,
+-- see `METADATA_COLUMNS`.
+CREATE TABLE staged_files (
+    hash TEXT NOT NULL,
+    path TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    file_type TEXT,
+    mtime INTEGER,
+    mode INTEGER,
+    uid INTEGER,
+    gid INTEGER,
+    inode INTEGER,
+    links INTEGER,
+    PRIMARY KEY (hash, path)
+) WITHOUT ROWID;
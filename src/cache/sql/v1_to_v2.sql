@@ -0,0 +1,16 @@
+UPDATE metadata_integer SET value = 2 WHERE key = 'version';
+
+-- The entries of all snapshots merged together.
+-- This is kept up to date when saving or deleting snapshots.
+CREATE TABLE aggregate_entries (
+    path_id INTEGER PRIMARY KEY,
+    is_dir INTEGER NOT NULL,
+    max_size INTEGER NOT NULL,
+    max_size_snapshot_hash TEXT NOT NULL,
+    max_size_time INTEGER NOT NULL,
+    first_seen INTEGER NOT NULL,
+    first_seen_snapshot_hash TEXT NOT NULL,
+    last_seen INTEGER NOT NULL,
+    last_seen_snapshot_hash TEXT NOT NULL,
+    FOREIGN KEY (path_id) REFERENCES paths (id)
+);
@@ -0,0 +1,10 @@
+UPDATE metadata_integer SET value = 3 WHERE key = 'version';
+
+-- How much would be freed by removing each path from all snapshots.
+-- This is expensive to compute so it is only done on demand,
+-- it is cleared whenever the snapshots change.
+CREATE TABLE unique_sizes (
+    path_id INTEGER PRIMARY KEY,
+    size INTEGER NOT NULL,
+    FOREIGN KEY (path_id) REFERENCES paths (id)
+);
@@ -0,0 +1,5 @@
+UPDATE metadata_integer SET value = 4 WHERE key = 'version';
+
+-- Marks that are restic exclude patterns (e.g. **/node_modules)
+-- instead of literal paths.
+CREATE TABLE pattern_marks (pattern TEXT PRIMARY KEY) WITHOUT ROWID;
@@ -0,0 +1,24 @@
+UPDATE metadata_integer SET value = 5 WHERE key = 'version';
+
+-- The files of the snapshots that are still being fetched.
+-- They are saved in chunks so that an interrupted sync can continue
+-- where it stopped instead of fetching them again from the start.
+CREATE TABLE staged_snapshots (
+    hash TEXT PRIMARY KEY,
+    -- The cached snapshot it is fetched incrementally from, if any
+    base TEXT,
+    -- How many files are staged
+    files INTEGER NOT NULL,
+    -- The path of the last staged file, to check that a resumed fetch
+    -- lists the files in the same order
+    last_path TEXT,
+    -- All the files are staged, it only needs to be saved
+    complete INTEGER NOT NULL
+) WITHOUT ROWID;
+
+CREATE TABLE staged_files (
+    hash TEXT NOT NULL,
+    path TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    PRIMARY KEY (hash, path)
+) WITHOUT ROWID;
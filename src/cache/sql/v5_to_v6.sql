@@ -0,0 +1,9 @@
+UPDATE metadata_integer SET value = 6 WHERE key = 'version';
+
+-- The metadata columns of the files (type, mtime, mode, uid, gid, inode
+-- and links) are added to the entries tables and to staged_files by
+-- `migrate_v5_to_v6`, they are NULL for the snapshots that are already
+-- cached.
+-- The files that are staged have no metadata, they are fetched again.
+DELETE FROM staged_snapshots;
+DELETE FROM staged_files;
@@ -1,20 +1,26 @@
 use std::{
-    cmp::Reverse, collections::HashSet, convert::Infallible, env, fs, iter,
-    mem, path::PathBuf,
+    cmp::{max, Reverse},
+    collections::{BTreeMap, HashSet},
+    convert::Infallible,
+    env, fs, iter, mem,
+    path::PathBuf,
 };
 
 use camino::{Utf8Path, Utf8PathBuf};
 use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use rusqlite::Connection;
 use uuid::Uuid;
 
 use crate::{
     cache::{
+        dedup::UniqueSizes,
         determine_version,
         filetree::{InsertError, SizeTree},
-        get_tables, timestamp_to_datetime, Cache, EntryDetails, Migrator,
+        get_tables, timestamp_to_datetime, Cache, EntryDetails, MarkEntry,
+        Migrator, StagedSnapshot, VersionId, LATEST_VERSION, METADATA_COLUMNS,
     },
-    restic::Snapshot,
+    restic::{File, FileMetadata, FileType, Snapshot},
 };
 
 pub fn mk_datetime(
@@ -48,6 +54,12 @@ impl Tempfile {
     }
 }
 
+impl Default for Tempfile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub fn path_parent(path: &Utf8Path) -> Option<Utf8PathBuf> {
     let parent = path.parent().map(ToOwned::to_owned);
     parent.and_then(|p| if p.as_str().is_empty() { None } else { Some(p) })
@@ -86,6 +98,10 @@ impl Iterator for PathGenerator {
     }
 }
 
+pub fn mk_file(path: &str, size: usize) -> File {
+    File { path: path.into(), size, metadata: FileMetadata::default() }
+}
+
 pub fn generate_sizetree(depth: usize, branching_factor: usize) -> SizeTree {
     let mut sizetree = SizeTree::new();
     for path in PathGenerator::new(depth, branching_factor) {
@@ -112,6 +128,60 @@ fn to_sorted_entries(tree: &SizeTree) -> Vec<(Vec<&str>, usize, bool)> {
     entries
 }
 
+/// The paths in the order of `SizeTree::iter`.
+fn to_entries(tree: &SizeTree) -> Vec<(Vec<String>, usize, bool)> {
+    let mut path: Vec<String> = Vec::new();
+    tree.iter()
+        .map(|(level, component, size, is_dir)| {
+            path.truncate(level - 2);
+            path.push(component.to_owned());
+            (path.clone(), size, is_dir)
+        })
+        .collect()
+}
+
+/// A `SizeTree` as a map from every node to its size.
+type SizeModel = BTreeMap<Vec<String>, usize>;
+
+fn model_insert(model: &mut SizeModel, path: &[String], size: usize) -> bool {
+    if path.is_empty() || model.contains_key(path) {
+        return false;
+    }
+    for i in 1..=path.len() {
+        *model.entry(path[..i].to_vec()).or_default() += size;
+    }
+    true
+}
+
+fn model_entries(model: &SizeModel) -> Vec<(Vec<String>, usize, bool)> {
+    model
+        .iter()
+        .map(|(path, &size)| {
+            let is_dir = model
+                .range(path.clone()..)
+                .nth(1)
+                .is_some_and(|(next, _)| next.starts_with(path));
+            (path.clone(), size, is_dir)
+        })
+        .collect()
+}
+
+fn random_trees(rng: &mut StdRng) -> (SizeTree, SizeModel) {
+    let mut tree = SizeTree::new();
+    let mut model = SizeModel::new();
+    for _ in 0..rng.random_range(0..200) {
+        let path = (0..rng.random_range(0..5))
+            .map(|_| {
+                ["a", "b", "c", "d", "e"][rng.random_range(0..5)].to_owned()
+            })
+            .collect::<Vec<_>>();
+        let size = rng.random_range(0..1000);
+        let inserted = tree.insert(&path, size).is_ok();
+        assert_eq!(inserted, model_insert(&mut model, &path, size));
+    }
+    (tree, model)
+}
+
 fn assert_get_entries_correct_at_path<P: AsRef<Utf8Path>>(
     cache: &Cache,
     tree: &SizeTree,
@@ -136,7 +206,7 @@ fn assert_get_entries_correct_at_path<P: AsRef<Utf8Path>>(
         }
     };
     db_entries.sort_by_key(|(component, _, _)| component.clone());
-    let mut entries = to_sorted_entries(&tree)
+    let mut entries = to_sorted_entries(tree)
         .iter()
         .filter_map(|(components, size, is_dir)| {
             // keep only the ones with parent == loc
@@ -184,6 +254,25 @@ fn example_tree_2() -> SizeTree {
     sizetree
 }
 
+/// A snapshot with only the id and time set to something interesting.
+fn mk_snapshot(id: &str, year: i32) -> Snapshot {
+    Snapshot {
+        id: id.to_string(),
+        time: mk_datetime(year, 4, 12, 12, 00, 00),
+        parent: None,
+        tree: id.to_string(),
+        paths: HashSet::new(),
+        hostname: None,
+        username: None,
+        uid: None,
+        gid: None,
+        excludes: HashSet::new(),
+        tags: HashSet::new(),
+        original_id: None,
+        program_version: None,
+    }
+}
+
 #[test]
 fn sizetree_iter_empty() {
     let sizetree = SizeTree::new();
@@ -272,6 +361,43 @@ fn insert_existing() {
     );
 }
 
+#[test]
+fn sizetree_iter_sorted() {
+    let mut sizetree = SizeTree::new();
+    for path in [["b", "y"], ["a", "z"], ["b", "x"], ["a", "x"]] {
+        sizetree.insert(path, 1).unwrap();
+    }
+    assert_eq!(
+        sizetree.iter().collect::<Vec<_>>(),
+        vec![
+            (2, "a", 2, true),
+            (3, "x", 1, false),
+            (3, "z", 1, false),
+            (2, "b", 2, true),
+            (3, "x", 1, false),
+            (3, "y", 1, false),
+        ]
+    );
+}
+
+#[test]
+fn sizetree_random_against_model() {
+    let mut rng = StdRng::seed_from_u64(0);
+    for _ in 0..100 {
+        let (tree0, model0) = random_trees(&mut rng);
+        let (tree1, model1) = random_trees(&mut rng);
+        assert_eq!(to_entries(&tree0), model_entries(&model0));
+        assert_eq!(to_entries(&tree1), model_entries(&model1));
+
+        let mut merged = model0;
+        for (path, size) in model1 {
+            let entry = merged.entry(path).or_default();
+            *entry = max(*entry, size);
+        }
+        assert_eq!(to_entries(&tree0.merge(tree1)), model_entries(&merged));
+    }
+}
+
 #[test]
 fn merge_test() {
     let tree = example_tree_0().merge(example_tree_1());
@@ -470,6 +596,318 @@ fn cache_snapshots_entries() {
     test_entries(&cache, example_tree_0().merge(example_tree_2()));
 }
 
+#[test]
+fn cache_get_files() {
+    let tempfile = Tempfile::new();
+    let mut cache = Migrator::open(&tempfile.0).unwrap().migrate().unwrap();
+    let snapshot = Snapshot {
+        id: "foo".to_string(),
+        time: mk_datetime(2024, 4, 12, 12, 00, 00),
+        parent: None,
+        tree: "sometree".to_string(),
+        paths: HashSet::new(),
+        hostname: None,
+        username: None,
+        uid: None,
+        gid: None,
+        excludes: HashSet::new(),
+        tags: HashSet::new(),
+        original_id: None,
+        program_version: None,
+    };
+    cache.save_snapshot(&snapshot, example_tree_0()).unwrap();
+    cache
+        .save_snapshot(
+            &Snapshot { id: "bar".to_string(), ..snapshot },
+            example_tree_1(),
+        )
+        .unwrap();
+
+    // Read from another connection like the fetching threads do
+    let reader = cache.reopen().unwrap();
+    let get_files = |hash: &str, path: &str| {
+        let path_id =
+            reader.get_path_id_by_path(Utf8Path::new(path)).unwrap().unwrap();
+        let mut files = reader.get_files(hash, path_id).unwrap();
+        files.sort();
+        files
+    };
+    assert_eq!(
+        get_files("foo", "a"),
+        vec![
+            ("0/x".into(), 1),
+            ("0/y".into(), 2),
+            ("0/z/0".into(), 1),
+            ("1/x/0".into(), 7),
+            ("1/x/1".into(), 2),
+        ]
+    );
+    assert_eq!(
+        get_files("foo", "a/1"),
+        vec![("x/0".into(), 7), ("x/1".into(), 2)]
+    );
+    assert_eq!(get_files("bar", "a/1"), vec![("x/1".into(), 1)]);
+    assert_eq!(get_files("foo", "a/0/x"), vec![]);
+    assert_eq!(get_files("foo", "a/2"), vec![]);
+}
+
+#[test]
+fn cache_staged_snapshots() {
+    let tempfile = Tempfile::new();
+    let mut cache = Migrator::open(&tempfile.0).unwrap().migrate().unwrap();
+    let files = |tree: &SizeTree| {
+        to_sorted_entries(tree)
+            .into_iter()
+            .filter(|(_, _, is_dir)| !is_dir)
+            .map(|(path, size, _)| mk_file(&path.join("/"), size))
+            .collect::<Vec<_>>()
+    };
+    let foo_files = files(&example_tree_0());
+    let (first, rest) = foo_files.split_at(2);
+    cache.stage_files("foo", None, first).unwrap();
+    cache.stage_files("bar", Some("foo"), &files(&example_tree_1())).unwrap();
+    cache.stage_files("foo", None, rest).unwrap();
+    cache.complete_staged_snapshot("foo", None).unwrap();
+
+    let mut staged = cache.get_staged_snapshots().unwrap();
+    staged.sort_by(|a, b| a.hash.cmp(&b.hash));
+    assert_eq!(
+        staged,
+        vec![
+            StagedSnapshot {
+                hash: "bar".into(),
+                base: Some("foo".into()),
+                files: files(&example_tree_1()).len(),
+                last_path: files(&example_tree_1())
+                    .last()
+                    .map(|f| f.path.clone()),
+                complete: false,
+            },
+            StagedSnapshot {
+                hash: "foo".into(),
+                base: None,
+                files: foo_files.len(),
+                last_path: foo_files.last().map(|f| f.path.clone()),
+                complete: true,
+            },
+        ]
+    );
+    // Nothing is visible until it's saved
+    assert!(cache.get_snapshots().unwrap().is_empty());
+
+    let foo = mk_snapshot("foo", 2024);
+    assert_eq!(
+        cache.save_staged_snapshot(&foo).unwrap(),
+        example_tree_0().iter().count()
+    );
+    let snapshots = cache.get_snapshots().unwrap();
+    assert_eq!(snapshots.len(), 1);
+    assert_eq!(snapshots[0].id, "foo");
+    for path in ["", "a", "a/0", "a/1", "a/1/x"] {
+        assert_get_entries_correct_at_path(&cache, &example_tree_0(), path);
+    }
+    let staged = cache.get_staged_snapshots().unwrap();
+    assert_eq!(staged.len(), 1);
+    assert_eq!(staged[0].hash, "bar");
+
+    cache.delete_staged_snapshot("bar").unwrap();
+    assert!(cache.get_staged_snapshots().unwrap().is_empty());
+}
+
+#[test]
+fn cache_save_staged_snapshot_streaming() {
+    // '.' and ' ' sort before '/', so the files of a directory
+    // are not next to each other when sorted by their path
+    let files = [
+        ("/a/b/c", 1),
+        ("/a.txt", 2),
+        ("/a b/x", 4),
+        ("/a/b/d/e", 8),
+        ("/a/b.txt", 16),
+        ("/a/b/d.txt", 32),
+        ("/z", 64),
+    ];
+    let mut tree = SizeTree::new();
+    for (path, size) in files {
+        tree.insert(Utf8Path::new(path).components(), size).unwrap();
+    }
+    let mut files = files
+        .iter()
+        .map(|(path, size)| mk_file(path, *size))
+        .collect::<Vec<_>>();
+    files.reverse();
+
+    let tempfile = Tempfile::new();
+    let mut cache = Migrator::open(&tempfile.0).unwrap().migrate().unwrap();
+    cache.stage_files("foo", None, &files[..3]).unwrap();
+    cache.stage_files("foo", None, &files[3..]).unwrap();
+    assert_eq!(
+        cache.save_staged_snapshot(&mk_snapshot("foo", 2024)).unwrap(),
+        tree.iter().count()
+    );
+    for path in ["", "/", "/a", "/a/b", "/a/b/d", "/a b"] {
+        assert_get_entries_correct_at_path(&cache, &tree, path);
+    }
+}
+
+#[test]
+fn cache_file_metadata() {
+    let metadata = FileMetadata {
+        file_type: Some(FileType::Symlink),
+        mtime: Some(mk_datetime(2020, 1, 2, 3, 4, 5)),
+        mode: Some(0o755),
+        uid: Some(1000),
+        gid: Some(100),
+        inode: Some(u64::MAX),
+        links: Some(2),
+    };
+    let tempfile = Tempfile::new();
+    let mut cache = Migrator::open(&tempfile.0).unwrap().migrate().unwrap();
+    cache
+        .stage_files(
+            "foo",
+            None,
+            &[
+                File { metadata: metadata.clone(), ..mk_file("/a/link", 0) },
+                mk_file("/a/x", 3),
+            ],
+        )
+        .unwrap();
+    cache.save_staged_snapshot(&mk_snapshot("foo", 2023)).unwrap();
+    cache.clone_snapshot(&mk_snapshot("bar", 2024), "foo").unwrap();
+
+    let details = |path: &str| {
+        let path_id = cache.get_path_id_by_path(path.into()).unwrap().unwrap();
+        let details = cache.get_entry_details(path_id).unwrap().unwrap();
+        (details.last_seen_snapshot_hash, details.metadata)
+    };
+    assert_eq!(details("/a/link"), ("bar".into(), metadata.clone()));
+    assert_eq!(details("/a/x"), ("bar".into(), FileMetadata::default()));
+    assert_eq!(details("/a"), ("bar".into(), FileMetadata::default()));
+
+    let path_id = cache.get_path_id_by_path("/a".into()).unwrap().unwrap();
+    let mut files = Vec::new();
+    cache
+        .for_each_file("bar", path_id, |file| {
+            files.push(file);
+            Ok::<(), rusqlite::Error>(())
+        })
+        .unwrap();
+    assert_eq!(
+        files,
+        vec![File { metadata, ..mk_file("link", 0) }, mk_file("x", 3)]
+    );
+}
+
+#[test]
+fn cache_files_modified_before() {
+    let modified = |path: &str, size, year: i32| File {
+        metadata: FileMetadata {
+            mtime: Some(mk_datetime(year, 1, 1, 0, 0, 0)),
+            ..FileMetadata::default()
+        },
+        ..mk_file(path, size)
+    };
+    let tempfile = Tempfile::new();
+    let mut cache = Migrator::open(&tempfile.0).unwrap().migrate().unwrap();
+    cache
+        .stage_files(
+            "foo",
+            None,
+            &[
+                modified("/a/b/c/d/deep", 5, 2019),
+                modified("/a/f", 2, 2020),
+                modified("/a/g", 3, 2020),
+                modified("/a/h", 1, 2020),
+                mk_file("/a/x", 4),
+                modified("/y", 6, 2020),
+            ],
+        )
+        .unwrap();
+    cache.save_staged_snapshot(&mk_snapshot("foo", 2023)).unwrap();
+    cache.stage_files("bar", None, &[modified("/a/f", 2, 2022)]).unwrap();
+    cache.save_staged_snapshot(&mk_snapshot("bar", 2024)).unwrap();
+
+    let path_id =
+        |path: &str| cache.get_path_id_by_path(path.into()).unwrap().unwrap();
+    let (a, deep, f, g, h, y) = (
+        path_id("/a"),
+        path_id("/a/b/c/d/deep"),
+        path_id("/a/f"),
+        path_id("/a/g"),
+        path_id("/a/h"),
+        path_id("/y"),
+    );
+    let before = mk_datetime(2021, 1, 1, 0, 0, 0);
+    let old_files = |cache: &Cache, path_id, limit| {
+        cache.get_files_modified_before(path_id, before, limit).unwrap()
+    };
+    // /a/f was last seen in bar, where it was modified after
+    assert_eq!(
+        old_files(&cache, Some(a), 10),
+        vec![
+            ("b/c/d/deep".into(), deep, 5),
+            ("g".into(), g, 3),
+            ("h".into(), h, 1)
+        ]
+    );
+    assert_eq!(
+        old_files(&cache, Some(a), 2),
+        vec![("b/c/d/deep".into(), deep, 5), ("g".into(), g, 3)]
+    );
+    assert_eq!(
+        old_files(&cache, None, 2),
+        vec![("/y".into(), y, 6), ("/a/b/c/d/deep".into(), deep, 5)]
+    );
+    cache.select_snapshots(Some(vec!["foo".into()])).unwrap();
+    assert_eq!(
+        old_files(&cache, Some(a), 3),
+        vec![
+            ("b/c/d/deep".into(), deep, 5),
+            ("g".into(), g, 3),
+            ("f".into(), f, 2)
+        ]
+    );
+}
+
+#[test]
+fn cache_clone_snapshot() {
+    let tempfile = Tempfile::new();
+    let mut cache = Migrator::open(&tempfile.0).unwrap().migrate().unwrap();
+    let foo = mk_snapshot("foo", 2023);
+    cache.save_snapshot(&foo, example_tree_0()).unwrap();
+    cache.stage_files("bar", None, &[mk_file("a/0/x", 1)]).unwrap();
+    let bar = Snapshot {
+        tags: HashSet::from(["copy".into()]),
+        ..mk_snapshot("bar", 2024)
+    };
+    assert_eq!(
+        cache.clone_snapshot(&bar, "foo").unwrap(),
+        example_tree_0().iter().count()
+    );
+    assert!(cache.get_staged_snapshots().unwrap().is_empty());
+
+    let snapshots = cache.get_snapshots().unwrap();
+    let bar_cached = snapshots.iter().find(|s| s.id == "bar").unwrap();
+    assert_eq!(bar_cached.tags, bar.tags);
+    cache.select_snapshots(Some(vec!["bar".into()])).unwrap();
+    for path in ["", "a", "a/0", "a/1", "a/1/x"] {
+        assert_get_entries_correct_at_path(&cache, &example_tree_0(), path);
+    }
+    // The aggregate now has bar as the latest one
+    cache.select_snapshots(None).unwrap();
+    let path_id = cache.get_path_id_by_path("a/0".into()).unwrap().unwrap();
+    let details = cache.get_entry_details(path_id).unwrap().unwrap();
+    assert_eq!(details.first_seen_snapshot_hash, "foo");
+    assert_eq!(details.last_seen_snapshot_hash, "bar");
+
+    // Deleting the source keeps the copy
+    cache.delete_snapshot("foo").unwrap();
+    for path in ["", "a", "a/0", "a/1", "a/1/x"] {
+        assert_get_entries_correct_at_path(&cache, &example_tree_0(), path);
+    }
+}
+
 // TODO: Ideally we would run more than 10_000 but at the moment this is too slow.
 #[test]
 fn lots_of_snapshots() {
@@ -517,6 +955,8 @@ fn lots_of_snapshots() {
             last_seen: timestamp_to_datetime((NUM_SNAPSHOTS - 1) as i64)
                 .unwrap(),
             last_seen_snapshot_hash: (NUM_SNAPSHOTS - 1).to_string(),
+            unique_size: None,
+            metadata: FileMetadata::default(),
         }
     );
 }
@@ -585,3 +1025,620 @@ fn test_migrate_v0_to_v1() {
 
     cache_snapshots_entries();
 }
+
+/// A cache with the snapshots `foo` and `bar` turned back into `version`
+/// by dropping what the later migrations added.
+fn populate_old_version(version: VersionId) -> Tempfile {
+    let file = Tempfile::new();
+    let mut cache = Migrator::open(&file.0).unwrap().migrate().unwrap();
+    cache.save_snapshot(&mk_snapshot("foo", 2024), example_tree_0()).unwrap();
+    cache.save_snapshot(&mk_snapshot("bar", 2023), example_tree_1()).unwrap();
+    if version < 6 {
+        for table in ["entries_foo", "entries_bar"] {
+            for (name, _) in METADATA_COLUMNS {
+                cache
+                    .conn
+                    .execute(
+                        &format!("ALTER TABLE \"{table}\" DROP COLUMN {name}"),
+                        [],
+                    )
+                    .unwrap();
+            }
+        }
+    }
+    let added_tables = [
+        (2, "aggregate_entries"),
+        (3, "unique_sizes"),
+        (4, "pattern_marks"),
+        (5, "staged_snapshots"),
+        (5, "staged_files"),
+    ];
+    for (added_in, table) in added_tables {
+        if version < added_in {
+            cache.conn.execute(&format!("DROP TABLE {table}"), []).unwrap();
+        }
+    }
+    cache
+        .conn
+        .execute(
+            "UPDATE metadata_integer SET value = ? WHERE key = 'version'",
+            [version],
+        )
+        .unwrap();
+    assert_eq!(determine_version(&cache.conn).unwrap(), Some(version));
+    file
+}
+
+/// `(path_id, max_size, max_size_snapshot_hash, last_seen_snapshot_hash)`
+/// for each row of `aggregate_entries`.
+fn aggregate_rows(conn: &Connection) -> Vec<(u64, usize, String, String)> {
+    conn.prepare(
+        "SELECT path_id, max_size, max_size_snapshot_hash, \
+             last_seen_snapshot_hash \
+         FROM aggregate_entries ORDER BY path_id",
+    )
+    .unwrap()
+    .query_map([], |row| {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+    })
+    .unwrap()
+    .collect::<Result<_, _>>()
+    .unwrap()
+}
+
+#[test]
+fn test_migrate_v1_to_v2() {
+    let file = populate_old_version(1);
+
+    let migrator = Migrator::open_with_target(&file.0, 2).unwrap();
+    assert_eq!(migrator.need_to_migrate(), Some((Some(1), 2)));
+    assert!(!migrator.resync_necessary());
+    let cache = migrator.migrate().unwrap();
+    assert_eq!(determine_version(&cache.conn).unwrap(), Some(2));
+
+    // The rest of the queries need the newer tables,
+    // so look at the aggregate directly
+    let path_id = cache.get_path_id_by_path("a/0".into()).unwrap().unwrap();
+    let rows = aggregate_rows(&cache.conn);
+    assert_eq!(rows.len(), 13);
+    assert!(rows.contains(&(path_id.0, 14, "bar".into(), "foo".into())));
+}
+
+#[test]
+fn test_migrate_v2_to_v3() {
+    let file = populate_old_version(2);
+    let aggregate = {
+        let conn = Connection::open(&file.0).unwrap();
+        aggregate_rows(&conn)
+    };
+
+    let migrator = Migrator::open_with_target(&file.0, 3).unwrap();
+    assert_eq!(migrator.need_to_migrate(), Some((Some(2), 3)));
+    assert!(!migrator.resync_necessary());
+    let cache = migrator.migrate().unwrap();
+    assert_eq!(determine_version(&cache.conn).unwrap(), Some(3));
+
+    assert!(get_tables(&cache.conn).unwrap().contains("unique_sizes"));
+    assert!(!cache.has_unique_sizes().unwrap());
+    assert_eq!(aggregate_rows(&cache.conn), aggregate);
+}
+
+/// (column, type, not null, primary key)
+type Column = (String, String, bool, bool);
+
+fn table_columns(conn: &Connection) -> Vec<(String, Vec<Column>)> {
+    let mut tables = get_tables(conn).unwrap().into_iter().collect::<Vec<_>>();
+    tables.sort();
+    tables
+        .into_iter()
+        .map(|table| {
+            let mut stmt = conn
+                .prepare(&format!("PRAGMA table_info(\"{table}\")"))
+                .unwrap();
+            let columns = stmt
+                .query_map([], |row| {
+                    Ok((
+                        row.get("name")?,
+                        row.get("type")?,
+                        row.get("notnull")?,
+                        row.get::<_, u32>("pk")? > 0,
+                    ))
+                })
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            (table, columns)
+        })
+        .collect()
+}
+
+#[test]
+fn test_migrate_none_to_latest() {
+    let fresh = Tempfile::new();
+    let migrator = Migrator::open(&fresh.0).unwrap();
+    // A new cache is created with the latest schema in one step
+    assert_eq!(migrator.migrations.len(), 1);
+    assert_eq!(migrator.need_to_migrate(), Some((None, LATEST_VERSION)));
+    let fresh_cache = migrator.migrate().unwrap();
+
+    let upgraded = Tempfile::new();
+    Migrator::open_with_target(&upgraded.0, 1).unwrap().migrate().unwrap();
+    let upgraded_cache =
+        Migrator::open(&upgraded.0).unwrap().migrate().unwrap();
+
+    assert_eq!(
+        table_columns(&fresh_cache.conn),
+        table_columns(&upgraded_cache.conn)
+    );
+}
+
+#[test]
+fn test_migrate_v1_to_latest() {
+    let file = populate_old_version(1);
+
+    // The current queries need the newer tables, so this goes through
+    // every migration after v1
+    let migrator = Migrator::open(&file.0).unwrap();
+    assert_eq!(migrator.need_to_migrate(), Some((Some(1), LATEST_VERSION)));
+    assert!(!migrator.resync_necessary());
+    let cache = migrator.migrate().unwrap();
+    assert_eq!(determine_version(&cache.conn).unwrap(), Some(LATEST_VERSION));
+
+    let tree = example_tree_0().merge(example_tree_1());
+    for path in ["", "a", "a/0", "a/1", "a/2", "a/1/x", "a/something"] {
+        assert_get_entries_correct_at_path(&cache, &tree, path);
+    }
+    let path_id = cache.get_path_id_by_path("a/0".into()).unwrap().unwrap();
+    assert_eq!(
+        cache.get_entry_details(path_id).unwrap().unwrap(),
+        EntryDetails {
+            max_size: 14,
+            max_size_snapshot_hash: "bar".to_string(),
+            first_seen: mk_datetime(2023, 4, 12, 12, 00, 00),
+            first_seen_snapshot_hash: "bar".to_string(),
+            last_seen: mk_datetime(2024, 4, 12, 12, 00, 00),
+            last_seen_snapshot_hash: "foo".to_string(),
+            unique_size: None,
+            metadata: FileMetadata::default(),
+        }
+    );
+}
+
+#[test]
+fn aggregate_entries_after_delete() {
+    let tempfile = Tempfile::new();
+    let mut cache = Migrator::open(&tempfile.0).unwrap().migrate().unwrap();
+    cache.save_snapshot(&mk_snapshot("foo", 2024), example_tree_0()).unwrap();
+    cache.save_snapshot(&mk_snapshot("bar", 2023), example_tree_1()).unwrap();
+    cache.save_snapshot(&mk_snapshot("wat", 2022), example_tree_2()).unwrap();
+
+    cache.delete_snapshot("bar").unwrap();
+    let path_id = cache.get_path_id_by_path("a/0".into()).unwrap().unwrap();
+    assert_eq!(
+        cache.get_entry_details(path_id).unwrap().unwrap(),
+        EntryDetails {
+            max_size: 4,
+            max_size_snapshot_hash: "foo".to_string(),
+            first_seen: mk_datetime(2024, 4, 12, 12, 00, 00),
+            first_seen_snapshot_hash: "foo".to_string(),
+            last_seen: mk_datetime(2024, 4, 12, 12, 00, 00),
+            last_seen_snapshot_hash: "foo".to_string(),
+            unique_size: None,
+            metadata: FileMetadata::default(),
+        }
+    );
+    let path_id = cache.get_path_id_by_path("a/2".into()).unwrap().unwrap();
+    assert_eq!(
+        cache
+            .get_entry_details(path_id)
+            .unwrap()
+            .unwrap()
+            .last_seen_snapshot_hash,
+        "wat"
+    );
+
+    cache.delete_snapshot("wat").unwrap();
+    assert_eq!(cache.get_entry_details(path_id).unwrap(), None);
+    assert_get_entries_correct_at_path(&cache, &example_tree_0(), "a");
+}
+
+#[test]
+fn cache_select_snapshots() {
+    let tempfile = Tempfile::new();
+    let mut cache = Migrator::open(&tempfile.0).unwrap().migrate().unwrap();
+    cache.save_snapshot(&mk_snapshot("foo", 2024), example_tree_0()).unwrap();
+    cache.save_snapshot(&mk_snapshot("bar", 2023), example_tree_1()).unwrap();
+    cache.save_snapshot(&mk_snapshot("wat", 2022), example_tree_2()).unwrap();
+
+    let paths = ["", "a", "b", "a/0", "a/1", "a/2", "b/0", "a/something"];
+
+    cache
+        .select_snapshots(Some(vec!["foo".to_string(), "wat".to_string()]))
+        .unwrap();
+    let tree = example_tree_0().merge(example_tree_2());
+    for path in paths {
+        assert_get_entries_correct_at_path(&cache, &tree, path);
+    }
+    let path_id = cache.get_path_id_by_path("a/1".into()).unwrap().unwrap();
+    assert_eq!(
+        cache.get_entry_details(path_id).unwrap().unwrap(),
+        EntryDetails {
+            max_size: 9,
+            max_size_snapshot_hash: "foo".to_string(),
+            first_seen: mk_datetime(2022, 4, 12, 12, 00, 00),
+            first_seen_snapshot_hash: "wat".to_string(),
+            last_seen: mk_datetime(2024, 4, 12, 12, 00, 00),
+            last_seen_snapshot_hash: "foo".to_string(),
+            unique_size: None,
+            metadata: FileMetadata::default(),
+        }
+    );
+
+    cache.select_snapshots(Some(vec![])).unwrap();
+    assert_eq!(cache.get_entries(None).unwrap(), vec![]);
+    assert_eq!(cache.get_entry_details(path_id).unwrap(), None);
+
+    cache.select_snapshots(None).unwrap();
+    let tree = tree.merge(example_tree_1());
+    for path in paths {
+        assert_get_entries_correct_at_path(&cache, &tree, path);
+    }
+}
+
+#[test]
+fn cache_get_diff() {
+    let tempfile = Tempfile::new();
+    let mut cache = Migrator::open(&tempfile.0).unwrap().migrate().unwrap();
+    cache.save_snapshot(&mk_snapshot("foo", 2023), example_tree_0()).unwrap();
+    cache.save_snapshot(&mk_snapshot("bar", 2024), example_tree_1()).unwrap();
+
+    let diff = |path: &str| {
+        let path_id = if path.is_empty() {
+            None
+        } else {
+            Some(cache.get_path_id_by_path(path.into()).unwrap().unwrap())
+        };
+        cache
+            .get_diff("foo", "bar", path_id)
+            .unwrap()
+            .into_iter()
+            .map(|e| (e.component, e.is_dir, e.old_size, e.new_size))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(diff(""), vec![("a".into(), true, Some(13), Some(22))]);
+    assert_eq!(
+        diff("a"),
+        vec![
+            ("0".into(), true, Some(4), Some(14)),
+            ("1".into(), true, Some(9), Some(1)),
+            ("2".into(), true, None, Some(7)),
+        ]
+    );
+    // y did not change
+    assert_eq!(
+        diff("a/0"),
+        vec![
+            ("z".into(), true, Some(1), Some(9)),
+            ("x".into(), false, Some(1), Some(3)),
+        ]
+    );
+    assert_eq!(
+        diff("a/1/x"),
+        vec![
+            ("0".into(), false, Some(7), None),
+            ("1".into(), false, Some(2), Some(1))
+        ]
+    );
+    let path_id = cache.get_path_id_by_path("a/2".into()).unwrap();
+    assert_eq!(cache.get_diff("bar", "foo", path_id).unwrap()[0].delta(), -7);
+}
+
+#[test]
+fn cache_pattern_marks() {
+    let tempfile = Tempfile::new();
+    let mut cache = Migrator::open(&tempfile.0).unwrap().migrate().unwrap();
+    cache.upsert_pattern_mark("/home/*/.cache").unwrap();
+    cache.upsert_pattern_mark("**/node_modules").unwrap();
+    cache.upsert_pattern_mark("/home/*/.cache").unwrap();
+    cache.upsert_mark("/home/user/big.iso".into()).unwrap();
+    assert_eq!(
+        cache.get_pattern_marks().unwrap(),
+        vec!["**/node_modules".to_string(), "/home/*/.cache".to_string()]
+    );
+    // They are separate from the path marks
+    assert_marks(&cache, &["/home/user/big.iso"]);
+
+    cache.delete_pattern_mark("**/node_modules").unwrap();
+    assert_eq!(cache.get_pattern_marks().unwrap(), vec!["/home/*/.cache"]);
+
+    cache.delete_all_marks().unwrap();
+    assert!(cache.get_pattern_marks().unwrap().is_empty());
+    assert_marks(&cache, &[]);
+}
+
+#[test]
+fn cache_get_mark_entries() {
+    let tempfile = Tempfile::new();
+    let mut cache = Migrator::open(&tempfile.0).unwrap().migrate().unwrap();
+    cache.save_snapshot(&mk_snapshot("foo", 2024), example_tree_0()).unwrap();
+    cache.save_snapshot(&mk_snapshot("bar", 2023), example_tree_1()).unwrap();
+    cache.upsert_mark("a/0".into()).unwrap();
+    cache.upsert_mark("a/2".into()).unwrap();
+    cache.upsert_mark("gone/away".into()).unwrap();
+
+    let path_id = |path: &str| cache.get_path_id_by_path(path.into()).unwrap();
+    let mut entries = cache.get_mark_entries().unwrap();
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    assert_eq!(
+        entries,
+        vec![
+            MarkEntry {
+                path: "a/0".into(),
+                path_id: path_id("a/0"),
+                parent_id: path_id("a"),
+                size: Some(14),
+            },
+            MarkEntry {
+                path: "a/2".into(),
+                path_id: path_id("a/2"),
+                parent_id: path_id("a"),
+                size: Some(7),
+            },
+            MarkEntry {
+                path: "gone/away".into(),
+                path_id: None,
+                parent_id: None,
+                size: None,
+            },
+        ]
+    );
+
+    // a/2 is only in bar
+    cache.delete_snapshot("bar").unwrap();
+    let entries = cache.get_mark_entries().unwrap();
+    let a2 = entries.iter().find(|e| e.path == "a/2").unwrap();
+    assert_eq!((a2.path_id, a2.size), (None, None));
+}
+
+#[test]
+fn cache_marks_summary() {
+    let tempfile = Tempfile::new();
+    let mut cache = Migrator::open(&tempfile.0).unwrap().migrate().unwrap();
+    cache.save_snapshot(&mk_snapshot("foo", 2024), example_tree_0()).unwrap();
+    cache.save_snapshot(&mk_snapshot("bar", 2023), example_tree_1()).unwrap();
+    cache.save_snapshot(&mk_snapshot("wat", 2022), example_tree_2()).unwrap();
+    cache.upsert_mark("a/0".into()).unwrap();
+    cache.upsert_mark("a/0/x".into()).unwrap();
+    cache.upsert_pattern_mark("x").unwrap();
+    cache.upsert_pattern_mark("nothing").unwrap();
+
+    let summary = cache.get_marks_summary().unwrap();
+    let marks = summary
+        .marks
+        .iter()
+        .map(|m| {
+            (
+                m.mark.as_str(),
+                m.is_pattern,
+                m.snapshots,
+                m.max_size,
+                m.total_size,
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        marks,
+        vec![
+            ("a/0", false, 2, 14, 18),
+            ("a/0/x", false, 2, 3, 4),
+            ("nothing", true, 0, 0, 0),
+            ("x", true, 3, 11, 32),
+        ]
+    );
+    // a/0/x is inside a/0 so it doesn't count again
+    let snapshots = summary
+        .snapshots
+        .iter()
+        .map(|(s, size)| (s.id.as_str(), *size))
+        .collect::<Vec<_>>();
+    assert_eq!(snapshots, vec![("foo", 13), ("bar", 22), ("wat", 11)]);
+
+    cache.select_snapshots(Some(vec!["wat".into()])).unwrap();
+    let summary = cache.get_marks_summary().unwrap();
+    assert_eq!(summary.snapshots.len(), 1);
+    assert_eq!(summary.snapshots[0].1, 11);
+}
+
+#[test]
+fn cache_check_marks() {
+    let tempfile = Tempfile::new();
+    let mut cache = Migrator::open(&tempfile.0).unwrap().migrate().unwrap();
+    let mut wat = mk_snapshot("wat", 2022);
+    // Written differently from the mark but it excludes the same paths
+    wat.excludes = HashSet::from(["a/0".into(), "**/nothing/".into()]);
+    cache.save_snapshot(&mk_snapshot("foo", 2024), example_tree_0()).unwrap();
+    cache.save_snapshot(&mk_snapshot("bar", 2023), example_tree_1()).unwrap();
+    cache.save_snapshot(&wat, example_tree_2()).unwrap();
+
+    let checks = cache
+        .check_marks(
+            vec!["a/0".into()],
+            vec!["x".into(), "nothing".into(), "[".into()],
+        )
+        .unwrap();
+    let checks = checks
+        .iter()
+        .map(|c| {
+            (
+                c.mark.as_str(),
+                c.is_pattern,
+                c.present
+                    .iter()
+                    .map(|(s, size)| (s.id.as_str(), *size))
+                    .collect::<Vec<_>>(),
+                c.absent
+                    .iter()
+                    .map(|(s, exclude)| (s.id.as_str(), exclude.as_deref()))
+                    .collect::<Vec<_>>(),
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        checks,
+        vec![
+            (
+                "a/0",
+                false,
+                vec![("foo", 4), ("bar", 14)],
+                vec![("wat", Some("a/0"))]
+            ),
+            ("x", true, vec![("foo", 10), ("bar", 11), ("wat", 11)], vec![]),
+            (
+                "nothing",
+                true,
+                vec![],
+                vec![
+                    ("foo", None),
+                    ("bar", None),
+                    ("wat", Some("**/nothing/"))
+                ]
+            ),
+        ]
+    );
+
+    cache.select_snapshots(Some(vec!["wat".into()])).unwrap();
+    let checks = cache.check_marks(vec!["a/0".into()], vec![]).unwrap();
+    assert!(checks[0].present.is_empty());
+    assert_eq!(checks[0].absent.len(), 1);
+}
+
+#[test]
+fn cache_search() {
+    let tempfile = Tempfile::new();
+    let mut cache = Migrator::open(&tempfile.0).unwrap().migrate().unwrap();
+    cache.save_snapshot(&mk_snapshot("foo", 2023), example_tree_0()).unwrap();
+    cache.save_snapshot(&mk_snapshot("bar", 2024), example_tree_1()).unwrap();
+    cache.save_snapshot(&mk_snapshot("wat", 2022), example_tree_2()).unwrap();
+
+    // Sorted by size and then path, because the order of ties is unspecified
+    let search = |cache: &Cache, pattern: &str, limit| {
+        let mut hits = cache
+            .search(pattern, limit)
+            .unwrap()
+            .into_iter()
+            .map(|hit| {
+                assert_eq!(
+                    cache.get_path_id_by_path(&hit.path).unwrap(),
+                    Some(hit.path_id)
+                );
+                assert_eq!(
+                    cache.get_parent_id(hit.path_id).unwrap(),
+                    Some(hit.parent_id)
+                );
+                (hit.path.into_string(), hit.size, hit.is_dir)
+            })
+            .collect::<Vec<_>>();
+        hits.sort_by_key(|(path, size, _)| (Reverse(*size), path.clone()));
+        hits
+    };
+    assert_eq!(
+        search(&cache, "x", 10),
+        vec![
+            ("a/1/x".into(), 9, true),
+            ("a/2/x".into(), 7, true),
+            ("a/0/x".into(), 3, false),
+            ("b/0/x".into(), 3, false),
+        ]
+    );
+    assert_eq!(search(&cache, "x", 1), vec![("a/1/x".into(), 9, true)]);
+    assert_eq!(search(&cache, "nothing", 10), vec![]);
+    assert_eq!(
+        search(&cache, "[yz]", 10),
+        vec![
+            ("a/0/z".into(), 9, true),
+            ("b/0/z".into(), 9, true),
+            ("a/0/y".into(), 2, false),
+            ("b/0/y".into(), 2, false),
+        ]
+    );
+
+    cache.select_snapshots(Some(vec!["foo".to_string()])).unwrap();
+    assert_eq!(
+        search(&cache, "?", 2),
+        vec![("a".into(), 13, true), ("a/1".into(), 9, true)]
+    );
+}
+
+#[test]
+fn cache_unique_sizes() {
+    let tempfile = Tempfile::new();
+    let mut cache = Migrator::open(&tempfile.0).unwrap().migrate().unwrap();
+    cache.save_snapshot(&mk_snapshot("foo", 2024), example_tree_0()).unwrap();
+    assert!(!cache.has_unique_sizes().unwrap());
+
+    let mut unique_sizes = UniqueSizes::new();
+    unique_sizes.add_file(["a", "0", "x"], ["b0"]);
+    unique_sizes.add_file(["a", "0", "y"], ["b0", "b1"]);
+    unique_sizes.add_file(["a", "1", "x", "0"], ["b2"]);
+    let blob_sizes = [
+        ("b0".to_string(), 1),
+        ("b1".to_string(), 10),
+        ("b2".to_string(), 100),
+    ]
+    .into_iter()
+    .collect();
+    cache.save_unique_sizes(unique_sizes.finish(&blob_sizes)).unwrap();
+    assert!(cache.has_unique_sizes().unwrap());
+
+    let unique_size_of = |cache: &Cache, path: &str| {
+        let path = Utf8Path::new(path);
+        let parent_id = path
+            .parent()
+            .filter(|p| !p.as_str().is_empty())
+            .map(|p| cache.get_path_id_by_path(p).unwrap().unwrap());
+        cache
+            .get_entries(parent_id)
+            .unwrap()
+            .into_iter()
+            .find(|e| e.component == path.file_name().unwrap())
+            .unwrap()
+            .unique_size
+    };
+    assert_eq!(unique_size_of(&cache, "a"), Some(111));
+    assert_eq!(unique_size_of(&cache, "a/0"), Some(11));
+    assert_eq!(unique_size_of(&cache, "a/0/x"), Some(0));
+    assert_eq!(unique_size_of(&cache, "a/1/x"), Some(100));
+    // Not in any blob list
+    assert_eq!(unique_size_of(&cache, "a/0/z"), None);
+
+    let path_id = cache.get_path_id_by_path("a/0".into()).unwrap().unwrap();
+    assert_eq!(
+        cache.get_entry_details(path_id).unwrap().unwrap().unique_size,
+        Some(11)
+    );
+    cache.select_snapshots(Some(vec!["foo".to_string()])).unwrap();
+    assert_eq!(unique_size_of(&cache, "a/0"), Some(11));
+    assert_eq!(
+        cache.get_entry_details(path_id).unwrap().unwrap().unique_size,
+        Some(11)
+    );
+
+    // Any change to the snapshots invalidates them
+    cache.save_snapshot(&mk_snapshot("bar", 2023), example_tree_1()).unwrap();
+    assert!(!cache.has_unique_sizes().unwrap());
+    assert_eq!(unique_size_of(&cache, "a/0"), None);
+}
+
+#[test]
+fn test_migrate_v0_to_latest() {
+    let marks = ["/foo", "/bar/wat", "foo/a/b/c", "something"];
+    let file = populate_v0(marks).unwrap();
+
+    let migrator = Migrator::open(&file.0).unwrap();
+    assert_eq!(migrator.need_to_migrate(), Some((Some(0), LATEST_VERSION)));
+    assert!(migrator.resync_necessary());
+    let cache = migrator.migrate().unwrap();
+
+    assert_marks(&cache, &marks);
+    assert_eq!(determine_version(&cache.conn).unwrap(), Some(LATEST_VERSION));
+}
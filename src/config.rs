@@ -0,0 +1,134 @@
+use std::{collections::BTreeMap, fs, io, path::Path};
+
+use anyhow::Context;
+use serde::Deserialize;
+
+use crate::args::OutputFormat;
+
+/// The config file (`config.toml` in the config directory of redu).
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The keys of the actions whose default keys are changed
+    pub keybindings: BTreeMap<String, KeyList>,
+    /// Selected with --profile
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+/// Values for the options, like the ones given on the command line.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    pub repo: Option<String>,
+    pub repository_file: Option<String>,
+    pub password_command: Option<String>,
+    pub password_file: Option<String>,
+    pub parallelism: Option<usize>,
+    pub no_cache: bool,
+    pub hosts: Vec<String>,
+    /// Each one is a comma separated list, like --tag
+    pub tags: Vec<String>,
+    pub paths: Vec<String>,
+    pub since: Option<String>,
+    pub until: Option<String>,
+    pub output_format: Option<OutputFormat>,
+}
+
+/// A single key or a list of keys.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(untagged)]
+pub enum KeyList {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl KeyList {
+    pub fn iter(&self) -> impl Iterator<Item = &String> {
+        match self {
+            KeyList::One(key) => std::slice::from_ref(key).iter(),
+            KeyList::Many(keys) => keys.iter(),
+        }
+    }
+}
+
+impl Config {
+    /// A missing file is the same as an empty one.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(Config::default())
+            }
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!("unable to read {}", path.display())
+                })
+            }
+        };
+        toml::from_str(&contents)
+            .with_context(|| format!("invalid config file {}", path.display()))
+    }
+}
+
+/// Tests //////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_parse_test() {
+        let config: Config = toml::from_str(
+            "[keybindings]\n\
+             unmark_all = \"ctrl-x\"\n\
+             down = [\"down\", \"t\"]\n\
+             help = []\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.keybindings.into_iter().collect::<Vec<_>>(),
+            vec![
+                ("down".into(), KeyList::Many(vec!["down".into(), "t".into()])),
+                ("help".into(), KeyList::Many(vec![])),
+                ("unmark_all".into(), KeyList::One("ctrl-x".into())),
+            ]
+        );
+        assert!(toml::from_str::<Config>("").unwrap().keybindings.is_empty());
+        assert!(toml::from_str::<Config>("[keybinds]").is_err());
+    }
+
+    #[test]
+    fn config_profiles_test() {
+        let config: Config = toml::from_str(
+            "[profiles.work]\n\
+             repo = \"sftp:backup:/restic\"\n\
+             password_command = \"pass restic\"\n\
+             parallelism = 2\n\
+             no_cache = true\n\
+             hosts = [\"laptop\"]\n\
+             since = \"2024-01-01\"\n\
+             output_format = \"iexclude\"\n\
+             [profiles.home]\n\
+             repository_file = \"/etc/restic/repo\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.profiles["work"],
+            Profile {
+                repo: Some("sftp:backup:/restic".into()),
+                password_command: Some("pass restic".into()),
+                parallelism: Some(2),
+                no_cache: true,
+                hosts: vec!["laptop".into()],
+                since: Some("2024-01-01".into()),
+                output_format: Some(OutputFormat::Iexclude),
+                ..Profile::default()
+            }
+        );
+        assert_eq!(
+            config.profiles["home"].repository_file.as_deref(),
+            Some("/etc/restic/repo")
+        );
+        assert!(toml::from_str::<Config>("[profiles.x]\nrepository = \"a\"")
+            .is_err());
+    }
+}
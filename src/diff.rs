@@ -0,0 +1,201 @@
+use std::{cmp::Reverse, io::Write};
+
+use anyhow::Context;
+use camino::{Utf8Path, Utf8PathBuf};
+use redu::cache::{Cache, PathId};
+use serde::Serialize;
+
+use crate::{args::ReportFormat, ui::escape_name};
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct DiffReportEntry {
+    pub path: Utf8PathBuf,
+    pub is_dir: bool,
+    /// `None` if it was added
+    pub old_size: Option<usize>,
+    /// `None` if it was removed
+    pub new_size: Option<usize>,
+    pub delta: isize,
+}
+
+/// Returns the `top` entries that changed the most between the two snapshots,
+/// at most `depth` levels below `path`.
+/// Only the directories that changed size are looked into.
+pub fn collect(
+    cache: &Cache,
+    old_hash: &str,
+    new_hash: &str,
+    path: Option<&Utf8Path>,
+    depth: u32,
+    top: usize,
+) -> anyhow::Result<Vec<DiffReportEntry>> {
+    let path_id = match path {
+        None => None,
+        Some(path) => Some(
+            cache
+                .get_path_id_by_path(path)?
+                .with_context(|| format!("{path} is not in any snapshot"))?,
+        ),
+    };
+
+    let mut found = Vec::new();
+    // (path, path_id, level)
+    let mut stack: Vec<(Utf8PathBuf, Option<PathId>, u32)> =
+        vec![(path.map(Utf8Path::to_path_buf).unwrap_or_default(), path_id, 0)];
+    while let Some((path, path_id, level)) = stack.pop() {
+        for entry in cache.get_diff(old_hash, new_hash, path_id)? {
+            let entry_path = path.join(&entry.component);
+            if entry.is_dir && level + 1 < depth {
+                stack.push((
+                    entry_path.clone(),
+                    Some(entry.path_id),
+                    level + 1,
+                ));
+            }
+            found.push(DiffReportEntry {
+                path: entry_path,
+                is_dir: entry.is_dir,
+                old_size: entry.old_size,
+                new_size: entry.new_size,
+                delta: entry.delta(),
+            });
+        }
+    }
+    found.sort_by_key(|entry| Reverse(entry.delta.unsigned_abs()));
+    found.truncate(top);
+    Ok(found)
+}
+
+pub fn write(
+    out: &mut impl Write,
+    format: ReportFormat,
+    report: &[DiffReportEntry],
+) -> anyhow::Result<()> {
+    match format {
+        ReportFormat::Table => write_table(out, report)?,
+        ReportFormat::Tsv => write_tsv(out, report)?,
+        ReportFormat::Json => {
+            serde_json::to_writer_pretty(&mut *out, report)?;
+            writeln!(out)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_table(
+    out: &mut impl Write,
+    report: &[DiffReportEntry],
+) -> std::io::Result<()> {
+    writeln!(out, "{:>12}  {:>11}  {:>11}  PATH", "DELTA", "OLD", "NEW")?;
+    for entry in report {
+        let mut path = escape_name(entry.path.as_str()).into_owned();
+        if entry.is_dir && !path.ends_with('/') {
+            path.push('/');
+        }
+        writeln!(
+            out,
+            "{:>12}  {:>11}  {:>11}  {path}",
+            format_delta(entry.delta),
+            format_o_size(entry.old_size),
+            format_o_size(entry.new_size),
+        )?;
+    }
+    Ok(())
+}
+
+fn write_tsv(
+    out: &mut impl Write,
+    report: &[DiffReportEntry],
+) -> std::io::Result<()> {
+    writeln!(out, "path\tis_dir\told_size\tnew_size\tdelta")?;
+    for entry in report {
+        writeln!(
+            out,
+            "{}\t{}\t{}\t{}\t{}",
+            escape_name(entry.path.as_str()),
+            entry.is_dir,
+            entry.old_size.map(|s| s.to_string()).unwrap_or_default(),
+            entry.new_size.map(|s| s.to_string()).unwrap_or_default(),
+            entry.delta,
+        )?;
+    }
+    Ok(())
+}
+
+/// Human readable size with an explicit sign.
+pub fn format_delta(delta: isize) -> String {
+    let size = humansize::format_size(delta.unsigned_abs(), humansize::BINARY);
+    match delta.signum() {
+        1 => format!("+{size}"),
+        -1 => format!("-{size}"),
+        _ => size,
+    }
+}
+
+/// `-` if there is no size (it does not exist in that snapshot).
+pub fn format_o_size(size: Option<usize>) -> String {
+    match size {
+        None => "-".into(),
+        Some(size) => humansize::format_size(size, humansize::BINARY),
+    }
+}
+
+/// Tests //////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example_report() -> Vec<DiffReportEntry> {
+        vec![
+            DiffReportEntry {
+                path: "/home/user".into(),
+                is_dir: true,
+                old_size: Some(1024),
+                new_size: Some(3 * 1024 * 1024),
+                delta: 3 * 1024 * 1024 - 1024,
+            },
+            DiffReportEntry {
+                path: "/home/old".into(),
+                is_dir: false,
+                old_size: Some(2048),
+                new_size: None,
+                delta: -2048,
+            },
+            DiffReportEntry {
+                path: "/home/new".into(),
+                is_dir: false,
+                old_size: None,
+                new_size: Some(10),
+                delta: 10,
+            },
+        ]
+    }
+
+    fn write_to_string(format: ReportFormat) -> String {
+        let mut out = Vec::new();
+        write(&mut out, format, &example_report()).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn write_table_test() {
+        assert_eq!(
+            write_to_string(ReportFormat::Table),
+            "       DELTA          OLD          NEW  PATH\n\
+             \x20  +3.00 MiB        1 KiB        3 MiB  /home/user/\n\
+             \x20     -2 KiB        2 KiB            -  /home/old\n\
+             \x20      +10 B            -         10 B  /home/new\n"
+        );
+    }
+
+    #[test]
+    fn write_tsv_test() {
+        assert_eq!(
+            write_to_string(ReportFormat::Tsv),
+            "path\tis_dir\told_size\tnew_size\tdelta\n\
+             /home/user\ttrue\t1024\t3145728\t3144704\n\
+             /home/old\tfalse\t2048\t\t-2048\n\
+             /home/new\tfalse\t\t10\t10\n"
+        );
+    }
+}
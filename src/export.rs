@@ -0,0 +1,124 @@
+use std::io::Write;
+
+use redu::cache::{Cache, PathId};
+
+/// Writes the (selected snapshots of the) cache as an ncdu JSON export,
+/// that can be loaded with `ncdu -f`.
+///
+/// Files get their size from the cache as both apparent and disk size,
+/// ncdu computes the directory sizes itself by adding up their contents.
+pub fn write_ncdu(
+    out: &mut impl Write,
+    cache: &Cache,
+    timestamp: i64,
+) -> anyhow::Result<()> {
+    write_ncdu_with(out, timestamp, |path_id: Option<PathId>| {
+        Ok(cache
+            .get_entries(path_id)?
+            .into_iter()
+            .map(|e| (e.component, e.is_dir, e.size, Some(e.path_id)))
+            .collect())
+    })
+}
+
+/// `(name, is_dir, size, key to get the children)`
+type NcduEntry<K> = (String, bool, usize, K);
+
+/// Does the actual writing, `get_children` returns the children of a
+/// directory given its key.
+/// This is split from `write_ncdu` so that it can be tested without a cache.
+fn write_ncdu_with<K: Default>(
+    out: &mut impl Write,
+    timestamp: i64,
+    mut get_children: impl FnMut(K) -> anyhow::Result<Vec<NcduEntry<K>>>,
+) -> anyhow::Result<()> {
+    write!(
+        out,
+        "[1,2,{{\"progname\":\"redu\",\"progver\":\"{}\",\"timestamp\":{timestamp}}}",
+        env!("CARGO_PKG_VERSION"),
+    )?;
+    // The absolute paths are all under a single "/" entry, use it as the root
+    let mut children = get_children(K::default())?;
+    if let [(name, true, _, _)] = children.as_slice() {
+        if name == "/" {
+            let (_, _, _, key) = children.pop().unwrap();
+            children = get_children(key)?;
+        }
+    }
+    out.write_all(b",\n")?;
+    write_ncdu_dir(out, "/", children, &mut get_children)?;
+    out.write_all(b"]\n")?;
+    Ok(())
+}
+
+fn write_ncdu_dir<K>(
+    out: &mut impl Write,
+    name: &str,
+    children: Vec<NcduEntry<K>>,
+    get_children: &mut impl FnMut(K) -> anyhow::Result<Vec<NcduEntry<K>>>,
+) -> anyhow::Result<()> {
+    out.write_all(b"[{\"name\":")?;
+    serde_json::to_writer(&mut *out, name)?;
+    out.write_all(b"}")?;
+    for (name, is_dir, size, key) in children {
+        out.write_all(b",\n")?;
+        if is_dir {
+            let grandchildren = get_children(key)?;
+            write_ncdu_dir(out, &name, grandchildren, get_children)?;
+        } else {
+            out.write_all(b"{\"name\":")?;
+            serde_json::to_writer(&mut *out, &name)?;
+            write!(out, ",\"asize\":{size},\"dsize\":{size}}}")?;
+        }
+    }
+    out.write_all(b"]")?;
+    Ok(())
+}
+
+/// Tests //////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use camino::Utf8Path;
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn write_ncdu_test() {
+        // A small tree keyed by path
+        let get_children = |key: String| -> anyhow::Result<_> {
+            let children: &[(&str, bool, usize)] = match key.as_str() {
+                "" => &[("/", true, 10)],
+                "/" => &[("home", true, 10), ("a\"b\n", false, 3)],
+                "/home" => &[("x", false, 5), ("empty", true, 0)],
+                _ => &[],
+            };
+            Ok(children
+                .iter()
+                .map(|(name, is_dir, size)| {
+                    let key = Utf8Path::new(&key).join(name).into_string();
+                    (name.to_string(), *is_dir, *size, key)
+                })
+                .collect())
+        };
+        let mut out = Vec::new();
+        write_ncdu_with(&mut out, 1234, get_children).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(
+            json,
+            json!([1, 2, {
+                "progname": "redu",
+                "progver": env!("CARGO_PKG_VERSION"),
+                "timestamp": 1234,
+            }, [
+                {"name": "/"},
+                [
+                    {"name": "home"},
+                    {"name": "x", "asize": 5, "dsize": 5},
+                    [{"name": "empty"}],
+                ],
+                {"name": "a\"b\n", "asize": 3, "dsize": 3},
+            ]])
+        );
+    }
+}
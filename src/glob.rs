@@ -0,0 +1,346 @@
+use thiserror::Error;
+
+/// A shell-like pattern with the same syntax as restic
+/// (Go's `filepath.Match`):
+/// - `*` matches any sequence of characters
+/// - `?` matches any single character
+/// - `[abc]`, `[a-z]`, `[^a-z]` match a single character in (or not in)
+///   the class
+/// - `\` escapes the next character
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Glob {
+    tokens: Vec<Token>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Token {
+    Char(char),
+    Any,
+    Star,
+    Class { negated: bool, ranges: Vec<(char, char)> },
+}
+
+#[derive(Debug, Eq, Error, PartialEq)]
+pub enum GlobError {
+    #[error("unterminated character class")]
+    UnterminatedClass,
+    #[error("unescaped - or ] in character class")]
+    UnescapedClassChar,
+    #[error("trailing backslash")]
+    TrailingBackslash,
+    #[error("empty pattern")]
+    Empty,
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Result<Self, GlobError> {
+        let mut tokens = Vec::new();
+        let mut chars = pattern.chars();
+        while let Some(c) = chars.next() {
+            tokens.push(match c {
+                '*' => {
+                    if tokens.last() == Some(&Token::Star) {
+                        continue;
+                    }
+                    Token::Star
+                }
+                '?' => Token::Any,
+                '[' => parse_class(&mut chars)?,
+                '\\' => Token::Char(
+                    chars.next().ok_or(GlobError::TrailingBackslash)?,
+                ),
+                c => Token::Char(c),
+            });
+        }
+        Ok(Glob { tokens })
+    }
+
+    /// Does the whole of `s` match?
+    pub fn matches(&self, s: &str) -> bool {
+        let s = s.chars().collect::<Vec<_>>();
+        let (mut t, mut i) = (0, 0);
+        // Where to resume after the last star if the match fails
+        let mut backtrack: Option<(usize, usize)> = None;
+        while i < s.len() {
+            match self.tokens.get(t) {
+                Some(Token::Star) => {
+                    backtrack = Some((t, i));
+                    t += 1;
+                    continue;
+                }
+                Some(token) if token.matches(s[i]) => {
+                    t += 1;
+                    i += 1;
+                    continue;
+                }
+                _ => {}
+            }
+            match backtrack {
+                Some((star_t, star_i)) => {
+                    // Let the star eat one more character
+                    backtrack = Some((star_t, star_i + 1));
+                    t = star_t + 1;
+                    i = star_i + 1;
+                }
+                None => return false,
+            }
+        }
+        self.tokens[t..].iter().all(|token| *token == Token::Star)
+    }
+}
+
+impl Token {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Token::Char(d) => c == *d,
+            Token::Any => true,
+            Token::Star => false,
+            Token::Class { negated, ranges } => {
+                ranges.iter().any(|(lo, hi)| *lo <= c && c <= *hi) != *negated
+            }
+        }
+    }
+}
+
+fn parse_class(chars: &mut std::str::Chars) -> Result<Token, GlobError> {
+    let mut negated = false;
+    if chars.clone().next() == Some('^') {
+        chars.next();
+        negated = true;
+    }
+    let mut ranges = Vec::new();
+    loop {
+        let mut lookahead = chars.clone();
+        if lookahead.next() == Some(']') && !ranges.is_empty() {
+            *chars = lookahead;
+            break;
+        }
+        let lo = parse_class_char(chars)?;
+        let hi = if chars.clone().next() == Some('-') {
+            chars.next();
+            parse_class_char(chars)?
+        } else {
+            lo
+        };
+        ranges.push((lo, hi));
+    }
+    Ok(Token::Class { negated, ranges })
+}
+
+/// Like Go, a `-` or `]` that is part of the class has to be escaped.
+fn parse_class_char(chars: &mut std::str::Chars) -> Result<char, GlobError> {
+    match chars.next().ok_or(GlobError::UnterminatedClass)? {
+        '-' | ']' => Err(GlobError::UnescapedClassChar),
+        '\\' => chars.next().ok_or(GlobError::UnterminatedClass),
+        c => Ok(c),
+    }
+}
+
+/// A restic exclude pattern: a `Glob` for each path component,
+/// where a `**` component matches any number of components.
+/// Patterns that start with `/` match from the root,
+/// the others can start at any directory.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ExcludePattern {
+    absolute: bool,
+    parts: Vec<Part>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Part {
+    DoubleStar,
+    Glob(Glob),
+}
+
+impl ExcludePattern {
+    pub fn new(pattern: &str) -> Result<Self, GlobError> {
+        let parts = pattern
+            .split('/')
+            .filter(|part| !part.is_empty() && *part != ".")
+            .map(|part| match part {
+                "**" => Ok(Part::DoubleStar),
+                part => Glob::new(part).map(Part::Glob),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if parts.is_empty() {
+            return Err(GlobError::Empty);
+        }
+        Ok(ExcludePattern { absolute: pattern.starts_with('/'), parts })
+    }
+
+    /// Does it match `path` or any of its parents?
+    /// This is what restic excludes, as excluding a directory excludes
+    /// everything in it.
+    pub fn matches(&self, path: &str) -> bool {
+        self.find(path, false)
+    }
+
+    /// Does it match `path` itself (and not only one of its parents)?
+    pub fn matches_exactly(&self, path: &str) -> bool {
+        self.find(path, true)
+    }
+
+    /// Do both patterns exclude the same paths?
+    /// This sees through the ways of writing the same pattern
+    /// (`**/x` and `x`, `x/**` and `x`, a trailing `/`...),
+    /// not through every pair of globs that happen to be equivalent.
+    pub fn excludes_same_as(&self, other: &ExcludePattern) -> bool {
+        self.normalized() == other.normalized()
+    }
+
+    fn normalized(&self) -> ExcludePattern {
+        let mut parts = self.parts.clone();
+        parts.dedup_by(|a, b| *a == Part::DoubleStar && *b == Part::DoubleStar);
+        // Excluding a directory excludes everything in it
+        while parts.len() > 1 && parts.last() == Some(&Part::DoubleStar) {
+            parts.pop();
+        }
+        // A leading ** can start anywhere, like a relative pattern
+        let absolute = self.absolute && parts[0] != Part::DoubleStar;
+        if parts.len() > 1 && parts[0] == Part::DoubleStar {
+            parts.remove(0);
+        }
+        ExcludePattern { absolute, parts }
+    }
+
+    fn find(&self, path: &str, exact: bool) -> bool {
+        let (path_absolute, path) = match path.strip_prefix('/') {
+            Some(path) => (true, path),
+            None => (false, path),
+        };
+        let components = path
+            .split('/')
+            .filter(|component| !component.is_empty())
+            .collect::<Vec<_>>();
+        if self.absolute {
+            path_absolute && match_parts(&self.parts, &components, exact)
+        } else {
+            (0..components.len())
+                .any(|i| match_parts(&self.parts, &components[i..], exact))
+        }
+    }
+}
+
+/// Do the `parts` match the start of `components` (all of them if `exact`)?
+fn match_parts(parts: &[Part], components: &[&str], exact: bool) -> bool {
+    match parts.split_first() {
+        None => !exact || components.is_empty(),
+        Some((Part::DoubleStar, rest)) => (0..=components.len())
+            .any(|i| match_parts(rest, &components[i..], exact)),
+        Some((Part::Glob(glob), rest)) => match components.split_first() {
+            Some((component, components)) => {
+                glob.matches(component) && match_parts(rest, components, exact)
+            }
+            None => false,
+        },
+    }
+}
+
+/// Does the pattern use any special characters?
+pub fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?', '[', '\\'])
+}
+
+/// Tests //////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, s: &str) -> bool {
+        Glob::new(pattern).unwrap().matches(s)
+    }
+
+    #[test]
+    fn glob_test() {
+        assert!(matches("abc", "abc"));
+        assert!(!matches("abc", "abcd"));
+        assert!(matches("*", ""));
+        assert!(matches("*.iso", "debian.iso"));
+        assert!(!matches("*.iso", "debian.iso.part"));
+        assert!(matches("*.iso*", "debian.iso.part"));
+        assert!(matches("a*b*c", "axxbyybzc"));
+        assert!(!matches("a*b*c", "axxbyybz"));
+        assert!(matches("??", "ab"));
+        assert!(!matches("??", "abc"));
+        assert!(matches("[abc]", "b"));
+        assert!(!matches("[abc]", "d"));
+        assert!(matches("[a-c]x", "bx"));
+        assert!(matches("[^a-c]x", "dx"));
+        assert!(!matches("[^a-c]x", "ax"));
+        assert!(matches("[a\\-]", "-"));
+        assert!(matches("\\*", "*"));
+        assert!(!matches("\\*", "a"));
+        assert!(matches("[\\]]", "]"));
+        assert!(matches("学*", "学校"));
+        assert_eq!(Glob::new("[abc"), Err(GlobError::UnterminatedClass));
+        assert_eq!(Glob::new("abc\\"), Err(GlobError::TrailingBackslash));
+        assert_eq!(Glob::new("[]"), Err(GlobError::UnescapedClassChar));
+        assert_eq!(Glob::new("[]a]"), Err(GlobError::UnescapedClassChar));
+        assert_eq!(Glob::new("[a-]"), Err(GlobError::UnescapedClassChar));
+        assert_eq!(Glob::new("[-a]"), Err(GlobError::UnescapedClassChar));
+        assert_eq!(Glob::new("[a-c"), Err(GlobError::UnterminatedClass));
+    }
+
+    #[test]
+    fn exclude_pattern_test() {
+        let pattern = |p: &str| ExcludePattern::new(p).unwrap();
+
+        let p = pattern("node_modules");
+        assert!(p.matches("/home/user/node_modules"));
+        assert!(p.matches("/home/user/node_modules/x/y"));
+        assert!(p.matches_exactly("/home/user/node_modules"));
+        assert!(!p.matches_exactly("/home/user/node_modules/x"));
+        assert!(!p.matches("/home/user/node_modules2"));
+
+        let p = pattern("/home/*/.cache/");
+        assert!(p.matches_exactly("/home/user/.cache"));
+        assert!(p.matches("/home/user/.cache/thumbnails"));
+        assert!(!p.matches("/srv/home/user/.cache"));
+        assert!(!p.matches("home/user/.cache"));
+        assert!(!p.matches("/home/a/b/.cache"));
+
+        let p = pattern("**/target/*.rlib");
+        assert!(p.matches_exactly("/src/a/target/x.rlib"));
+        assert!(p.matches_exactly("/target/x.rlib"));
+        assert!(!p.matches("/src/a/target/x.rs"));
+
+        let p = pattern("/home/**/*.iso");
+        assert!(p.matches_exactly("/home/debian.iso"));
+        assert!(p.matches_exactly("/home/a/b/c/debian.iso"));
+        assert!(!p.matches("/srv/debian.iso"));
+
+        let p = pattern("a/**");
+        assert!(p.matches_exactly("/x/a"));
+        assert!(p.matches_exactly("/x/a/b"));
+
+        let p = pattern("user/docs");
+        assert!(p.matches_exactly("/home/user/docs"));
+        assert!(!p.matches("/home/user"));
+
+        assert!(pattern("\\*").matches_exactly("/x/*"));
+
+        let same = |a: &str, b: &str| pattern(a).excludes_same_as(&pattern(b));
+        assert!(same("node_modules", "**/node_modules"));
+        assert!(same("node_modules", "/**/node_modules/"));
+        assert!(same("/home/*/.cache/", "/home/*/.cache/**"));
+        assert!(same("a/**/**/b", "a/**/b"));
+        assert!(same("\\a", "a"));
+        assert!(same("**", "/**"));
+        assert!(!same("/node_modules", "node_modules"));
+        assert!(!same("a/**/b", "a/b"));
+        assert!(!same("*.iso", "*.img"));
+        assert_eq!(ExcludePattern::new("/"), Err(GlobError::Empty));
+        assert_eq!(
+            ExcludePattern::new("a/[b"),
+            Err(GlobError::UnterminatedClass)
+        );
+    }
+
+    #[test]
+    fn is_glob_test() {
+        assert!(is_glob("*.iso"));
+        assert!(is_glob("a?"));
+        assert!(is_glob("[ab]"));
+        assert!(!is_glob("node_modules"));
+    }
+}
@@ -0,0 +1,471 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    str::FromStr,
+};
+
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use thiserror::Error;
+
+use crate::{config::KeyList, ui::Event};
+
+/// A key with its modifiers, e.g. `ctrl-b`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct KeyChord {
+    pub modifiers: KeyModifiers,
+    pub code: KeyCode,
+}
+
+impl KeyChord {
+    /// The case of a character already tells whether shift was pressed
+    /// (and terminals don't agree on reporting it), so it is left out.
+    /// The same goes for shift-tab, which crossterm reports as `BackTab`.
+    fn normalize(mut self) -> Self {
+        match self.code {
+            KeyCode::Char(c) => {
+                if self.modifiers.contains(KeyModifiers::SHIFT) {
+                    self.code = KeyCode::Char(c.to_ascii_uppercase());
+                }
+                self.modifiers.remove(KeyModifiers::SHIFT);
+            }
+            KeyCode::Tab if self.modifiers.contains(KeyModifiers::SHIFT) => {
+                self.code = KeyCode::BackTab;
+                self.modifiers.remove(KeyModifiers::SHIFT);
+            }
+            KeyCode::BackTab => self.modifiers.remove(KeyModifiers::SHIFT),
+            _ => {}
+        }
+        self
+    }
+}
+
+impl From<&KeyEvent> for KeyChord {
+    fn from(event: &KeyEvent) -> Self {
+        KeyChord { modifiers: event.modifiers, code: event.code }.normalize()
+    }
+}
+
+impl FromStr for KeyChord {
+    type Err = String;
+
+    /// Modifiers (`ctrl-`, `alt-`, `shift-`) followed by a character
+    /// or the name of a key, e.g. `M`, `ctrl-b`, `pgdown` or `space`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut modifiers = KeyModifiers::empty();
+        let mut rest = s;
+        while let Some((prefix, key)) = rest.split_once('-') {
+            if key.is_empty() {
+                break;
+            }
+            modifiers |= match prefix.to_lowercase().as_str() {
+                "ctrl" | "c" => KeyModifiers::CONTROL,
+                "alt" | "m" => KeyModifiers::ALT,
+                "shift" | "s" => KeyModifiers::SHIFT,
+                _ => return Err(format!("unknown modifier {prefix:?}")),
+            };
+            rest = key;
+        }
+        let mut chars = rest.chars();
+        let code = match (chars.next(), chars.next()) {
+            (None, _) => return Err("missing key".into()),
+            (Some(c), None) => KeyCode::Char(c),
+            _ => match rest.to_lowercase().as_str() {
+                "left" => KeyCode::Left,
+                "right" => KeyCode::Right,
+                "up" => KeyCode::Up,
+                "down" => KeyCode::Down,
+                "pgup" | "pageup" => KeyCode::PageUp,
+                "pgdown" | "pagedown" => KeyCode::PageDown,
+                "home" => KeyCode::Home,
+                "end" => KeyCode::End,
+                "enter" | "return" => KeyCode::Enter,
+                "esc" | "escape" => KeyCode::Esc,
+                "tab" => KeyCode::Tab,
+                "backtab" => KeyCode::BackTab,
+                "backspace" => KeyCode::Backspace,
+                "del" | "delete" => KeyCode::Delete,
+                "ins" | "insert" => KeyCode::Insert,
+                "space" => KeyCode::Char(' '),
+                name => match name.strip_prefix('f').map(str::parse) {
+                    Some(Ok(n)) if (1..=12).contains(&n) => KeyCode::F(n),
+                    _ => return Err(format!("unknown key {rest:?}")),
+                },
+            },
+        };
+        Ok(KeyChord { modifiers, code }.normalize())
+    }
+}
+
+impl fmt::Display for KeyChord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.modifiers.contains(KeyModifiers::CONTROL) {
+            f.write_str("C-")?;
+        }
+        if self.modifiers.contains(KeyModifiers::ALT) {
+            f.write_str("M-")?;
+        }
+        if self.modifiers.contains(KeyModifiers::SHIFT) {
+            f.write_str("S-")?;
+        }
+        match self.code {
+            KeyCode::Char(' ') => f.write_str("Space"),
+            KeyCode::Char(c) => write!(f, "{c}"),
+            KeyCode::PageUp => f.write_str("PgUp"),
+            KeyCode::PageDown => f.write_str("PgDown"),
+            KeyCode::Delete => f.write_str("Del"),
+            KeyCode::BackTab => f.write_str("S-Tab"),
+            code => write!(f, "{code}"),
+        }
+    }
+}
+
+/// The actions that can be bound to keys:
+/// (name in the config file, event, description).
+#[rustfmt::skip]
+const ACTIONS: &[(&str, Event, &str)] = &[
+    ("left", Event::Left, "Go to the parent directory"),
+    ("right", Event::Right, "Enter the directory"),
+    ("up", Event::Up, "Move up"),
+    ("down", Event::Down, "Move down"),
+    ("page_up", Event::PageUp, "Page up"),
+    ("page_down", Event::PageDown, "Page down"),
+    ("enter", Event::Enter, "Details / select"),
+    ("exit", Event::Exit, "Close dialog / clear filter"),
+    ("filter", Event::Filter, "Filter the current directory (substring or glob)"),
+    ("next_match", Event::NextMatch, "Next match of the filter"),
+    ("prev_match", Event::PrevMatch, "Previous match of the filter"),
+    ("search", Event::Search, "Find by name or glob in the whole repository"),
+    ("mark", Event::Mark, "Mark"),
+    ("mark_pattern", Event::MarkPattern, "Mark a pattern (restic exclude pattern, e.g. **/node_modules)"),
+    ("unmark", Event::Unmark, "Unmark (or remove the pattern marks that match)"),
+    ("unmark_all", Event::UnmarkAll, "Clear all marks"),
+    ("list_marks", Event::ListMarks, "List of marks"),
+    ("sort", Event::Sort, "Change the order of the list of marks"),
+    ("import_marks", Event::ImportMarks, "Import an exclude file as marks"),
+    ("summarize_marks", Event::SummarizeMarks, "Summary of what the marks remove from each snapshot"),
+    ("pick_snapshot", Event::PickSnapshot, "Pick a snapshot to browse"),
+    ("pick_diff", Event::PickDiff, "Pick two snapshots to compare"),
+    ("aggregate", Event::Aggregate, "Go back to all snapshots"),
+    ("generate", Event::Generate, "Generate"),
+    ("generate_script", Event::GenerateScript, "Generate the rewrite script"),
+    ("help", Event::Help, "Show this help"),
+    ("quit", Event::Quit, "Quit"),
+];
+
+/// The keys of each action when the config file doesn't change them.
+const DEFAULT_KEYS: &[(&str, &[&str])] = &[
+    ("left", &["left", "h"]),
+    ("right", &["right", "l"]),
+    ("up", &["up", "k"]),
+    ("down", &["down", "j"]),
+    ("page_up", &["pgup", "ctrl-b"]),
+    ("page_down", &["pgdown", "ctrl-f"]),
+    ("enter", &["enter"]),
+    ("exit", &["esc"]),
+    ("filter", &["/"]),
+    ("next_match", &["n"]),
+    ("prev_match", &["N"]),
+    ("search", &["f"]),
+    ("mark", &["m"]),
+    ("mark_pattern", &["M"]),
+    ("unmark", &["u"]),
+    ("unmark_all", &["c"]),
+    ("list_marks", &["L"]),
+    ("sort", &["o"]),
+    ("import_marks", &["I"]),
+    ("summarize_marks", &["e"]),
+    ("pick_snapshot", &["s"]),
+    ("pick_diff", &["d"]),
+    ("aggregate", &["a"]),
+    ("generate", &["g"]),
+    ("generate_script", &["G"]),
+    ("help", &["?"]),
+    ("quit", &["q"]),
+];
+
+#[derive(Debug, Error, Eq, PartialEq)]
+pub enum KeybindingsError {
+    #[error("unknown action {0:?}")]
+    UnknownAction(String),
+    #[error("invalid key {key:?} for {action}: {why}")]
+    InvalidKey { action: String, key: String, why: String },
+    #[error("{key} is bound to both {first} and {second}")]
+    Conflict { key: KeyChord, first: &'static str, second: &'static str },
+    #[error("quit needs at least one key, otherwise there is no way out")]
+    QuitUnbound,
+}
+
+#[derive(Clone, Debug)]
+pub struct Keybindings {
+    /// The keys of each action, in the same order as `ACTIONS`
+    keys: Vec<Vec<KeyChord>>,
+}
+
+impl Keybindings {
+    /// The default keybindings with the actions in `config` bound to
+    /// their keys instead (an empty list unbinds an action, except quit).
+    pub fn new(
+        config: &BTreeMap<String, KeyList>,
+    ) -> Result<Self, KeybindingsError> {
+        let mut keys = vec![Vec::new(); ACTIONS.len()];
+        for (action, default_keys) in DEFAULT_KEYS {
+            keys[action_index(action).unwrap()] =
+                default_keys.iter().map(|key| key.parse().unwrap()).collect();
+        }
+        for (action, key_list) in config {
+            let index = action_index(action).ok_or_else(|| {
+                KeybindingsError::UnknownAction(action.into())
+            })?;
+            keys[index] = key_list
+                .iter()
+                .map(|key| {
+                    key.parse().map_err(|why| KeybindingsError::InvalidKey {
+                        action: action.into(),
+                        key: key.into(),
+                        why,
+                    })
+                })
+                .collect::<Result<_, _>>()?;
+        }
+        if keys[action_index("quit").unwrap()].is_empty() {
+            return Err(KeybindingsError::QuitUnbound);
+        }
+
+        let mut bound: HashMap<KeyChord, usize> = HashMap::new();
+        for (index, action_keys) in keys.iter().enumerate() {
+            for key in action_keys {
+                match bound.insert(*key, index) {
+                    Some(other) if other != index => {
+                        return Err(KeybindingsError::Conflict {
+                            key: *key,
+                            first: ACTIONS[other].0,
+                            second: ACTIONS[index].0,
+                        })
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(Keybindings { keys })
+    }
+
+    pub fn event(&self, key: &KeyEvent) -> Option<Event> {
+        let chord = KeyChord::from(key);
+        self.keys
+            .iter()
+            .position(|keys| keys.contains(&chord))
+            .map(|index| ACTIONS[index].1.clone())
+    }
+
+    /// The first key of each action, to show in the UI.
+    pub fn labels(&self) -> KeyLabels {
+        KeyLabels {
+            labels: ACTIONS
+                .iter()
+                .zip(&self.keys)
+                .filter_map(|((action, _, _), keys)| {
+                    Some((*action, keys.first()?.to_string()))
+                })
+                .collect(),
+            help_lines: self.help_lines(),
+        }
+    }
+
+    /// A line for each action with all of its keys.
+    pub fn help_lines(&self) -> Vec<String> {
+        ACTIONS
+            .iter()
+            .zip(&self.keys)
+            .map(|((action, _, description), keys)| {
+                let keys = if keys.is_empty() {
+                    format!("({action} is unbound)")
+                } else {
+                    keys.iter()
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>()
+                        .join("/")
+                };
+                format!("{keys}: {description}")
+            })
+            .collect()
+    }
+}
+
+impl Default for Keybindings {
+    fn default() -> Self {
+        Keybindings::new(&BTreeMap::new()).unwrap()
+    }
+}
+
+fn action_index(action: &str) -> Option<usize> {
+    ACTIONS.iter().position(|(name, _, _)| *name == action)
+}
+
+/// The key to show for each action, unbound actions are missing.
+#[derive(Clone, Debug)]
+pub struct KeyLabels {
+    labels: HashMap<&'static str, String>,
+    /// For the help overlay
+    help_lines: Vec<String>,
+}
+
+impl KeyLabels {
+    pub fn get(&self, action: &str) -> Option<&str> {
+        self.labels.get(action).map(String::as_str)
+    }
+
+    /// See `Keybindings::help_lines`.
+    pub fn help_lines(&self) -> &[String] {
+        &self.help_lines
+    }
+}
+
+impl Default for KeyLabels {
+    fn default() -> Self {
+        Keybindings::default().labels()
+    }
+}
+
+/// Tests //////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chord(modifiers: KeyModifiers, code: KeyCode) -> KeyChord {
+        KeyChord { modifiers, code }
+    }
+
+    #[test]
+    fn key_chord_parse_test() {
+        let none = KeyModifiers::empty();
+        assert_eq!("c".parse(), Ok(chord(none, KeyCode::Char('c'))));
+        assert_eq!("M".parse(), Ok(chord(none, KeyCode::Char('M'))));
+        assert_eq!("shift-m".parse(), Ok(chord(none, KeyCode::Char('M'))));
+        assert_eq!("-".parse(), Ok(chord(none, KeyCode::Char('-'))));
+        assert_eq!(
+            "ctrl-b".parse(),
+            Ok(chord(KeyModifiers::CONTROL, KeyCode::Char('b')))
+        );
+        assert_eq!(
+            "Ctrl-Alt--".parse(),
+            Ok(chord(
+                KeyModifiers::CONTROL | KeyModifiers::ALT,
+                KeyCode::Char('-')
+            ))
+        );
+        assert_eq!("PgDown".parse(), Ok(chord(none, KeyCode::PageDown)));
+        assert_eq!("space".parse(), Ok(chord(none, KeyCode::Char(' '))));
+        assert_eq!("f5".parse(), Ok(chord(none, KeyCode::F(5))));
+        // crossterm reports shift-tab as BackTab
+        assert_eq!("shift-tab".parse(), Ok(chord(none, KeyCode::BackTab)));
+        assert_eq!("backtab".parse(), Ok(chord(none, KeyCode::BackTab)));
+        assert_eq!(
+            KeyChord::from(&KeyEvent::new(
+                KeyCode::BackTab,
+                KeyModifiers::SHIFT
+            )),
+            chord(none, KeyCode::BackTab)
+        );
+        assert!("".parse::<KeyChord>().is_err());
+        assert!("hyper-x".parse::<KeyChord>().is_err());
+        assert!("f13".parse::<KeyChord>().is_err());
+        assert!("nope".parse::<KeyChord>().is_err());
+    }
+
+    #[test]
+    fn key_chord_display_test() {
+        let display = |s: &str| s.parse::<KeyChord>().unwrap().to_string();
+        assert_eq!(display("ctrl-b"), "C-b");
+        assert_eq!(display("alt-shift-left"), "M-S-Left");
+        assert_eq!(display("pgup"), "PgUp");
+        assert_eq!(display("space"), "Space");
+        assert_eq!(display("enter"), "Enter");
+        assert_eq!(display("N"), "N");
+        assert_eq!(display("shift-tab"), "S-Tab");
+    }
+
+    #[test]
+    fn keybindings_default_test() {
+        let keybindings = Keybindings::default();
+        let event = |modifiers, code| {
+            keybindings.event(&KeyEvent::new(code, modifiers))
+        };
+        let none = KeyModifiers::empty();
+        assert!(matches!(
+            event(none, KeyCode::Char('c')),
+            Some(Event::UnmarkAll)
+        ));
+        // Some terminals report the shift of uppercase letters
+        assert!(matches!(
+            event(KeyModifiers::SHIFT, KeyCode::Char('M')),
+            Some(Event::MarkPattern)
+        ));
+        assert!(matches!(
+            event(none, KeyCode::Char('M')),
+            Some(Event::MarkPattern)
+        ));
+        assert!(matches!(
+            event(KeyModifiers::CONTROL, KeyCode::Char('f')),
+            Some(Event::PageDown)
+        ));
+        assert!(event(none, KeyCode::Char('z')).is_none());
+        assert_eq!(keybindings.help_lines().len(), ACTIONS.len());
+        assert_eq!(
+            keybindings.help_lines()[0],
+            "Left/h: Go to the parent directory"
+        );
+    }
+
+    #[test]
+    fn keybindings_config_test() {
+        let config = |entries: &[(&str, &[&str])]| {
+            entries
+                .iter()
+                .map(|(action, keys)| {
+                    let keys = keys.iter().map(|k| k.to_string()).collect();
+                    (action.to_string(), KeyList::Many(keys))
+                })
+                .collect::<BTreeMap<_, _>>()
+        };
+
+        let keybindings = Keybindings::new(&config(&[
+            ("unmark_all", &["ctrl-x"]),
+            ("search", &[]),
+        ]))
+        .unwrap();
+        let event = |modifiers, code| {
+            keybindings.event(&KeyEvent::new(code, modifiers))
+        };
+        assert!(event(KeyModifiers::empty(), KeyCode::Char('c')).is_none());
+        assert!(event(KeyModifiers::empty(), KeyCode::Char('f')).is_none());
+        assert!(matches!(
+            event(KeyModifiers::CONTROL, KeyCode::Char('x')),
+            Some(Event::UnmarkAll)
+        ));
+        let labels = keybindings.labels();
+        assert_eq!(labels.get("unmark_all"), Some("C-x"));
+        assert_eq!(labels.get("search"), None);
+        assert_eq!(labels.get("page_up"), Some("PgUp"));
+
+        assert_eq!(
+            Keybindings::new(&config(&[("mark", &["c"])])).unwrap_err(),
+            KeybindingsError::Conflict {
+                key: "c".parse().unwrap(),
+                first: "mark",
+                second: "unmark_all",
+            }
+        );
+        assert_eq!(
+            Keybindings::new(&config(&[("nope", &["x"])])).unwrap_err(),
+            KeybindingsError::UnknownAction("nope".into())
+        );
+        assert!(matches!(
+            Keybindings::new(&config(&[("quit", &["ctrl-"])])),
+            Err(KeybindingsError::InvalidKey { .. })
+        ));
+        assert_eq!(
+            Keybindings::new(&config(&[("quit", &[])])).unwrap_err(),
+            KeybindingsError::QuitUnbound
+        );
+    }
+}
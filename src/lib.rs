@@ -1,3 +1,4 @@
 pub mod cache;
+pub mod glob;
 pub mod reporter;
 pub mod restic;
@@ -1,6 +1,8 @@
 use std::{
+    collections::{HashMap, HashSet},
     fs,
-    io::{self, stderr},
+    io::{self, stderr, Write},
+    mem,
     sync::{
         atomic::{AtomicBool, Ordering},
         mpsc::{self, RecvTimeoutError},
@@ -11,7 +13,7 @@ use std::{
 };
 
 use anyhow::Context;
-use args::Args;
+use args::{Args, Command, ExportFormat, MarksCommand, OutputFormat};
 use camino::{Utf8Path, Utf8PathBuf};
 use chrono::Local;
 use crossterm::{
@@ -28,23 +30,33 @@ use rand::{rng, seq::SliceRandom};
 use ratatui::{
     backend::{Backend, CrosstermBackend},
     layout::Size,
-    style::Stylize,
     widgets::WidgetRef,
     CompletedFrame, Terminal,
 };
 use redu::{
-    cache::{self, filetree::SizeTree, Cache, Migrator},
+    cache::{self, dedup::UniqueSizes, Cache, Migrator, StagedSnapshot},
     reporter::{Counter, NullReporter, Reporter, TermReporter},
-    restic::{self, escape_for_exclude, Restic, Snapshot},
+    restic::{self, File, Restic, Snapshot, SnapshotFilter, Tree},
 };
 use scopeguard::defer;
 use simplelog::{ThreadLogMode, WriteLogger};
 use thiserror::Error;
 use util::snapshot_short_id;
 
-use crate::ui::{Action, App, Event};
+use crate::{
+    config::Config,
+    keys::Keybindings,
+    ui::{Action, App, Event},
+};
 
 mod args;
+mod config;
+mod diff;
+mod export;
+mod keys;
+mod marks;
+mod report;
+mod search;
 mod ui;
 mod util;
 
@@ -58,19 +70,28 @@ macro_rules! info_report {
 }
 
 fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
-    let restic = Restic::new(args.repository, args.password, args.no_cache);
-
     let dirs = ProjectDirs::from("eu", "drdo", "redu")
         .expect("unable to determine project directory");
 
+    let config_file = dirs.config_dir().join("config.toml");
+    let config = Config::load(&config_file)?;
+    let keybindings =
+        Keybindings::new(&config.keybindings).with_context(|| {
+            format!("invalid keybindings in {}", config_file.display())
+        })?;
+
+    let args = Args::parse(&keybindings.help_lines(), &config.profiles);
+    // Subcommands never show the UI
+    let non_interactive = args.non_interactive || args.command.is_some();
+    let restic = Restic::new(args.repository, args.password, args.no_cache);
+
     // Initialize the logger
     let log_config = simplelog::ConfigBuilder::new()
         .set_target_level(LevelFilter::Error)
         .set_thread_mode(ThreadLogMode::Names)
         .build();
 
-    if args.non_interactive {
+    if non_interactive {
         WriteLogger::init(args.log_level, log_config, stderr())?;
     } else {
         fn generate_filename() -> String {
@@ -108,7 +129,7 @@ fn main() -> anyhow::Result<()> {
         }))?;
     }
 
-    let reporter: Arc<dyn Reporter + Send + Sync> = if args.non_interactive {
+    let reporter: Arc<dyn Reporter + Send + Sync> = if non_interactive {
         Arc::new(NullReporter::new())
     } else {
         Arc::new(TermReporter::new())
@@ -153,25 +174,159 @@ fn main() -> anyhow::Result<()> {
         }
     };
 
-    sync_snapshots(&restic, &mut cache, reporter.clone(), args.parallelism)?;
+    sync_snapshots(
+        &restic,
+        &mut cache,
+        reporter.clone(),
+        args.parallelism,
+        args.incremental,
+    )?;
 
-    if args.non_interactive {
-        info_report!(reporter, "Finished syncing");
-    } else {
-        let paths = ui(&*reporter, cache)?;
-        for line in paths {
-            println!("{}", escape_for_exclude(line.as_str()));
+    if args.unique_sizes && !cache.has_unique_sizes()? {
+        compute_unique_sizes(&restic, &mut cache, &*reporter)?;
+    }
+
+    match args.command {
+        Some(Command::Report { path, top, depth, format, modified_before }) => {
+            select_snapshots(&mut cache, &args.snapshot_filter, &*reporter)?;
+            let report = report::collect(
+                &cache,
+                path.as_deref(),
+                depth,
+                top,
+                modified_before,
+            )?;
+            report::write(&mut io::stdout().lock(), format, &report)?;
+        }
+        Some(Command::Diff { old, new, path, top, depth, format }) => {
+            let old = find_snapshot(&cache, &args.snapshot_filter, &old)?;
+            let new = find_snapshot(&cache, &args.snapshot_filter, &new)?;
+            let report = diff::collect(
+                &cache,
+                &old.id,
+                &new.id,
+                path.as_deref(),
+                depth,
+                top,
+            )?;
+            diff::write(&mut io::stdout().lock(), format, &report)?;
+        }
+        Some(Command::Search { pattern, top, format }) => {
+            select_snapshots(&mut cache, &args.snapshot_filter, &*reporter)?;
+            let results = search::collect(&cache, &pattern, top)?;
+            search::write(&mut io::stdout().lock(), format, &results)?;
+        }
+        Some(Command::Marks { command: MarksCommand::Import { file } }) => {
+            let contents = fs::read_to_string(&file)
+                .with_context(|| format!("unable to read {file}"))?;
+            let report = marks::import(&mut cache, &contents)?;
+            for line in report.lines() {
+                println!("{line}");
+            }
+        }
+        Some(Command::Marks { command: MarksCommand::Check { marks } }) => {
+            select_snapshots(&mut cache, &args.snapshot_filter, &*reporter)?;
+            let (paths, patterns) = if marks.is_empty() {
+                (cache.get_marks()?, cache.get_pattern_marks()?)
+            } else {
+                marks::split_marks(&marks)?
+            };
+            let checks = cache.check_marks(paths, patterns)?;
+            for line in marks::check_lines(&checks) {
+                println!("{line}");
+            }
+        }
+        Some(Command::Export { format, snapshot }) => {
+            select_snapshots(&mut cache, &args.snapshot_filter, &*reporter)?;
+            if let Some(id) = snapshot {
+                let snapshot =
+                    find_snapshot(&cache, &args.snapshot_filter, &id)?;
+                info_report!(
+                    reporter,
+                    "Exporting snapshot {}",
+                    snapshot_short_id(&snapshot.id)
+                );
+                cache.select_snapshots(Some(vec![snapshot.id.clone()]))?;
+            }
+            let mut out = io::BufWriter::new(io::stdout().lock());
+            match format {
+                ExportFormat::Ncdu => export::write_ncdu(
+                    &mut out,
+                    &cache,
+                    Local::now().timestamp(),
+                )?,
+            }
+            out.flush()?;
+        }
+        None if args.non_interactive => {
+            info_report!(reporter, "Finished syncing");
+        }
+        None => {
+            select_snapshots(&mut cache, &args.snapshot_filter, &*reporter)?;
+            let (lines, separator) = ui(
+                &*reporter,
+                cache,
+                &args.snapshot_filter,
+                &keybindings,
+                args.output_format,
+                args.script,
+                &args.script_options,
+            )?;
+            let mut out = io::stdout().lock();
+            for line in lines {
+                write!(out, "{line}{separator}")?;
+            }
         }
     }
 
     Ok(())
 }
 
+/// Find a cached snapshot by id, see `util::resolve_snapshot`.
+/// `latest` takes the filter into account.
+fn find_snapshot(
+    cache: &Cache,
+    filter: &SnapshotFilter,
+    id: &str,
+) -> anyhow::Result<Snapshot> {
+    let mut snapshots = cache.get_snapshots()?;
+    if id == "latest" {
+        snapshots.retain(|snapshot| filter.matches(snapshot));
+    }
+    Ok(util::resolve_snapshot(&snapshots, id)?.clone())
+}
+
+/// Restrict the cache to the snapshots that match the filter, if any.
+fn select_snapshots<R: Reporter + ?Sized>(
+    cache: &mut Cache,
+    filter: &SnapshotFilter,
+    reporter: &R,
+) -> anyhow::Result<()> {
+    if filter.is_empty() {
+        return Ok(());
+    }
+    let snapshots = cache.get_snapshots()?;
+    let selected = snapshots
+        .iter()
+        .filter(|snapshot| filter.matches(snapshot))
+        .map(|snapshot| snapshot.id.clone())
+        .collect::<Vec<_>>();
+    info_report!(
+        reporter,
+        "{} of {} snapshot(s) match the filters",
+        selected.len(),
+        snapshots.len()
+    );
+    cache.select_snapshots(Some(selected))?;
+    Ok(())
+}
+
 fn sync_snapshots<R: Reporter + Send + Sync + ?Sized>(
     restic: &Restic,
     cache: &mut Cache,
     reporter: Arc<R>,
     fetching_thread_count: usize,
+    incremental: bool,
 ) -> anyhow::Result<()> {
     let progress = reporter.add_loader(0, "Fetching repository snapshot list");
     let repo_snapshots = restic.snapshots()?;
@@ -207,14 +362,104 @@ fn sync_snapshots<R: Reporter + Send + Sync + ?Sized>(
         bar.end();
     }
 
-    let mut missing_snapshots: Vec<Snapshot> = repo_snapshots
-        .into_iter()
-        .filter(|repo_snapshot| {
-            !cache_snapshots
+    // The cached snapshots that are still in the repo
+    let kept_snapshots: Vec<Snapshot> = cache_snapshots
+        .iter()
+        .filter(|cache_snapshot| {
+            repo_snapshots
                 .iter()
-                .any(|cache_snapshot| cache_snapshot.id == repo_snapshot.id)
+                .any(|repo_snapshot| cache_snapshot.id == repo_snapshot.id)
         })
+        .cloned()
         .collect();
+    // The cached snapshots that we can fetch new snapshots against
+    let base_snapshots: &[Snapshot] =
+        if incremental { &kept_snapshots } else { &[] };
+
+    // What a previous sync staged, except for the snapshots that are gone
+    let mut staged_snapshots: HashMap<String, StagedSnapshot> = HashMap::new();
+    for staged in cache.get_staged_snapshots()? {
+        let is_missing = repo_snapshots.iter().any(|s| s.id == staged.hash)
+            && !cache_snapshots.iter().any(|s| s.id == staged.hash);
+        if is_missing {
+            staged_snapshots.insert(staged.hash.clone(), staged);
+        } else {
+            cache.delete_staged_snapshot(&staged.hash)?;
+        }
+    }
+
+    let mut missing_snapshots: Vec<FetchJob> = Vec::new();
+    let mut complete_snapshots: Vec<Snapshot> = Vec::new();
+    // Snapshots with the same tree as another one have the same files
+    // so they are copied instead of fetched:
+    // (snapshot, the one with the same tree).
+    // The ones that have to wait for the other one to be fetched
+    // are copied at the end.
+    let mut clones: Vec<(Snapshot, String)> = Vec::new();
+    let mut late_clones: Vec<(Snapshot, String)> = Vec::new();
+    for snapshot in repo_snapshots {
+        if cache_snapshots.iter().any(|s| s.id == snapshot.id) {
+            continue;
+        }
+        if let Some(source) = kept_snapshots
+            .iter()
+            .chain(&complete_snapshots)
+            .find(|s| s.tree == snapshot.tree)
+        {
+            let source = source.id.clone();
+            clones.push((snapshot, source));
+            continue;
+        }
+        if let Some(job) =
+            missing_snapshots.iter().find(|j| j.snapshot.tree == snapshot.tree)
+        {
+            let source = job.snapshot.id.clone();
+            late_clones.push((snapshot, source));
+            continue;
+        }
+        let base = find_base(&snapshot, base_snapshots).cloned();
+        let staged = match staged_snapshots.remove(&snapshot.id) {
+            Some(staged) if staged.complete => {
+                complete_snapshots.push(snapshot);
+                continue;
+            }
+            // The files are only in the same order if it's fetched
+            // the same way as before
+            Some(staged)
+                if staged.base.as_deref()
+                    == base.as_ref().map(|b| b.id.as_str()) =>
+            {
+                Some(staged)
+            }
+            Some(staged) => {
+                cache.delete_staged_snapshot(&staged.hash)?;
+                None
+            }
+            None => None,
+        };
+        missing_snapshots.push(FetchJob { snapshot, base, staged });
+    }
+
+    if !complete_snapshots.is_empty() {
+        info_report!(
+            reporter,
+            "Need to save {} snapshot(s) fetched by a previous sync",
+            complete_snapshots.len()
+        );
+        let mut bar = reporter.add_bar(
+            0,
+            "Saving snapshots ",
+            complete_snapshots.len() as u64,
+        );
+        for snapshot in complete_snapshots {
+            let file_count = cache.save_staged_snapshot(&snapshot)?;
+            info!("saved staged snapshot {} ({file_count} files)", snapshot.id);
+            bar.inc(1);
+        }
+        bar.end();
+    }
+    clone_snapshots(cache, &*reporter, clones)?;
+
     missing_snapshots.shuffle(&mut rng());
     let total_missing_snapshots = match missing_snapshots.len() {
         0 => {
@@ -236,6 +481,8 @@ fn sync_snapshots<R: Reporter + Send + Sync + ?Sized>(
 
     const SHOULD_QUIT_POLL_PERIOD: Duration = Duration::from_millis(500);
 
+    let base_trees = &BaseTrees::default();
+    let db_cache = &mut *cache;
     thread::scope(|scope| {
         macro_rules! spawn {
             ($name_fmt:literal, $scope:expr, $thunk:expr) => {
@@ -252,22 +499,26 @@ fn sync_snapshots<R: Reporter + Send + Sync + ?Sized>(
         // prematurely terminate (when other threads get unrecoverable errors).
         let should_quit: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
 
-        // Channel to funnel snapshots from the fetching threads to the db thread
-        let (snapshot_sender, snapshot_receiver) =
-            mpsc::sync_channel::<(Snapshot, SizeTree)>(fetching_thread_count);
+        // Channel to funnel the files from the fetching threads to the db thread
+        let (fetched_sender, fetched_receiver) =
+            mpsc::sync_channel::<Fetched>(fetching_thread_count);
 
         // Start fetching threads
         for i in 0..fetching_thread_count {
+            let reader =
+                if incremental { Some(db_cache.reopen()?) } else { None };
             let missing_queue = missing_queue.clone();
-            let snapshot_sender = snapshot_sender.clone();
+            let fetched_sender = fetched_sender.clone();
             let reporter = reporter.clone();
             let should_quit = should_quit.clone();
             handles.push(spawn!("fetching-{i}", &scope, move || {
                 fetching_thread_body(
                     restic,
+                    base_trees,
+                    reader,
                     missing_queue,
                     reporter,
-                    snapshot_sender,
+                    fetched_sender,
                     should_quit.clone(),
                 )
                 .inspect_err(|_| should_quit.store(true, Ordering::SeqCst))
@@ -275,8 +526,8 @@ fn sync_snapshots<R: Reporter + Send + Sync + ?Sized>(
             }));
         }
         // Drop the leftover channel so that the db thread
-        // can properly terminate when all snapshot senders are closed
-        drop(snapshot_sender);
+        // can properly terminate when all senders are closed
+        drop(fetched_sender);
 
         // Start DB thread
         handles.push({
@@ -284,10 +535,10 @@ fn sync_snapshots<R: Reporter + Send + Sync + ?Sized>(
             let should_quit = should_quit.clone();
             spawn!("db", &scope, move || {
                 db_thread_body(
-                    cache,
+                    db_cache,
                     &*reporter,
                     fetch_snapshots_bar,
-                    snapshot_receiver,
+                    fetched_receiver,
                     should_quit.clone(),
                     SHOULD_QUIT_POLL_PERIOD,
                 )
@@ -299,8 +550,60 @@ fn sync_snapshots<R: Reporter + Send + Sync + ?Sized>(
         for handle in handles {
             handle.join().unwrap()?
         }
-        Ok(())
-    })
+        Ok::<(), anyhow::Error>(())
+    })?;
+
+    clone_snapshots(cache, &*reporter, late_clones)?;
+    Ok(())
+}
+
+/// Saves each snapshot by copying the entries of the cached snapshot
+/// with the same tree.
+fn clone_snapshots<R: Reporter + ?Sized>(
+    cache: &mut Cache,
+    reporter: &R,
+    clones: Vec<(Snapshot, String)>,
+) -> Result<(), rusqlite::Error> {
+    if clones.is_empty() {
+        return Ok(());
+    }
+    info_report!(
+        reporter,
+        "Need to copy {} snapshot(s) with the same files as a cached one",
+        clones.len()
+    );
+    let mut bar =
+        reporter.add_bar(0, "Copying snapshots ", clones.len() as u64);
+    for (snapshot, source) in clones {
+        let file_count = cache.clone_snapshot(&snapshot, &source)?;
+        info!(
+            "copied snapshot {} from {source} ({file_count} files)",
+            snapshot.id
+        );
+        bar.inc(1);
+    }
+    bar.end();
+    Ok(())
+}
+
+/// A snapshot that needs to be fetched.
+#[derive(Clone)]
+struct FetchJob {
+    snapshot: Snapshot,
+    /// The cached snapshot to fetch it incrementally from
+    base: Option<Snapshot>,
+    /// What a previous sync already staged
+    staged: Option<StagedSnapshot>,
+}
+
+/// What the fetching threads send to the db thread.
+enum Fetched {
+    /// The next files of a snapshot, to add to its staged files
+    Files { hash: String, base: Option<String>, files: Vec<File> },
+    /// The staged files of the snapshot are not usable, start over
+    Discard(String),
+    /// All the files of the snapshot were sent
+    Done { snapshot: Box<Snapshot>, base: Option<String> },
 }
 
 #[derive(Debug, Error)]
@@ -309,39 +612,68 @@ enum FetchingThreadError {
     ResticLaunch(#[from] restic::LaunchError),
     Restic(#[from] restic::Error),
     Cache(#[from] rusqlite::Error),
+    /// The files don't come in the same order as the staged ones
+    StagingMismatch,
 }
 
 fn fetching_thread_body<R: Reporter + ?Sized>(
     restic: &Restic,
-    missing_queue: FixedSizeQueue<Snapshot>,
+    base_trees: &BaseTrees,
+    cache: Option<Cache>,
+    missing_queue: FixedSizeQueue<FetchJob>,
     reporter: Arc<R>,
-    snapshot_sender: mpsc::SyncSender<(Snapshot, SizeTree)>,
+    fetched_sender: mpsc::SyncSender<Fetched>,
     should_quit: Arc<AtomicBool>,
 ) -> Result<(), FetchingThreadError> {
     defer! { trace!("terminated") }
     trace!("started");
-    while let Some(snapshot) = missing_queue.pop() {
+    while let Some(FetchJob { snapshot, base, staged }) = missing_queue.pop() {
         let short_id = snapshot_short_id(&snapshot.id);
-        let mut progress = reporter.add_counter(
-            4,
-            &format!("fetching {short_id} "),
-            " file(s)",
-        );
-        let mut sizetree = SizeTree::new();
-        let files = restic.ls(&snapshot.id)?;
         trace!("started fetching snapshot ({short_id})");
         let start = Instant::now();
-        for r in files {
-            if should_quit.load(Ordering::SeqCst) {
-                return Ok(());
-            }
-            let file = r?;
-            sizetree
-                .insert(file.path.components(), file.size)
-                .expect("repeated entry in restic snapshot ls");
-            progress.inc(1);
+        let finished = fetch_staged(
+            &snapshot,
+            base.as_ref(),
+            staged,
+            &fetched_sender,
+            |stager| {
+                let mut progress = reporter.add_counter(
+                    4,
+                    &format!("fetching {short_id} "),
+                    " file(s)",
+                );
+                let finished = match (&cache, &base) {
+                    (Some(cache), Some(base)) => {
+                        info!(
+                            "fetching incrementally from {} ({short_id})",
+                            snapshot_short_id(&base.id)
+                        );
+                        fetch_incremental(
+                            restic,
+                            base_trees,
+                            cache,
+                            &snapshot,
+                            base,
+                            stager,
+                            &mut *progress,
+                            &should_quit,
+                        )
+                    }
+                    _ => fetch_full(
+                        restic,
+                        &snapshot,
+                        stager,
+                        &mut *progress,
+                        &should_quit,
+                    ),
+                };
+                progress.end();
+                finished
+            },
+        )?;
+        if !finished {
+            return Ok(());
         }
-        progress.end();
         info!(
             "snapshot fetched in {}s ({short_id})",
             start.elapsed().as_secs_f64()
@@ -350,7 +682,12 @@ fn fetching_thread_body<R: Reporter + ?Sized>(
             return Ok(());
         }
         let start = Instant::now();
-        snapshot_sender.send((snapshot.clone(), sizetree)).unwrap();
+        fetched_sender
+            .send(Fetched::Done {
+                snapshot: Box::new(snapshot.clone()),
+                base: base.map(|base| base.id),
+            })
+            .unwrap();
         debug!(
             "waited {}s to send snapshot ({short_id})",
             start.elapsed().as_secs_f64()
@@ -359,6 +696,302 @@ fn fetching_thread_body<R: Reporter + ?Sized>(
     Ok(())
 }
 
+/// How many files are sent to the db thread at a time,
+/// each chunk is a checkpoint that an interrupted sync can resume from.
+const STAGING_CHUNK_SIZE: usize = 10_000;
+
+/// Runs `fetch` with a `Stager` for the snapshot and sends the last files.
+/// If the staged files turn out not to match what `fetch` lists,
+/// they are discarded and it starts over from the first file.
+///
+/// Returns `false` if we should quit before finishing.
+fn fetch_staged(
+    snapshot: &Snapshot,
+    base: Option<&Snapshot>,
+    mut staged: Option<StagedSnapshot>,
+    sender: &mpsc::SyncSender<Fetched>,
+    mut fetch: impl FnMut(&mut Stager) -> Result<bool, FetchingThreadError>,
+) -> Result<bool, FetchingThreadError> {
+    let short_id = snapshot_short_id(&snapshot.id);
+    loop {
+        if let Some(staged) = &staged {
+            info!(
+                "resuming after {} staged file(s) ({short_id})",
+                staged.files
+            );
+        }
+        let mut stager = Stager::new(snapshot, base, staged.take(), sender);
+        let finished = fetch(&mut stager).and_then(|finished| {
+            if finished {
+                stager.finish()?;
+            }
+            Ok(finished)
+        });
+        match finished {
+            Err(FetchingThreadError::StagingMismatch) => {
+                info!("staged files don't match, starting over ({short_id})");
+                sender.send(Fetched::Discard(snapshot.id.clone())).unwrap();
+            }
+            finished => return finished,
+        }
+    }
+}
+
+/// Sends the files of a snapshot to the db thread in chunks.
+/// When resuming, the files that were already staged are skipped.
+/// restic lists them in the same order every time but it still has to
+/// go through them, so this only saves the work of staging them again.
+struct Stager<'a> {
+    hash: &'a str,
+    base: Option<&'a str>,
+    sender: &'a mpsc::SyncSender<Fetched>,
+    /// How many files to skip and the path of the last one
+    skip: usize,
+    last_staged: Option<Utf8PathBuf>,
+    seen: usize,
+    chunk: Vec<File>,
+}
+
+impl<'a> Stager<'a> {
+    fn new(
+        snapshot: &'a Snapshot,
+        base: Option<&'a Snapshot>,
+        staged: Option<StagedSnapshot>,
+        sender: &'a mpsc::SyncSender<Fetched>,
+    ) -> Self {
+        let (skip, last_staged) = staged
+            .map(|staged| (staged.files, staged.last_path))
+            .unwrap_or_default();
+        Stager {
+            hash: &snapshot.id,
+            base: base.map(|base| base.id.as_str()),
+            sender,
+            skip,
+            last_staged,
+            seen: 0,
+            chunk: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, file: File) -> Result<(), FetchingThreadError> {
+        self.seen += 1;
+        if self.seen < self.skip {
+            return Ok(());
+        }
+        if self.seen == self.skip {
+            return if self.last_staged.as_ref() == Some(&file.path) {
+                Ok(())
+            } else {
+                Err(FetchingThreadError::StagingMismatch)
+            };
+        }
+        self.chunk.push(file);
+        if self.chunk.len() >= STAGING_CHUNK_SIZE {
+            self.flush();
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) {
+        if !self.chunk.is_empty() {
+            self.sender
+                .send(Fetched::Files {
+                    hash: self.hash.to_string(),
+                    base: self.base.map(ToString::to_string),
+                    files: mem::take(&mut self.chunk),
+                })
+                .unwrap();
+        }
+    }
+
+    /// Sends the remaining files.
+    fn finish(&mut self) -> Result<(), FetchingThreadError> {
+        if self.seen < self.skip {
+            return Err(FetchingThreadError::StagingMismatch);
+        }
+        self.flush();
+        Ok(())
+    }
+}
+
+/// Returns `false` if we should quit before finishing.
+fn fetch_full(
+    restic: &Restic,
+    snapshot: &Snapshot,
+    stager: &mut Stager,
+    progress: &mut dyn Counter,
+    should_quit: &AtomicBool,
+) -> Result<bool, FetchingThreadError> {
+    for r in restic.ls(&snapshot.id)? {
+        if should_quit.load(Ordering::SeqCst) {
+            return Ok(false);
+        }
+        stager.add(r?)?;
+        progress.inc(1);
+    }
+    Ok(true)
+}
+
+/// How many tree objects `fetch_incremental` fetches at a time.
+/// Each one is a restic process that loads the repository index,
+/// so this multiplies the memory that restic uses by up to this much.
+const TREE_BATCH_SIZE: usize = 4;
+
+/// Walks the tree of `snapshot` side by side with the tree of `base`.
+/// Only the tree objects that differ are fetched from restic,
+/// the files in the subtrees that are identical are copied from the cache.
+///
+/// Returns `false` if we should quit before finishing.
+#[allow(clippy::too_many_arguments)]
+fn fetch_incremental(
+    restic: &impl Trees,
+    base_trees: &BaseTrees,
+    cache: &Cache,
+    snapshot: &Snapshot,
+    base: &Snapshot,
+    stager: &mut Stager,
+    progress: &mut dyn Counter,
+    should_quit: &AtomicBool,
+) -> Result<bool, FetchingThreadError> {
+    // (path, tree id, tree id of the same path in base)
+    let mut stack: Vec<(Utf8PathBuf, String, Option<String>)> =
+        vec![("/".into(), snapshot.tree.clone(), Some(base.tree.clone()))];
+    loop {
+        if should_quit.load(Ordering::SeqCst) {
+            return Ok(false);
+        }
+        let mut batch = Vec::with_capacity(TREE_BATCH_SIZE);
+        while batch.len() < TREE_BATCH_SIZE {
+            let Some((path, tree, base_tree)) = stack.pop() else {
+                break;
+            };
+            if base_tree.as_ref() != Some(&tree) {
+                batch.push((path, tree, base_tree));
+                continue;
+            }
+            if let Some(path_id) = cache.get_path_id_by_path(&path)? {
+                cache.for_each_file(&base.id, path_id, |file| {
+                    stager.add(File { path: path.join(&file.path), ..file })?;
+                    progress.inc(1);
+                    Ok::<(), FetchingThreadError>(())
+                })?;
+            }
+        }
+        if batch.is_empty() {
+            return Ok(true);
+        }
+
+        let ids =
+            batch.iter().map(|(_, tree, _)| tree.clone()).collect::<Vec<_>>();
+        let base_ids = batch
+            .iter()
+            .filter_map(|(_, _, base_tree)| base_tree.clone())
+            .collect::<Vec<_>>();
+        let base_listings = base_trees.get(restic, &base_ids)?;
+        let trees = restic.trees(&ids)?;
+        for ((path, _, base_tree), tree) in batch.into_iter().zip(trees) {
+            let base_subtrees = match base_tree {
+                None => Arc::default(),
+                Some(base_tree) => base_listings[&base_tree].clone(),
+            };
+            for node in tree.nodes {
+                // Like `Restic::ls`, everything but the directories is a file
+                match (node.node_type.as_str(), &node.subtree) {
+                    ("dir", Some(subtree)) => {
+                        let base_subtree =
+                            base_subtrees.get(&node.name).cloned();
+                        stack.push((
+                            path.join(&node.name),
+                            subtree.clone(),
+                            base_subtree,
+                        ));
+                    }
+                    ("dir", None) => {}
+                    _ => {
+                        let file_path = path.join(&node.name);
+                        stager.add(node.into_file(file_path))?;
+                        progress.inc(1);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Where `fetch_incremental` gets the tree objects from.
+trait Trees {
+    fn trees(&self, ids: &[String]) -> Result<Vec<Tree>, restic::Error>;
+}
+
+impl Trees for Restic {
+    fn trees(&self, ids: &[String]) -> Result<Vec<Tree>, restic::Error> {
+        Restic::trees(self, ids)
+    }
+}
+
+/// The subtree of each directory of the tree objects of the base snapshots.
+/// Many new snapshots usually have the same base, so this is shared
+/// by the fetching threads and each base tree is only fetched once a sync.
+#[derive(Default)]
+struct BaseTrees(Mutex<HashMap<String, Arc<HashMap<String, String>>>>);
+
+impl BaseTrees {
+    /// Fetches the trees that we don't have yet.
+    fn get(
+        &self,
+        restic: &impl Trees,
+        ids: &[String],
+    ) -> Result<HashMap<String, Arc<HashMap<String, String>>>, restic::Error>
+    {
+        let missing = {
+            let known = self.0.lock().unwrap();
+            ids.iter()
+                .filter(|id| !known.contains_key(*id))
+                .cloned()
+                .collect::<HashSet<_>>()
+                .into_iter()
+                .collect::<Vec<_>>()
+        };
+        if !missing.is_empty() {
+            let trees = restic.trees(&missing)?;
+            let mut known = self.0.lock().unwrap();
+            for (id, tree) in missing.into_iter().zip(trees) {
+                let subtrees = tree
+                    .nodes
+                    .into_iter()
+                    .filter_map(|node| Some((node.name, node.subtree?)))
+                    .collect();
+                known.insert(id, Arc::new(subtrees));
+            }
+        }
+        let known = self.0.lock().unwrap();
+        Ok(ids.iter().map(|id| (id.clone(), known[id].clone())).collect())
+    }
+}
+
+/// Picks an already cached snapshot to fetch `snapshot` incrementally from.
+/// This is the parent if we have it, otherwise the latest older snapshot
+/// of the same host and paths (the same one that restic would pick).
+fn find_base<'a>(
+    snapshot: &Snapshot,
+    cached_snapshots: &'a [Snapshot],
+) -> Option<&'a Snapshot> {
+    snapshot
+        .parent
+        .as_ref()
+        .and_then(|parent| cached_snapshots.iter().find(|s| &s.id == parent))
+        .or_else(|| {
+            cached_snapshots
+                .iter()
+                .filter(|s| {
+                    s.hostname == snapshot.hostname
+                        && s.paths == snapshot.paths
+                        && s.time < snapshot.time
+                })
+                .max_by_key(|s| s.time)
+        })
+}
+
 #[derive(Debug, Error)]
 #[error("error in db thread")]
 enum DBThreadError {
@@ -369,26 +1002,35 @@ fn db_thread_body<R: Reporter + ?Sized>(
     cache: &mut Cache,
     reporter: &R,
     mut fetch_snapshots_bar: Box<dyn Counter>,
-    snapshot_receiver: mpsc::Receiver<(Snapshot, SizeTree)>,
+    fetched_receiver: mpsc::Receiver<Fetched>,
     should_quit: Arc<AtomicBool>,
     should_quit_poll_period: Duration,
 ) -> Result<(), DBThreadError> {
     defer! { trace!("terminated") }
     trace!("started");
     loop {
-        trace!("waiting for snapshot");
+        trace!("waiting for files");
         if should_quit.load(Ordering::SeqCst) {
             return Ok(());
         }
         let start = Instant::now();
         // We wait with timeout to poll the should_quit periodically
-        match snapshot_receiver.recv_timeout(should_quit_poll_period) {
-            Ok((snapshot, sizetree)) => {
+        match fetched_receiver.recv_timeout(should_quit_poll_period) {
+            Ok(Fetched::Files { hash, base, files }) => {
+                trace!("got {} file(s), staging", files.len());
+                cache.stage_files(&hash, base.as_deref(), &files)?;
+            }
+            Ok(Fetched::Discard(hash)) => {
+                cache.delete_staged_snapshot(&hash)?;
+            }
+            Ok(Fetched::Done { snapshot, base }) => {
                 debug!(
                     "waited {}s to get snapshot",
                     start.elapsed().as_secs_f64()
                 );
                 trace!("got snapshot, saving");
+                cache
+                    .complete_staged_snapshot(&snapshot.id, base.as_deref())?;
                 if should_quit.load(Ordering::SeqCst) {
                     return Ok(());
                 }
@@ -396,7 +1038,7 @@ fn db_thread_body<R: Reporter + ?Sized>(
                 let progress =
                     reporter.add_loader(4, &format!("saving {short_id}"));
                 let start = Instant::now();
-                let file_count = cache.save_snapshot(&snapshot, sizetree)?;
+                let file_count = cache.save_staged_snapshot(&snapshot)?;
                 progress.end();
                 fetch_snapshots_bar.inc(1);
                 info!(
@@ -415,54 +1057,182 @@ fn db_thread_body<R: Reporter + ?Sized>(
     }
 }
 
-fn convert_event(event: crossterm::event::Event) -> Option<Event> {
+/// Walks every snapshot to find which paths reference each blob.
+/// Identical subtrees at the same path are only walked once.
+fn compute_unique_sizes<R: Reporter + ?Sized>(
+    restic: &Restic,
+    cache: &mut Cache,
+    reporter: &R,
+) -> anyhow::Result<()> {
+    let progress = reporter.add_loader(0, "Fetching blob sizes");
+    let blob_sizes = restic.blob_sizes()?;
+    progress.end();
+
+    let snapshots = cache.get_snapshots()?;
+    let mut bar =
+        reporter.add_bar(0, "Computing unique sizes ", snapshots.len() as u64);
+    let start = Instant::now();
+    let unique_sizes = walk_unique_sizes(restic, &snapshots, || bar.inc(1))?;
+    bar.end();
+    info!("unique sizes computed in {}s", start.elapsed().as_secs_f64());
+
+    let progress = reporter.add_loader(0, "Saving unique sizes");
+    cache.save_unique_sizes(unique_sizes.finish(&blob_sizes))?;
+    progress.end();
+    Ok(())
+}
+
+/// Adds the files of every snapshot to a `UniqueSizes`,
+/// calling `snapshot_done` after each snapshot.
+///
+/// Each distinct tree object is fetched once and its files are added
+/// where it was first found. Its subdirectories are remembered so that
+/// the same tree somewhere else is walked without fetching it again.
+/// The trees that were found in more than one place are fetched once more
+/// at the end, to add their files in the other places.
+fn walk_unique_sizes(
+    restic: &impl Trees,
+    snapshots: &[Snapshot],
+    mut snapshot_done: impl FnMut(),
+) -> Result<UniqueSizes, restic::Error> {
+    let mut unique_sizes = UniqueSizes::new();
+    // tree id -> (the node of the path it was first found at,
+    //             the (name, tree id) of its subdirectories)
+    let mut walked: HashMap<String, (usize, Vec<(String, String)>)> =
+        HashMap::new();
+    // tree id -> the nodes of the other paths it was found at
+    let mut moved: HashMap<String, HashSet<usize>> = HashMap::new();
+    for snapshot in snapshots {
+        let mut stack = vec![(UniqueSizes::ROOT, snapshot.tree.clone())];
+        loop {
+            let mut batch = Vec::with_capacity(TREE_BATCH_SIZE);
+            while batch.len() < TREE_BATCH_SIZE {
+                let Some((node, tree)) = stack.pop() else {
+                    break;
+                };
+                match walked.get(&tree) {
+                    None => batch.push((node, tree)),
+                    Some((first_node, _)) if *first_node == node => {}
+                    Some((_, subdirs)) => {
+                        if moved.entry(tree).or_default().insert(node) {
+                            for (name, subtree) in subdirs {
+                                let child = unique_sizes.child(node, name);
+                                stack.push((child, subtree.clone()));
+                            }
+                        }
+                    }
+                }
+            }
+            if batch.is_empty() {
+                break;
+            }
+            let ids =
+                batch.iter().map(|(_, tree)| tree.clone()).collect::<Vec<_>>();
+            for ((node, id), tree) in batch.into_iter().zip(restic.trees(&ids)?)
+            {
+                if walked.contains_key(&id) {
+                    // It was twice in the batch, walk it again
+                    stack.push((node, id));
+                    continue;
+                }
+                let mut subdirs = Vec::new();
+                for tree_node in tree.nodes {
+                    let child = unique_sizes.child(node, &tree_node.name);
+                    match (tree_node.node_type.as_str(), tree_node.subtree) {
+                        ("file", _) => {
+                            unique_sizes.add_blobs(child, &tree_node.content)
+                        }
+                        ("dir", Some(subtree)) => {
+                            stack.push((child, subtree.clone()));
+                            subdirs.push((tree_node.name, subtree));
+                        }
+                        _ => {}
+                    }
+                }
+                walked.insert(id, (node, subdirs));
+            }
+        }
+        snapshot_done();
+    }
+
+    let moved = moved.into_iter().collect::<Vec<_>>();
+    for batch in moved.chunks(TREE_BATCH_SIZE) {
+        let ids =
+            batch.iter().map(|(tree, _)| tree.clone()).collect::<Vec<_>>();
+        for ((_, nodes), tree) in batch.iter().zip(restic.trees(&ids)?) {
+            for tree_node in tree.nodes.iter().filter(|n| n.node_type == "file")
+            {
+                for &node in nodes {
+                    let child = unique_sizes.child(node, &tree_node.name);
+                    unique_sizes.add_blobs(child, &tree_node.content);
+                }
+            }
+        }
+    }
+    Ok(unique_sizes)
+}
+
+fn convert_event(
+    keybindings: &Keybindings,
+    event: crossterm::event::Event,
+) -> Option<Event> {
     use crossterm::event::{Event as TermEvent, KeyEventKind};
     use ui::Event::*;
 
-    const KEYBINDINGS: &[((KeyModifiers, KeyCode), Event)] = &[
-        ((KeyModifiers::empty(), KeyCode::Left), Left),
-        ((KeyModifiers::empty(), KeyCode::Char('h')), Left),
-        ((KeyModifiers::empty(), KeyCode::Right), Right),
-        ((KeyModifiers::empty(), KeyCode::Char('l')), Right),
-        ((KeyModifiers::empty(), KeyCode::Up), Up),
-        ((KeyModifiers::empty(), KeyCode::Char('k')), Up),
-        ((KeyModifiers::empty(), KeyCode::Down), Down),
-        ((KeyModifiers::empty(), KeyCode::Char('j')), Down),
-        ((KeyModifiers::empty(), KeyCode::PageUp), PageUp),
-        ((KeyModifiers::CONTROL, KeyCode::Char('b')), PageUp),
-        ((KeyModifiers::empty(), KeyCode::PageDown), PageDown),
-        ((KeyModifiers::CONTROL, KeyCode::Char('f')), PageDown),
-        ((KeyModifiers::empty(), KeyCode::Enter), Enter),
-        ((KeyModifiers::empty(), KeyCode::Esc), Exit),
-        ((KeyModifiers::empty(), KeyCode::Char('m')), Mark),
-        ((KeyModifiers::empty(), KeyCode::Char('u')), Unmark),
-        ((KeyModifiers::empty(), KeyCode::Char('c')), UnmarkAll),
-        ((KeyModifiers::empty(), KeyCode::Char('q')), Quit),
-        ((KeyModifiers::empty(), KeyCode::Char('g')), Generate),
-    ];
     match event {
         TermEvent::Resize(w, h) => Some(Resize(Size::new(w, h))),
         TermEvent::Key(event) if event.kind == KeyEventKind::Press => {
-            KEYBINDINGS.iter().find_map(|((mods, code), ui_event)| {
-                if event.modifiers == *mods && event.code == *code {
-                    Some(ui_event.clone())
-                } else {
-                    None
+            keybindings.event(&event)
+        }
+        _ => None,
+    }
+}
+
+/// While typing into a text input all the keys go into it.
+fn convert_input_event(event: crossterm::event::Event) -> Option<Event> {
+    use crossterm::event::{Event as TermEvent, KeyEventKind};
+    use ui::Event::*;
+
+    match event {
+        TermEvent::Resize(w, h) => Some(Resize(Size::new(w, h))),
+        TermEvent::Key(event) if event.kind == KeyEventKind::Press => {
+            match event.code {
+                KeyCode::Enter => Some(Enter),
+                KeyCode::Esc => Some(Exit),
+                KeyCode::Up => Some(Up),
+                KeyCode::Down => Some(Down),
+                KeyCode::Backspace => Some(InputBackspace),
+                KeyCode::Char(c)
+                    if (event.modifiers - KeyModifiers::SHIFT).is_empty() =>
+                {
+                    Some(InputChar(c))
                 }
-            })
+                _ => None,
+            }
         }
         _ => None,
     }
 }
 
+/// Runs the UI, returns what to print when quitting
+/// and what to print after each line.
 fn ui<R: Reporter + ?Sized>(
     reporter: &R,
     mut cache: Cache,
-) -> anyhow::Result<Vec<Utf8PathBuf>> {
+    snapshot_filter: &SnapshotFilter,
+    keybindings: &Keybindings,
+    output_format: OutputFormat,
+    always_script: bool,
+    script_options: &marks::ScriptOptions,
+) -> anyhow::Result<(Vec<String>, char)> {
+    // What to go back to when leaving a single snapshot view
+    let aggregate_selection = cache.selected_snapshots().map(<[_]>::to_vec);
+    // (old, new) snapshots of the diff view
+    let mut diff: Option<(String, String)> = None;
     let entries = cache.get_entries(None)?;
     if entries.is_empty() {
         info_report!(reporter, "The repository is empty!");
-        return Ok(vec![]);
+        return Ok((vec![], '\n'));
     }
 
     stderr().execute(EnterAlternateScreen)?;
@@ -484,26 +1254,19 @@ fn ui<R: Reporter + ?Sized>(
             Utf8PathBuf::new(),
             entries,
             cache.get_marks()?,
-            vec![
-                "Enter".bold(),
-                ":Details  ".into(),
-                "m".bold(),
-                ":Mark  ".into(),
-                "u".bold(),
-                ":Unmark  ".into(),
-                "c".bold(),
-                ":ClearAllMarks  ".into(),
-                "g".bold(),
-                ":Generate  ".into(),
-                "q".bold(),
-                ":Quit".into(),
-            ],
+            cache.get_pattern_marks()?,
+            keybindings.labels(),
         )
     };
 
     render(&mut terminal, &app)?;
     loop {
-        let mut o_event = convert_event(crossterm::event::read()?);
+        let term_event = crossterm::event::read()?;
+        let mut o_event = if app.is_editing() {
+            convert_input_event(term_event)
+        } else {
+            convert_event(keybindings, term_event)
+        };
         while let Some(event) = o_event {
             o_event = match app.update(event) {
                 Action::Nothing => None,
@@ -511,38 +1274,154 @@ fn ui<R: Reporter + ?Sized>(
                     render(&mut terminal, &app)?;
                     None
                 }
-                Action::Quit => return Ok(vec![]),
-                Action::Generate(paths) => return Ok(paths),
+                Action::Quit => return Ok((vec![], '\n')),
+                Action::Generate { paths, patterns, script } => {
+                    // All the (filtered) snapshots, not only the one being browsed
+                    cache.select_snapshots(aggregate_selection.clone())?;
+                    if !(script || always_script) {
+                        let lines = marks::output_lines(
+                            &cache,
+                            output_format,
+                            &paths,
+                            &patterns,
+                        )?;
+                        let separator = if output_format == OutputFormat::Nul {
+                            '\0'
+                        } else {
+                            '\n'
+                        };
+                        return Ok((lines, separator));
+                    }
+                    let format = if script_options.case_insensitive {
+                        OutputFormat::Iexclude
+                    } else {
+                        OutputFormat::Exclude
+                    };
+                    let lines =
+                        marks::output_lines(&cache, format, &paths, &patterns)?;
+                    let snapshots =
+                        marks::snapshots_to_rewrite(&cache, paths, patterns)?;
+                    let script =
+                        marks::rewrite_script(&lines, &snapshots, script_options);
+                    return Ok((script, '\n'));
+                }
                 Action::GetParentEntries(path_id) => {
                     let parent_id = cache.get_parent_id(path_id)?
                         .expect("The UI requested a GetParentEntries with a path_id that does not exist");
-                    let entries = cache.get_entries(parent_id)?;
-                    Some(Event::Entries { path_id: parent_id, entries })
+                    Some(get_entries_event(&cache, &diff, parent_id)?)
                 }
                 Action::GetEntries(path_id) => {
-                    let entries = cache.get_entries(path_id)?;
-                    Some(Event::Entries { path_id, entries })
+                    Some(get_entries_event(&cache, &diff, path_id)?)
                 }
                 Action::GetEntryDetails(path_id) =>
                     Some(Event::EntryDetails(cache.get_entry_details(path_id)?
                         .expect("The UI requested a GetEntryDetails with a path_id that does not exist"))),
                 Action::UpsertMark(path) => {
                     cache.upsert_mark(&path)?;
-                    Some(Event::Marks(cache.get_marks()?))
+                    Some(marks_event(&cache)?)
                 }
                 Action::DeleteMark(loc) => {
                     cache.delete_mark(&loc).unwrap();
-                    Some(Event::Marks(cache.get_marks()?))
+                    Some(marks_event(&cache)?)
                 }
                 Action::DeleteAllMarks => {
                     cache.delete_all_marks()?;
-                    Some(Event::Marks(Vec::new()))
+                    Some(marks_event(&cache)?)
+                }
+                Action::UpsertPatternMark(pattern) => {
+                    cache.upsert_pattern_mark(&pattern)?;
+                    Some(marks_event(&cache)?)
+                }
+                Action::DeletePatternMarks(patterns) => {
+                    for pattern in patterns {
+                        cache.delete_pattern_mark(&pattern)?;
+                    }
+                    Some(marks_event(&cache)?)
+                }
+                Action::GetSnapshots => {
+                    let mut snapshots = cache.get_snapshots()?;
+                    snapshots.retain(|s| snapshot_filter.matches(s));
+                    Some(Event::Snapshots(snapshots))
+                }
+                Action::SelectSnapshot(hash) => {
+                    cache.select_snapshots(match hash {
+                        Some(hash) => Some(vec![hash]),
+                        None => aggregate_selection.clone(),
+                    })?;
+                    diff = None;
+                    Some(get_entries_event(&cache, &diff, app.path_id())?)
+                }
+                Action::SelectDiff { old, new } => {
+                    diff = Some((old, new));
+                    Some(get_entries_event(&cache, &diff, app.path_id())?)
+                }
+                Action::Search(pattern) => {
+                    let hits = cache.search(&pattern, 100)?;
+                    Some(Event::SearchResults { pattern, hits })
+                }
+                Action::GetMarksSummary => {
+                    Some(Event::MarksSummary(cache.get_marks_summary()?))
+                }
+                Action::ImportMarks(file) => {
+                    let lines = match fs::read_to_string(&file) {
+                        Err(e) => vec![format!("Unable to read {file}: {e}")],
+                        Ok(contents) => {
+                            marks::import(&mut cache, &contents)?.lines()
+                        }
+                    };
+                    Some(Event::MarksImported {
+                        lines,
+                        paths: cache.get_marks()?,
+                        patterns: cache.get_pattern_marks()?,
+                    })
+                }
+                Action::GetMarkEntries => {
+                    Some(Event::MarkEntries(cache.get_mark_entries()?))
+                }
+                Action::Jump { path_id, parent_id, path } => {
+                    if diff.take().is_some() {
+                        // The UI goes back to all the snapshots
+                        cache.select_snapshots(aggregate_selection.clone())?;
+                    }
+                    Some(Event::Jump {
+                        path_id: parent_id,
+                        path: path
+                            .parent()
+                            .map(Utf8Path::to_path_buf)
+                            .unwrap_or_default(),
+                        entries: cache.get_entries(parent_id)?,
+                        selected: path_id,
+                    })
                 }
             }
         }
     }
 }
 
+fn marks_event(cache: &Cache) -> anyhow::Result<Event> {
+    Ok(Event::Marks {
+        paths: cache.get_marks()?,
+        patterns: cache.get_pattern_marks()?,
+    })
+}
+
+/// The entries at `path_id` for the UI, diffed if we are in the diff view.
+fn get_entries_event(
+    cache: &Cache,
+    diff: &Option<(String, String)>,
+    path_id: Option<cache::PathId>,
+) -> anyhow::Result<Event> {
+    Ok(match diff {
+        None => {
+            Event::Entries { path_id, entries: cache.get_entries(path_id)? }
+        }
+        Some((old, new)) => Event::DiffEntries {
+            path_id,
+            entries: cache.get_diff(old, new, path_id)?,
+        },
+    })
+}
+
 fn render<'a>(
     terminal: &'a mut Terminal<impl Backend>,
     app: &App,
@@ -567,3 +1446,412 @@ impl<T> FixedSizeQueue<T> {
         self.0.lock().unwrap().pop()
     }
 }
+
+/// Tests //////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use chrono::{TimeZone, Utc};
+    use serde_json::json;
+    use uuid::Uuid;
+
+    use super::*;
+
+    pub(crate) struct TempCache {
+        path: std::path::PathBuf,
+        pub(crate) cache: Cache,
+    }
+
+    impl TempCache {
+        pub(crate) fn new() -> Self {
+            let path = env::temp_dir().join(Uuid::new_v4().to_string());
+            let cache = Migrator::open(&path).unwrap().migrate().unwrap();
+            TempCache { path, cache }
+        }
+    }
+
+    impl Drop for TempCache {
+        fn drop(&mut self) {
+            fs::remove_file(&self.path).unwrap();
+        }
+    }
+
+    /// Panics if a tree that is not in `trees` is fetched.
+    struct FakeTrees {
+        trees: HashMap<String, Tree>,
+        fetched: Mutex<Vec<String>>,
+    }
+
+    impl Trees for FakeTrees {
+        fn trees(&self, ids: &[String]) -> Result<Vec<Tree>, restic::Error> {
+            self.fetched.lock().unwrap().extend(ids.iter().cloned());
+            Ok(ids
+                .iter()
+                .map(|id| {
+                    self.trees
+                        .get(id)
+                        .unwrap_or_else(|| {
+                            panic!("tree {id} should not be fetched")
+                        })
+                        .clone()
+                })
+                .collect())
+        }
+    }
+
+    pub(crate) fn mk_snapshot(id: &str, hour: u32) -> Snapshot {
+        Snapshot {
+            id: id.to_string(),
+            time: Utc.with_ymd_and_hms(2024, 1, 1, hour, 0, 0).unwrap(),
+            parent: None,
+            tree: format!("tree_{id}"),
+            paths: HashSet::from(["/home".to_string()]),
+            hostname: Some("host".to_string()),
+            username: None,
+            uid: None,
+            gid: None,
+            excludes: HashSet::new(),
+            tags: HashSet::new(),
+            original_id: None,
+            program_version: None,
+        }
+    }
+
+    fn mk_tree(nodes: serde_json::Value) -> Tree {
+        serde_json::from_value(json!({ "nodes": nodes })).unwrap()
+    }
+
+    fn mk_file(path: &str, size: usize) -> File {
+        File { path: path.into(), size, metadata: Default::default() }
+    }
+
+    #[test]
+    fn fetch_incremental_copies_unchanged_subtrees() {
+        let mut temp = TempCache::new();
+        let base = mk_snapshot("base", 1);
+        let snapshot = mk_snapshot("new", 2);
+        temp.cache
+            .stage_files(
+                &base.id,
+                None,
+                &[mk_file("/a/x", 2), mk_file("/b/y", 3), mk_file("/f", 1)],
+            )
+            .unwrap();
+        temp.cache.save_staged_snapshot(&base).unwrap();
+
+        // The tree of /a is the same in both snapshots, so it is not in
+        // the map and fetching it panics.
+        let new_tree = mk_tree(json!([
+            { "name": "a", "type": "dir", "subtree": "tree_a" },
+            { "name": "b", "type": "dir", "subtree": "tree_b1" },
+            { "name": "g", "type": "file", "size": 4 },
+        ]));
+        let trees = FakeTrees {
+            trees: HashMap::from([
+                (
+                    base.tree.clone(),
+                    mk_tree(json!([
+                        { "name": "a", "type": "dir", "subtree": "tree_a" },
+                        { "name": "b", "type": "dir", "subtree": "tree_b0" },
+                        { "name": "f", "type": "file", "size": 1 },
+                    ])),
+                ),
+                (snapshot.tree.clone(), new_tree.clone()),
+                ("tree_other".to_string(), new_tree),
+                (
+                    "tree_b0".to_string(),
+                    mk_tree(
+                        json!([{ "name": "y", "type": "file", "size": 3 }]),
+                    ),
+                ),
+                (
+                    "tree_b1".to_string(),
+                    mk_tree(json!([
+                        { "name": "y", "type": "file", "size": 5 },
+                        { "name": "z", "type": "file", "size": 7 },
+                    ])),
+                ),
+            ]),
+            fetched: Mutex::new(Vec::new()),
+        };
+        let base_trees = BaseTrees::default();
+
+        let fetch = |snapshot: &Snapshot| {
+            let (sender, receiver) = mpsc::sync_channel(16);
+            let mut stager = Stager::new(snapshot, Some(&base), None, &sender);
+            let finished = fetch_incremental(
+                &trees,
+                &base_trees,
+                &temp.cache,
+                snapshot,
+                &base,
+                &mut stager,
+                &mut *NullReporter::new().add_counter(0, "", ""),
+                &AtomicBool::new(false),
+            )
+            .unwrap();
+            assert!(finished);
+            stager.finish().unwrap();
+            drop(sender);
+
+            let mut files = Vec::new();
+            for fetched in receiver {
+                match fetched {
+                    Fetched::Files { hash, base, files: chunk } => {
+                        assert_eq!(hash, snapshot.id);
+                        assert_eq!(base.as_deref(), Some("base"));
+                        files.extend(
+                            chunk
+                                .into_iter()
+                                .map(|file| (file.path, file.size)),
+                        );
+                    }
+                    _ => panic!("only files should be sent"),
+                }
+            }
+            files.sort();
+            files
+        };
+        let expected: Vec<(Utf8PathBuf, usize)> = vec![
+            ("/a/x".into(), 2),
+            ("/b/y".into(), 5),
+            ("/b/z".into(), 7),
+            ("/g".into(), 4),
+        ];
+        assert_eq!(fetch(&snapshot), expected);
+        // The trees of the base are only fetched for the first snapshot
+        assert_eq!(fetch(&mk_snapshot("other", 3)), expected);
+        let mut fetched = trees.fetched.into_inner().unwrap();
+        fetched.sort();
+        assert_eq!(
+            fetched,
+            [
+                "tree_b0",
+                "tree_b1",
+                "tree_b1",
+                "tree_base",
+                "tree_new",
+                "tree_other",
+            ]
+        );
+    }
+
+    fn mk_staged(files: usize, last_path: &str) -> StagedSnapshot {
+        StagedSnapshot {
+            hash: "new".into(),
+            base: None,
+            files,
+            last_path: Some(last_path.into()),
+            complete: false,
+        }
+    }
+
+    /// The paths in each message, `Discard` is `None`.
+    fn received_paths(
+        receiver: mpsc::Receiver<Fetched>,
+    ) -> Vec<Option<Vec<String>>> {
+        receiver
+            .into_iter()
+            .filter_map(|fetched| match fetched {
+                Fetched::Files { files, .. } => Some(Some(
+                    files.into_iter().map(|f| f.path.into_string()).collect(),
+                )),
+                Fetched::Discard(_) => Some(None),
+                Fetched::Done { .. } => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn stager_skips_staged_files() {
+        let snapshot = mk_snapshot("new", 1);
+        let (sender, receiver) = mpsc::sync_channel(16);
+        let mut stager =
+            Stager::new(&snapshot, None, Some(mk_staged(2, "/b")), &sender);
+        for path in ["/a", "/b", "/c", "/d"] {
+            stager.add(mk_file(path, 1)).unwrap();
+        }
+        stager.finish().unwrap();
+        drop(sender);
+        assert_eq!(
+            received_paths(receiver),
+            vec![Some(vec!["/c".into(), "/d".into()])]
+        );
+    }
+
+    #[test]
+    fn stager_mismatch() {
+        let snapshot = mk_snapshot("new", 1);
+        let (sender, _receiver) = mpsc::sync_channel(16);
+
+        // The last staged file is not where it should be
+        let mut stager =
+            Stager::new(&snapshot, None, Some(mk_staged(2, "/x")), &sender);
+        stager.add(mk_file("/a", 1)).unwrap();
+        assert!(matches!(
+            stager.add(mk_file("/b", 1)),
+            Err(FetchingThreadError::StagingMismatch)
+        ));
+
+        // There are less files than the staged ones
+        let mut stager =
+            Stager::new(&snapshot, None, Some(mk_staged(3, "/c")), &sender);
+        stager.add(mk_file("/a", 1)).unwrap();
+        stager.add(mk_file("/b", 1)).unwrap();
+        assert!(matches!(
+            stager.finish(),
+            Err(FetchingThreadError::StagingMismatch)
+        ));
+    }
+
+    #[test]
+    fn fetch_staged_starts_over_on_mismatch() {
+        let snapshot = mk_snapshot("new", 1);
+        let (sender, receiver) = mpsc::sync_channel(16);
+        let mut attempts = 0;
+        let finished = fetch_staged(
+            &snapshot,
+            None,
+            Some(mk_staged(2, "/x")),
+            &sender,
+            |stager| {
+                attempts += 1;
+                for path in ["/a", "/b", "/c"] {
+                    stager.add(mk_file(path, 1))?;
+                }
+                Ok(true)
+            },
+        )
+        .unwrap();
+        drop(sender);
+        assert!(finished);
+        assert_eq!(attempts, 2);
+        assert_eq!(
+            received_paths(receiver),
+            vec![None, Some(vec!["/a".into(), "/b".into(), "/c".into()])]
+        );
+    }
+
+    /// (path, unique size), sorted by path.
+    fn unique_size_paths(
+        unique_sizes: UniqueSizes,
+        blob_sizes: &HashMap<String, usize>,
+    ) -> Vec<(String, usize)> {
+        let mut path: Vec<Box<str>> = Vec::new();
+        let mut sizes = Vec::new();
+        for (level, component, size) in unique_sizes.finish(blob_sizes) {
+            path.truncate(level - 1);
+            path.push(component);
+            sizes.push((path.join("/"), size));
+        }
+        sizes.sort();
+        sizes
+    }
+
+    #[test]
+    fn walk_unique_sizes_fetches_each_tree_once() {
+        let tree_a = mk_tree(json!([
+            { "name": "x", "type": "file", "size": 10, "content": ["b1"] },
+        ]));
+        let trees = FakeTrees {
+            trees: HashMap::from([
+                (
+                    "tree_s1".to_string(),
+                    mk_tree(json!([
+                        { "name": "a", "type": "dir", "subtree": "tree_a" },
+                        { "name": "f", "type": "file", "content": ["b0"] },
+                    ])),
+                ),
+                (
+                    "tree_s2".to_string(),
+                    mk_tree(json!([
+                        { "name": "a", "type": "dir", "subtree": "tree_a" },
+                        { "name": "c", "type": "dir", "subtree": "tree_a" },
+                        { "name": "g", "type": "file", "content": ["b2"] },
+                    ])),
+                ),
+                (
+                    "tree_s3".to_string(),
+                    mk_tree(json!([
+                        { "name": "d", "type": "dir", "subtree": "tree_a" },
+                    ])),
+                ),
+                ("tree_a".to_string(), tree_a),
+            ]),
+            fetched: Mutex::new(Vec::new()),
+        };
+        let snapshots =
+            [mk_snapshot("s1", 1), mk_snapshot("s2", 2), mk_snapshot("s3", 3)];
+        let mut done = 0;
+        let unique_sizes =
+            walk_unique_sizes(&trees, &snapshots, || done += 1).unwrap();
+        assert_eq!(done, 3);
+
+        let mut expected = UniqueSizes::new();
+        expected.add_file(["a", "x"], ["b1"]);
+        expected.add_file(["f"], ["b0"]);
+        expected.add_file(["a", "x"], ["b1"]);
+        expected.add_file(["c", "x"], ["b1"]);
+        expected.add_file(["g"], ["b2"]);
+        expected.add_file(["d", "x"], ["b1"]);
+        let blob_sizes = HashMap::from([
+            ("b0".to_string(), 1),
+            ("b1".to_string(), 10),
+            ("b2".to_string(), 100),
+        ]);
+        assert_eq!(
+            unique_size_paths(unique_sizes, &blob_sizes),
+            unique_size_paths(expected, &blob_sizes)
+        );
+
+        // tree_a is fetched again only once for the other places it is in
+        let mut fetched = trees.fetched.into_inner().unwrap();
+        fetched.sort();
+        assert_eq!(
+            fetched,
+            ["tree_a", "tree_a", "tree_s1", "tree_s2", "tree_s3"]
+        );
+    }
+
+    #[test]
+    fn find_base_prefers_parent() {
+        let parent = mk_snapshot("parent", 1);
+        let closer = mk_snapshot("closer", 2);
+        let snapshot =
+            Snapshot { parent: Some("parent".into()), ..mk_snapshot("new", 3) };
+        let cached = [parent, closer];
+        assert_eq!(
+            find_base(&snapshot, &cached).map(|s| s.id.as_str()),
+            Some("parent")
+        );
+    }
+
+    #[test]
+    fn find_base_latest_older_same_host_and_paths() {
+        let other_host =
+            Snapshot { hostname: Some("other".into()), ..mk_snapshot("h", 4) };
+        let other_paths = Snapshot {
+            paths: HashSet::from(["/etc".to_string()]),
+            ..mk_snapshot("p", 4)
+        };
+        let cached = [
+            mk_snapshot("old", 1),
+            mk_snapshot("older", 0),
+            mk_snapshot("closest", 3),
+            mk_snapshot("newer", 6),
+            other_host,
+            other_paths,
+        ];
+        // The parent is not cached
+        let snapshot =
+            Snapshot { parent: Some("gone".into()), ..mk_snapshot("new", 5) };
+        assert_eq!(
+            find_base(&snapshot, &cached).map(|s| s.id.as_str()),
+            Some("closest")
+        );
+
+        let first = mk_snapshot("first", 0);
+        assert!(find_base(&first, &cached).is_none());
+    }
+}
@@ -0,0 +1,918 @@
+use std::{
+    borrow::Cow,
+    collections::{BTreeSet, HashSet},
+};
+
+use anyhow::anyhow;
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::Local;
+use redu::{
+    cache::{Cache, MarkCheck},
+    glob::ExcludePattern,
+    restic::{
+        escape_for_exclude, escape_for_iexclude, parse_exclude_file,
+        unescape_exclude,
+    },
+};
+use serde::Serialize;
+
+use crate::{args::OutputFormat, ui::escape_name, util::snapshot_short_id};
+
+/// What an exclude file line turns into.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ImportedMark {
+    /// An absolute path without wildcards
+    Path(Utf8PathBuf),
+    Pattern(String),
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ImportReport {
+    pub paths: usize,
+    pub patterns: usize,
+    /// `(line number, pattern)` of the lines that match nothing in the cache
+    pub unmatched: Vec<(usize, String)>,
+    /// `(line number, pattern, why)` of the lines that were not imported
+    pub skipped: Vec<(usize, String, String)>,
+}
+
+/// Turns a pattern from an exclude file into a mark.
+pub fn classify(pattern: &str) -> Result<ImportedMark, String> {
+    if pattern.starts_with('!') {
+        return Err("negated patterns are not supported".into());
+    }
+    ExcludePattern::new(pattern).map_err(|e| e.to_string())?;
+    match unescape_exclude(pattern) {
+        // Relative patterns can match anywhere so they stay patterns
+        Some(path) if path.starts_with('/') => {
+            // Normalize like restic does (trailing slashes, . components)
+            Ok(ImportedMark::Path(Utf8Path::new(&path).components().collect()))
+        }
+        _ => Ok(ImportedMark::Pattern(pattern.into())),
+    }
+}
+
+/// Splits paths and patterns given by the user like `classify` does.
+pub fn split_marks(
+    marks: &[String],
+) -> anyhow::Result<(Vec<Utf8PathBuf>, Vec<String>)> {
+    let mut paths = Vec::new();
+    let mut patterns = Vec::new();
+    for mark in marks {
+        match classify(mark).map_err(|why| anyhow!("{mark}: {why}"))? {
+            ImportedMark::Path(path) => paths.push(path),
+            ImportedMark::Pattern(pattern) => patterns.push(pattern),
+        }
+    }
+    Ok((paths, patterns))
+}
+
+/// Imports the lines of a restic exclude file as marks.
+pub fn import(
+    cache: &mut Cache,
+    contents: &str,
+) -> anyhow::Result<ImportReport> {
+    let mut report = ImportReport::default();
+    // (line number, pattern) of the imported lines
+    let mut imported = Vec::new();
+    let getenv = |name: &str| std::env::var(name).ok();
+    for (line_number, pattern) in parse_exclude_file(contents, getenv) {
+        match classify(&pattern) {
+            Err(why) => report.skipped.push((line_number, pattern, why)),
+            Ok(ImportedMark::Path(path)) => {
+                cache.upsert_mark(&path)?;
+                report.paths += 1;
+                imported.push((line_number, pattern));
+            }
+            Ok(ImportedMark::Pattern(p)) => {
+                cache.upsert_pattern_mark(&p)?;
+                report.patterns += 1;
+                imported.push((line_number, pattern));
+            }
+        }
+    }
+
+    let patterns = imported
+        .iter()
+        .map(|(_, pattern)| ExcludePattern::new(pattern))
+        .collect::<Result<Vec<_>, _>>()?;
+    let matches = cache.find_pattern_matches(&patterns)?;
+    report.unmatched = imported
+        .into_iter()
+        .zip(matches)
+        .filter(|(_, matches)| matches.is_empty())
+        .map(|(line, _)| line)
+        .collect();
+    Ok(report)
+}
+
+impl ImportReport {
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = vec![format!(
+            "Imported {} path mark(s) and {} pattern mark(s)",
+            self.paths, self.patterns
+        )];
+        if !self.unmatched.is_empty() {
+            lines.push("".into());
+            lines.push("These lines match nothing in the cache:".into());
+            for (line_number, pattern) in &self.unmatched {
+                lines.push(format!("{line_number:>5}: {pattern}"));
+            }
+        }
+        if !self.skipped.is_empty() {
+            lines.push("".into());
+            lines.push("These lines were skipped:".into());
+            for (line_number, pattern, why) in &self.skipped {
+                lines.push(format!("{line_number:>5}: {pattern} ({why})"));
+            }
+        }
+        lines
+    }
+}
+
+/// For each mark the snapshots that still have something that it matches
+/// (so they need a `restic rewrite`) and the ones that don't.
+pub fn check_lines(checks: &[MarkCheck]) -> Vec<String> {
+    if checks.is_empty() {
+        return vec!["There are no marks".into()];
+    }
+    let human_size = |size| humansize::format_size(size, humansize::BINARY);
+    let snapshot_line = |size: &str, snapshot: &redu::restic::Snapshot| {
+        format!(
+            "    {size:>11}  {}  {}",
+            snapshot.time.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S"),
+            snapshot_short_id(&snapshot.id),
+        )
+    };
+    let mut lines = Vec::new();
+    for check in checks {
+        if !lines.is_empty() {
+            lines.push("".into());
+        }
+        let (symbol, mark) = if check.is_pattern {
+            ("~", Cow::Borrowed(check.mark.as_str()))
+        } else {
+            ("*", escape_name(&check.mark))
+        };
+        lines.push(format!("{symbol} {mark}"));
+        let total = check.present.len() + check.absent.len();
+        if !check.present.is_empty() {
+            lines.push(format!(
+                "  Still in {} of {total} snapshot(s):",
+                check.present.len()
+            ));
+            for (snapshot, size) in &check.present {
+                lines.push(snapshot_line(&human_size(*size), snapshot));
+            }
+        }
+        if !check.absent.is_empty() {
+            lines.push(format!(
+                "  Already absent from {} of {total} snapshot(s):",
+                check.absent.len()
+            ));
+            for (snapshot, excluded_by) in &check.absent {
+                let mut line = snapshot_line("-", snapshot);
+                if let Some(exclude) = excluded_by {
+                    line.push_str(&format!("  (excluded by {exclude})"));
+                }
+                lines.push(line);
+            }
+        }
+    }
+    lines
+}
+
+/// The lines of an exclude file with the marks,
+/// the paths are escaped so that they only match themselves.
+pub fn exclude_lines(
+    paths: &[Utf8PathBuf],
+    patterns: &[String],
+) -> Vec<String> {
+    paths
+        .iter()
+        .map(|path| escape_for_exclude(path.as_str()).into_owned())
+        .chain(patterns.iter().cloned())
+        .collect()
+}
+
+/// The marks in the given format, one item per line
+/// (they are separated with NULs instead of newlines for `Nul`).
+pub fn output_lines(
+    cache: &Cache,
+    format: OutputFormat,
+    paths: &[Utf8PathBuf],
+    patterns: &[String],
+) -> anyhow::Result<Vec<String>> {
+    let lines = match format {
+        OutputFormat::Exclude => exclude_lines(paths, patterns),
+        OutputFormat::Iexclude => paths
+            .iter()
+            .map(|path| escape_for_iexclude(path.as_str()))
+            .chain(patterns.iter().map(|p| iexclude_pattern(p)))
+            .collect(),
+        OutputFormat::Rsync => paths
+            .iter()
+            .map(|path| escape_for_rsync(path.as_str()))
+            .chain(patterns.iter().flat_map(|p| rsync_patterns(p)))
+            .collect(),
+        OutputFormat::Borg => paths
+            .iter()
+            .map(|path| escape_for_borg(path.as_str()))
+            .chain(patterns.iter().map(|p| borg_pattern(p)))
+            .collect(),
+        OutputFormat::Gitignore => paths
+            .iter()
+            .map(|path| escape_for_gitignore(path.as_str()))
+            .chain(patterns.iter().map(|p| gitignore_pattern(p)))
+            .collect(),
+        OutputFormat::Nul => {
+            // The patterns are replaced by the paths that they match
+            let compiled = patterns
+                .iter()
+                .filter_map(|p| ExcludePattern::new(p).ok())
+                .collect::<Vec<_>>();
+            let matches = cache.find_pattern_matches(&compiled)?;
+            paths
+                .iter()
+                .cloned()
+                .chain(matches.into_iter().flatten().map(|(_, path)| path))
+                .map(String::from)
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .collect()
+        }
+        OutputFormat::Json => {
+            let marks = cache
+                .get_marks_summary()?
+                .marks
+                .into_iter()
+                .map(|mark| JsonMark {
+                    exclude: if mark.is_pattern {
+                        mark.mark.clone()
+                    } else {
+                        escape_for_exclude(&mark.mark).into_owned()
+                    },
+                    mark: mark.mark,
+                    is_pattern: mark.is_pattern,
+                    snapshots: mark.snapshots,
+                    max_size: mark.max_size,
+                    total_size: mark.total_size,
+                })
+                .collect::<Vec<_>>();
+            vec![serde_json::to_string_pretty(&marks)?]
+        }
+    };
+    Ok(lines)
+}
+
+#[derive(Serialize)]
+struct JsonMark {
+    mark: String,
+    is_pattern: bool,
+    /// The line for a restic exclude file
+    exclude: String,
+    snapshots: usize,
+    max_size: usize,
+    total_size: usize,
+}
+
+/// An rsync exclude pattern that only matches the path.
+/// rsync only takes backslashes as escapes when there is a wildcard,
+/// newlines (which can't be in a line) are matched with `?`.
+pub fn escape_for_rsync(path: &str) -> String {
+    if !path.contains(['*', '?', '[', '\r', '\n']) {
+        return path.into();
+    }
+    let mut escaped = String::with_capacity(path.len() + 1);
+    for c in path.chars() {
+        match c {
+            '*' | '?' | '[' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\r' | '\n' => escaped.push('?'),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The rsync patterns for a restic pattern.
+/// Like restic, rsync matches the patterns that don't start with a `/` at
+/// any depth, but its `**` is not a whole component: `a/**/b` doesn't
+/// match `a/b` (so it gets a line for that too) and `a/**` doesn't match
+/// `a` (that's `a/***`).
+pub fn rsync_patterns(pattern: &str) -> Vec<String> {
+    let (absolute, parts) = pattern_parts(pattern);
+    let suffix = if pattern.ends_with('/') { "/" } else { "" };
+    let (last, middle) = parts.split_last().expect("parts is never empty");
+    let mut lines = vec![String::new()];
+    for part in middle {
+        lines = if part == "**" {
+            let mut with_stars = lines.clone();
+            for line in &mut with_stars {
+                line.push_str("**/");
+            }
+            lines.into_iter().chain(with_stars).collect()
+        } else {
+            lines.into_iter().map(|line| format!("{line}{part}/")).collect()
+        };
+    }
+    lines
+        .into_iter()
+        .map(|line| {
+            let prefix = if absolute { "/" } else { "" };
+            match last.as_str() {
+                "**" if line.is_empty() => "*".into(),
+                "**" => format!("{prefix}{line}***"),
+                last => format!("{prefix}{line}{last}{suffix}"),
+            }
+        })
+        .collect()
+}
+
+/// The pattern for `--iexclude-file`, which has the same syntax as the
+/// restic excludes. restic lowercases the patterns before matching them.
+pub fn iexclude_pattern(pattern: &str) -> String {
+    pattern.to_lowercase()
+}
+
+/// Whether a restic pattern is absolute and its components,
+/// with the `**` components that don't change what it matches removed:
+/// the repeated ones and the one at the start, as restic matches the
+/// relative patterns at any depth anyway.
+/// A `**` inside a component is only a `*` for restic.
+fn pattern_parts(pattern: &str) -> (bool, Vec<String>) {
+    let mut parts = pattern
+        .split('/')
+        .filter(|part| !part.is_empty() && *part != ".")
+        .map(
+            |part| {
+                if part == "**" {
+                    part.into()
+                } else {
+                    collapse_stars(part)
+                }
+            },
+        )
+        .collect::<Vec<_>>();
+    parts.dedup_by(|a, b| a == "**" && b == "**");
+    let mut absolute = pattern.starts_with('/');
+    if parts.first().is_some_and(|part| part == "**") {
+        absolute = false;
+        if parts.len() > 1 {
+            parts.remove(0);
+        }
+    }
+    if parts.is_empty() {
+        parts.push("**".into());
+    }
+    (absolute, parts)
+}
+
+/// Replaces each run of unescaped `*` with a single one.
+fn collapse_stars(part: &str) -> String {
+    let mut collapsed = String::with_capacity(part.len());
+    let mut chars = part.chars();
+    let mut after_star = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if after_star => continue,
+            '\\' => {
+                collapsed.push(c);
+                collapsed.extend(chars.next());
+            }
+            c => collapsed.push(c),
+        }
+        after_star = c == '*';
+    }
+    collapsed
+}
+
+/// A borg pattern that only matches the path (and what is inside it).
+/// borg strips whitespace from the ends of the lines, so the paths that
+/// end with whitespace or have newlines become an `fm:` pattern.
+pub fn escape_for_borg(path: &str) -> String {
+    if !path.contains(['\r', '\n']) && !path.ends_with(char::is_whitespace) {
+        return format!("pf:{path}");
+    }
+    let mut escaped = String::from("fm:");
+    for c in path.chars() {
+        match c {
+            '*' | '?' | '[' => {
+                escaped.push('[');
+                escaped.push(c);
+                escaped.push(']');
+            }
+            '\r' | '\n' => escaped.push('?'),
+            c if c.is_whitespace() => {
+                escaped.push('[');
+                escaped.push(c);
+                escaped.push(']');
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The borg shell-style pattern for a restic pattern.
+/// The relative restic patterns match anywhere, so they get a `**/` in
+/// front. borg also matches what is inside the paths that a pattern
+/// matches, so a trailing `**` is dropped like for gitignore.
+pub fn borg_pattern(pattern: &str) -> String {
+    let (absolute, mut parts) = pattern_parts(pattern);
+    if parts.len() > 1 && parts.last().is_some_and(|part| part == "**") {
+        parts.pop();
+    }
+    if parts == ["**"] {
+        return "sh:*".into();
+    }
+    let suffix = if pattern.ends_with('/') { "/" } else { "" };
+    let prefix = if absolute { "/" } else { "**/" };
+    let parts = parts
+        .iter()
+        .map(|part| if part == "**" { part.clone() } else { borg_part(part) })
+        .collect::<Vec<_>>();
+    format!("sh:{prefix}{}{suffix}", parts.join("/"))
+}
+
+/// A component of a restic pattern in borg's shell-style syntax.
+/// borg has no escapes, a `\` is just a backslash, so the escaped
+/// characters go in a class. Its classes are negated with `!` instead
+/// of `^` and only take a `]` first.
+fn borg_part(part: &str) -> String {
+    let mut translated = String::with_capacity(part.len());
+    let mut chars = part.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(c @ ('*' | '?' | '[' | '\\')) => {
+                    translated.push('[');
+                    translated.push(c);
+                    translated.push(']');
+                }
+                Some(c) => translated.push(c),
+                None => translated.push_str("[\\]"),
+            },
+            '[' => translated.push_str(&borg_class(&mut chars)),
+            c => translated.push(c),
+        }
+    }
+    translated
+}
+
+/// Translates a restic class, after its `[`, up to and including its `]`.
+fn borg_class(chars: &mut std::str::Chars) -> String {
+    fn unescaped(chars: &mut std::str::Chars) -> Option<char> {
+        match chars.next()? {
+            '\\' => chars.next(),
+            c => Some(c),
+        }
+    }
+
+    let negated = chars.as_str().starts_with('^');
+    if negated {
+        chars.next();
+    }
+    // The characters and the ranges
+    let mut items: Vec<String> = Vec::new();
+    while !chars.as_str().starts_with(']') {
+        let Some(c) = unescaped(chars) else { break };
+        let mut item = c.to_string();
+        if chars.as_str().starts_with('-') && !chars.as_str().starts_with("-]")
+        {
+            chars.next();
+            item.push('-');
+            item.extend(unescaped(chars));
+        }
+        items.push(item);
+    }
+    chars.next();
+    // `]` only works first, and `!` first would negate the class
+    items.sort_by_key(|item| match item.chars().next() {
+        Some(']') => 0,
+        Some('!') => 2,
+        _ => 1,
+    });
+    if !negated && items == ["!"] {
+        return "!".into();
+    }
+    let negation = if negated { "!" } else { "" };
+    format!("[{negation}{}]", items.concat())
+}
+
+/// A gitignore pattern that only matches the path.
+/// Newlines (which can't be in a line) are matched with `?`.
+pub fn escape_for_gitignore(path: &str) -> String {
+    let mut escaped = String::with_capacity(path.len() + 1);
+    if path.starts_with(['#', '!']) {
+        escaped.push('\\');
+    }
+    let trimmed = path.trim_end_matches(' ');
+    for c in trimmed.chars() {
+        match c {
+            '*' | '?' | '[' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\r' | '\n' => escaped.push('?'),
+            c => escaped.push(c),
+        }
+    }
+    // git drops the trailing spaces that aren't escaped
+    for _ in trimmed.len()..path.len() {
+        escaped.push_str("\\ ");
+    }
+    escaped
+}
+
+/// The gitignore pattern for a restic pattern.
+/// Relative restic patterns match anywhere but gitignore anchors
+/// the ones with a slash in the middle, so they get a `**/` in front.
+/// A trailing `**` also matches the directory itself for restic,
+/// for git ignoring the directory is the same.
+pub fn gitignore_pattern(pattern: &str) -> String {
+    let (absolute, mut parts) = pattern_parts(pattern);
+    if parts.len() > 1 && parts.last().is_some_and(|part| part == "**") {
+        parts.pop();
+    }
+    let suffix = if pattern.ends_with('/') { "/" } else { "" };
+    let prefix = if absolute {
+        "/"
+    } else if parts.len() > 1 {
+        "**/"
+    } else {
+        ""
+    };
+    format!("{prefix}{}{suffix}", parts.join("/"))
+}
+
+/// What goes into the rewrite script besides the rewrite itself.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ScriptOptions {
+    /// Where the script writes the exclude file
+    pub exclude_file: Utf8PathBuf,
+    /// Use `--iexclude-file`, the exclude lines have to be
+    /// `OutputFormat::Iexclude` ones
+    pub case_insensitive: bool,
+    /// Pass `--forget` to `restic rewrite`
+    pub forget: bool,
+    /// Run `restic prune` at the end
+    pub prune: bool,
+}
+
+/// The ids of the (selected) snapshots that have something that
+/// any of the marks matches, latest snapshot first.
+pub fn snapshots_to_rewrite(
+    cache: &Cache,
+    paths: Vec<Utf8PathBuf>,
+    patterns: Vec<String>,
+) -> anyhow::Result<Vec<String>> {
+    let present = cache
+        .check_marks(paths, patterns)?
+        .into_iter()
+        .flat_map(|check| check.present)
+        .map(|(snapshot, _)| snapshot.id)
+        .collect::<HashSet<_>>();
+    let mut snapshots = cache
+        .get_snapshots()?
+        .into_iter()
+        .filter(|snapshot| present.contains(&snapshot.id))
+        .collect::<Vec<_>>();
+    snapshots.sort_unstable_by_key(|snapshot| std::cmp::Reverse(snapshot.time));
+    Ok(snapshots.into_iter().map(|snapshot| snapshot.id).collect())
+}
+
+/// A shell script that writes the exclude file and rewrites
+/// the snapshots with it.
+/// redu never runs it, it's up to the user to review and run it.
+pub fn rewrite_script(
+    exclude_lines: &[String],
+    snapshots: &[String],
+    options: &ScriptOptions,
+) -> Vec<String> {
+    let exclude_file = shell_quote(options.exclude_file.as_str());
+    let mut lines = vec![
+        "#!/bin/sh".into(),
+        "# Generated by redu, review it before running it.".into(),
+        "# restic takes the repository and the password from the environment"
+            .into(),
+        "# (RESTIC_REPOSITORY, RESTIC_PASSWORD_COMMAND, ...) as usual.".into(),
+        "set -eu".into(),
+        "".into(),
+    ];
+    if exclude_lines.is_empty() {
+        lines.push(format!(": > {exclude_file}"));
+    } else {
+        lines.push("{".into());
+        for line in exclude_lines {
+            lines.push(format!(
+                "    printf {}",
+                shell_quote(&printf_format(line))
+            ));
+        }
+        lines.push(format!("}} > {exclude_file}"));
+    }
+    lines.push("".into());
+    if snapshots.is_empty() {
+        lines.push(
+            "# None of the snapshots have anything that the marks match".into(),
+        );
+        return lines;
+    }
+    lines.push(format!(
+        "# {} snapshot(s) have something that the marks match",
+        snapshots.len()
+    ));
+    let forget = if options.forget { " --forget" } else { "" };
+    let exclude_option =
+        if options.case_insensitive { "iexclude-file" } else { "exclude-file" };
+    lines.push(format!(
+        "restic rewrite{forget} --{exclude_option}={exclude_file} \\"
+    ));
+    for (i, snapshot) in snapshots.iter().enumerate() {
+        let end = if i + 1 < snapshots.len() { " \\" } else { "" };
+        lines.push(format!("    {snapshot}{end}"));
+    }
+    if options.prune {
+        lines.push("restic prune".into());
+    } else if options.forget {
+        lines.push("# restic prune".into());
+    } else {
+        lines.push("# restic forget <the ids above>".into());
+        lines.push("# restic prune".into());
+    }
+    lines
+}
+
+/// Quotes a string for a POSIX shell.
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// A printf format that prints the line (and a newline) exactly,
+/// with the control characters (like the NULs that `escape_for_exclude`
+/// can produce) as octal escapes.
+fn printf_format(line: &str) -> String {
+    let mut format = String::with_capacity(line.len() + 2);
+    for c in line.chars() {
+        match c {
+            '%' => format.push_str("%%"),
+            '\\' => format.push_str("\\\\"),
+            c if c.is_ascii_control() => {
+                format.push_str(&format!("\\{:03o}", c as u32))
+            }
+            c => format.push(c),
+        }
+    }
+    format.push_str("\\n");
+    format
+}
+
+/// Tests //////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_test() {
+        let path = |p: &str| Ok(ImportedMark::Path(p.into()));
+        let pattern = |p: &str| Ok(ImportedMark::Pattern(p.into()));
+        assert_eq!(classify("/home/user/big.iso"), path("/home/user/big.iso"));
+        assert_eq!(classify("/home/user/.cache/"), path("/home/user/.cache"));
+        assert_eq!(classify("/home/a[*]b"), path("/home/a*b"));
+        assert_eq!(classify("/home/*/.cache"), pattern("/home/*/.cache"));
+        assert_eq!(classify("node_modules"), pattern("node_modules"));
+        assert_eq!(classify("**/target"), pattern("**/target"));
+        assert!(classify("!/home/user/keep").is_err());
+        assert!(classify("/home/[a").is_err());
+    }
+
+    #[test]
+    fn exclude_lines_test() {
+        // Importing the generated lines gives back the same marks
+        let paths = vec![Utf8PathBuf::from("/a*b"), "/c".into()];
+        let patterns = vec!["**/node_modules".to_string()];
+        let marks = exclude_lines(&paths, &patterns)
+            .iter()
+            .map(|line| classify(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            marks,
+            vec![
+                ImportedMark::Path("/a*b".into()),
+                ImportedMark::Path("/c".into()),
+                ImportedMark::Pattern("**/node_modules".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn check_lines_test() {
+        let snapshot = |id: &str| redu::restic::Snapshot {
+            id: id.into(),
+            time: chrono::Utc::now(),
+            parent: None,
+            tree: "tree".into(),
+            paths: Default::default(),
+            hostname: None,
+            username: None,
+            uid: None,
+            gid: None,
+            excludes: Default::default(),
+            tags: Default::default(),
+            original_id: None,
+            program_version: None,
+        };
+        let checks = [
+            MarkCheck {
+                mark: "/a\tb".into(),
+                is_pattern: false,
+                present: vec![(snapshot("0123456789"), 2048)],
+                absent: vec![(snapshot("abcdef0123"), Some("/a*".into()))],
+            },
+            MarkCheck {
+                mark: "**/x".into(),
+                is_pattern: true,
+                present: vec![],
+                absent: vec![(snapshot("abcdef0123"), None)],
+            },
+        ];
+        let lines = check_lines(&checks);
+        assert_eq!(lines.len(), 9);
+        assert_eq!(lines[0], "* /a\\tb");
+        assert_eq!(lines[1], "  Still in 1 of 2 snapshot(s):");
+        assert!(lines[2].starts_with("          2 KiB  "));
+        assert!(lines[2].ends_with("  0123456"));
+        assert_eq!(lines[3], "  Already absent from 1 of 2 snapshot(s):");
+        assert!(lines[4].starts_with("              -  "));
+        assert!(lines[4].ends_with("  abcdef0  (excluded by /a*)"));
+        assert_eq!(&lines[5..7], ["", "~ **/x"]);
+        assert_eq!(lines[7], "  Already absent from 1 of 1 snapshot(s):");
+        assert!(lines[8].ends_with("  abcdef0"));
+        assert_eq!(check_lines(&[]), vec!["There are no marks"]);
+    }
+
+    #[test]
+    fn escape_for_rsync_test() {
+        assert_eq!(escape_for_rsync("/home/a\\b c"), "/home/a\\b c");
+        assert_eq!(
+            escape_for_rsync("foo* bar?[somethin\\g]]]\r\n"),
+            "foo\\* bar\\?\\[somethin\\\\g]]]??"
+        );
+    }
+
+    #[test]
+    fn escape_for_borg_test() {
+        assert_eq!(escape_for_borg("/home/a*b\\c"), "pf:/home/a*b\\c");
+        assert_eq!(
+            escape_for_borg("/foo* bar?[x]\n "),
+            "fm:/foo[*][ ]bar[?][[]x]?[ ]"
+        );
+        assert_eq!(borg_pattern("/home/*/.cache"), "sh:/home/*/.cache");
+        assert_eq!(borg_pattern("node_modules"), "sh:**/node_modules");
+        // borg negates classes with `!` and has no escapes
+        assert_eq!(borg_pattern("[^a-c]*"), "sh:**/[!a-c]*");
+        assert_eq!(borg_pattern("/a\\*b\\?\\[\\\\c"), "sh:/a[*]b[?][[][\\]c");
+        assert_eq!(borg_pattern("x\\y"), "sh:**/xy");
+        assert_eq!(borg_pattern("[x\\]]"), "sh:**/[]x]");
+        assert_eq!(borg_pattern("[^\\]]"), "sh:**/[!]]");
+        assert_eq!(borg_pattern("[!a]"), "sh:**/[a!]");
+        assert_eq!(borg_pattern("[!]"), "sh:**/!");
+        assert_eq!(borg_pattern("[\\^a]"), "sh:**/[^a]");
+    }
+
+    #[test]
+    fn escape_for_gitignore_test() {
+        assert_eq!(escape_for_gitignore("/home/user"), "/home/user");
+        assert_eq!(
+            escape_for_gitignore("foo* bar?[somethin\\g]]]\r\n  "),
+            "foo\\* bar\\?\\[somethin\\\\g]]]??\\ \\ "
+        );
+        assert_eq!(escape_for_gitignore("#a"), "\\#a");
+        assert_eq!(escape_for_gitignore("!a"), "\\!a");
+        assert_eq!(gitignore_pattern("node_modules"), "node_modules");
+        assert_eq!(gitignore_pattern("target/"), "target/");
+        assert_eq!(gitignore_pattern("a/b"), "**/a/b");
+        assert_eq!(gitignore_pattern("**/a/b"), "**/a/b");
+        assert_eq!(gitignore_pattern("/home/*/.cache"), "/home/*/.cache");
+    }
+
+    #[test]
+    fn output_patterns_test() {
+        // Not anchored: restic matches them at any depth
+        assert_eq!(iexclude_pattern("Node_Modules"), "node_modules");
+        assert_eq!(iexclude_pattern("a/**/B"), "a/**/b");
+
+        assert_eq!(rsync_patterns("node_modules"), vec!["node_modules"]);
+        assert_eq!(rsync_patterns("**/node_modules/"), vec!["node_modules/"]);
+        assert_eq!(rsync_patterns("a/**/b"), vec!["a/b", "a/**/b"]);
+        assert_eq!(rsync_patterns("a/**"), vec!["a/***"]);
+        assert_eq!(rsync_patterns("a**b"), vec!["a*b"]);
+        assert_eq!(rsync_patterns("a\\**"), vec!["a\\**"]);
+        assert_eq!(rsync_patterns("/**/x"), vec!["x"]);
+        assert_eq!(
+            rsync_patterns("/home/**/*.iso"),
+            vec!["/home/*.iso", "/home/**/*.iso"]
+        );
+        assert_eq!(rsync_patterns("**"), vec!["*"]);
+
+        assert_eq!(gitignore_pattern("*.iso"), "*.iso");
+        assert_eq!(gitignore_pattern("a/**/b"), "**/a/**/b");
+        assert_eq!(gitignore_pattern("x/a/**"), "**/x/a");
+        assert_eq!(gitignore_pattern("a/**"), "a");
+        assert_eq!(gitignore_pattern("a**b/c"), "**/a*b/c");
+        assert_eq!(gitignore_pattern("/**/x"), "x");
+
+        assert_eq!(borg_pattern("*.iso"), "sh:**/*.iso");
+        assert_eq!(borg_pattern("**/node_modules/"), "sh:**/node_modules/");
+        assert_eq!(borg_pattern("/home/**/x"), "sh:/home/**/x");
+        assert_eq!(borg_pattern("a/**"), "sh:**/a");
+        assert_eq!(borg_pattern("a**b"), "sh:**/a*b");
+        assert_eq!(borg_pattern("/**/x"), "sh:**/x");
+        assert_eq!(borg_pattern("**"), "sh:*");
+    }
+
+    #[test]
+    fn printf_format_test() {
+        assert_eq!(printf_format("/a/b"), "/a/b\\n");
+        assert_eq!(printf_format("/100%"), "/100%%\\n");
+        assert_eq!(printf_format("/a\\*"), "/a\\\\*\\n");
+        assert_eq!(printf_format("[^\0-a]"), "[^\\000-a]\\n");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+    }
+
+    #[test]
+    fn rewrite_script_test() {
+        let options = ScriptOptions {
+            exclude_file: "ex.txt".into(),
+            case_insensitive: false,
+            forget: false,
+            prune: false,
+        };
+        let excludes = vec!["/a".to_string(), "**/x".into()];
+        let snapshots = vec!["0123".to_string(), "4567".into()];
+        assert_eq!(
+            &rewrite_script(&excludes, &snapshots, &options)[6..],
+            [
+                "{",
+                "    printf '/a\\n'",
+                "    printf '**/x\\n'",
+                "} > 'ex.txt'",
+                "",
+                "# 2 snapshot(s) have something that the marks match",
+                "restic rewrite --exclude-file='ex.txt' \\",
+                "    0123 \\",
+                "    4567",
+                "# restic forget <the ids above>",
+                "# restic prune",
+            ]
+        );
+        let options = ScriptOptions {
+            case_insensitive: true,
+            forget: true,
+            prune: true,
+            ..options
+        };
+        assert_eq!(
+            &rewrite_script(&excludes, &snapshots[..1], &options)[11..],
+            [
+                "# 1 snapshot(s) have something that the marks match",
+                "restic rewrite --forget --iexclude-file='ex.txt' \\",
+                "    0123",
+                "restic prune",
+            ]
+        );
+        assert_eq!(
+            &rewrite_script(&[], &[], &options)[6..],
+            [
+                ": > 'ex.txt'",
+                "",
+                "# None of the snapshots have anything that the marks match",
+            ]
+        );
+    }
+
+    #[test]
+    fn import_report_lines_test() {
+        let report = ImportReport {
+            paths: 2,
+            patterns: 1,
+            unmatched: vec![(3, "/gone".into())],
+            skipped: vec![(10, "!keep".into(), "not supported".into())],
+        };
+        assert_eq!(
+            report.lines(),
+            [
+                "Imported 2 path mark(s) and 1 pattern mark(s)",
+                "",
+                "These lines match nothing in the cache:",
+                "    3: /gone",
+                "",
+                "These lines were skipped:",
+                "   10: !keep (not supported)",
+            ]
+        );
+    }
+}
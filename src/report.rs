@@ -0,0 +1,344 @@
+use std::{
+    cmp::{min, Reverse},
+    io::Write,
+};
+
+use anyhow::Context;
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::{DateTime, Utc};
+use redu::{
+    cache::{Cache, PathId},
+    restic::FileMetadata,
+};
+use serde::Serialize;
+
+use crate::{args::ReportFormat, ui::escape_name, util::snapshot_short_id};
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct ReportEntry {
+    pub path: Utf8PathBuf,
+    pub is_dir: bool,
+    pub max_size: usize,
+    pub max_size_snapshot: String,
+    pub first_seen: DateTime<Utc>,
+    pub first_seen_snapshot: String,
+    pub last_seen: DateTime<Utc>,
+    pub last_seen_snapshot: String,
+    pub unique_size: Option<usize>,
+    /// From the last snapshot that has it, empty for the directories
+    #[serde(flatten)]
+    pub metadata: FileMetadata,
+}
+
+/// Returns the `top` largest entries at most `depth` levels below `path`,
+/// largest first.
+/// With `modified_before` it returns the largest files last modified before
+/// then at any depth, the cache looks for them without walking the levels.
+pub fn collect(
+    cache: &Cache,
+    path: Option<&Utf8Path>,
+    depth: u32,
+    top: usize,
+    modified_before: Option<DateTime<Utc>>,
+) -> anyhow::Result<Vec<ReportEntry>> {
+    let path_id = match path {
+        None => None,
+        Some(path) => Some(
+            cache
+                .get_path_id_by_path(path)?
+                .with_context(|| format!("{path} is not in any snapshot"))?,
+        ),
+    };
+    let path = path.map(Utf8Path::to_path_buf).unwrap_or_default();
+
+    // (path, path_id, is_dir), largest first
+    let found: Vec<(Utf8PathBuf, PathId, bool)> = match modified_before {
+        Some(time) => cache
+            .get_files_modified_before(path_id, time, top)?
+            .into_iter()
+            .map(|(file_path, path_id, _)| {
+                (path.join(file_path), path_id, false)
+            })
+            .collect(),
+        None => {
+            let mut found = Vec::new();
+            // (path, path_id, level)
+            let mut stack: Vec<(Utf8PathBuf, Option<PathId>, u32)> =
+                vec![(path, path_id, 0)];
+            while let Some((path, path_id, level)) = stack.pop() {
+                for entry in cache.get_entries(path_id)? {
+                    let entry_path = path.join(&entry.component);
+                    if entry.is_dir && level + 1 < depth {
+                        stack.push((
+                            entry_path.clone(),
+                            Some(entry.path_id),
+                            level + 1,
+                        ));
+                    }
+                    found.push((entry_path, entry));
+                }
+            }
+            found.sort_by_key(|(_, entry)| Reverse(entry.size));
+            found
+                .into_iter()
+                .map(|(path, entry)| (path, entry.path_id, entry.is_dir))
+                .collect()
+        }
+    };
+
+    // The details are only fetched until we have enough entries
+    let mut report = Vec::with_capacity(min(found.len(), top));
+    for (path, path_id, is_dir) in found {
+        if report.len() >= top {
+            break;
+        }
+        let details = cache.get_entry_details(path_id)?.with_context(|| {
+            format!("missing details for {path} in the cache")
+        })?;
+        report.push(ReportEntry {
+            path,
+            is_dir,
+            max_size: details.max_size,
+            max_size_snapshot: details.max_size_snapshot_hash,
+            first_seen: details.first_seen,
+            first_seen_snapshot: details.first_seen_snapshot_hash,
+            last_seen: details.last_seen,
+            last_seen_snapshot: details.last_seen_snapshot_hash,
+            unique_size: details.unique_size,
+            metadata: details.metadata,
+        });
+    }
+    Ok(report)
+}
+
+pub fn write(
+    out: &mut impl Write,
+    format: ReportFormat,
+    report: &[ReportEntry],
+) -> anyhow::Result<()> {
+    match format {
+        ReportFormat::Table => write_table(out, report)?,
+        ReportFormat::Tsv => write_tsv(out, report)?,
+        ReportFormat::Json => {
+            serde_json::to_writer_pretty(&mut *out, report)?;
+            writeln!(out)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_table(
+    out: &mut impl Write,
+    report: &[ReportEntry],
+) -> std::io::Result<()> {
+    let show_unique_size = report.iter().any(|e| e.unique_size.is_some());
+    let header_unique_size =
+        if show_unique_size { format!(" {:>11}", "UNIQUE") } else { "".into() };
+    let show_modified = report.iter().any(|e| e.metadata.mtime.is_some());
+    let header_modified =
+        if show_modified { format!("  {:<10}", "MODIFIED") } else { "".into() };
+    writeln!(
+        out,
+        "{:>11}{header_unique_size}  {:<7}  {:<20}  {:<20}{header_modified}  PATH",
+        "SIZE", "MAX IN", "FIRST SEEN", "LAST SEEN",
+    )?;
+    for entry in report {
+        let unique_size = match (show_unique_size, entry.unique_size) {
+            (false, _) => "".into(),
+            (true, None) => format!(" {:>11}", "-"),
+            (true, Some(size)) => format!(" {:>11}", human_size(size)),
+        };
+        let modified = match (show_modified, entry.metadata.mtime) {
+            (false, _) => "".into(),
+            (true, None) => format!("  {:<10}", "-"),
+            (true, Some(mtime)) => format!("  {:<10}", mtime.date_naive()),
+        };
+        let mut path = escape_name(entry.path.as_str()).into_owned();
+        if entry.is_dir && !path.ends_with('/') {
+            path.push('/');
+        }
+        writeln!(
+            out,
+            "{:>11}{unique_size}  {:<7}  {:<20}  {:<20}{modified}  {path}",
+            human_size(entry.max_size),
+            snapshot_short_id(&entry.max_size_snapshot),
+            format!(
+                "{} ({})",
+                entry.first_seen.date_naive(),
+                snapshot_short_id(&entry.first_seen_snapshot)
+            ),
+            format!(
+                "{} ({})",
+                entry.last_seen.date_naive(),
+                snapshot_short_id(&entry.last_seen_snapshot)
+            ),
+        )?;
+    }
+    Ok(())
+}
+
+fn write_tsv(
+    out: &mut impl Write,
+    report: &[ReportEntry],
+) -> std::io::Result<()> {
+    writeln!(
+        out,
+        "path\tis_dir\tmax_size\tmax_size_snapshot\t\
+         first_seen\tfirst_seen_snapshot\tlast_seen\tlast_seen_snapshot\t\
+         unique_size\tfile_type\tmtime\tmode\tuid\tgid\tinode\tlinks"
+    )?;
+    fn opt<T: ToString>(value: Option<T>) -> String {
+        value.map(|v| v.to_string()).unwrap_or_default()
+    }
+    for entry in report {
+        let metadata = &entry.metadata;
+        writeln!(
+            out,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            escape_name(entry.path.as_str()),
+            entry.is_dir,
+            entry.max_size,
+            entry.max_size_snapshot,
+            entry.first_seen.to_rfc3339(),
+            entry.first_seen_snapshot,
+            entry.last_seen.to_rfc3339(),
+            entry.last_seen_snapshot,
+            opt(entry.unique_size),
+            opt(metadata.file_type.map(|t| t.as_str())),
+            opt(metadata.mtime.map(|t| t.to_rfc3339())),
+            opt(metadata.mode),
+            opt(metadata.uid),
+            opt(metadata.gid),
+            opt(metadata.inode),
+            opt(metadata.links),
+        )?;
+    }
+    Ok(())
+}
+
+fn human_size(size: usize) -> String {
+    humansize::format_size(size, humansize::BINARY)
+}
+
+/// Tests //////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use redu::restic::{File, FileType};
+
+    use super::*;
+    use crate::tests::{mk_snapshot, TempCache};
+
+    #[test]
+    fn collect_modified_before_ignores_depth() {
+        let modified = |path: &str, size, year| File {
+            path: path.into(),
+            size,
+            metadata: FileMetadata {
+                mtime: Some(Utc.with_ymd_and_hms(year, 1, 1, 0, 0, 0).unwrap()),
+                ..FileMetadata::default()
+            },
+        };
+        let mut temp = TempCache::new();
+        temp.cache
+            .stage_files(
+                "foo",
+                None,
+                &[
+                    modified("/a/b/c/d/old", 3, 2020),
+                    modified("/a/new", 5, 2024),
+                    modified("/a/small", 1, 2020),
+                ],
+            )
+            .unwrap();
+        temp.cache.save_staged_snapshot(&mk_snapshot("foo", 1)).unwrap();
+
+        let time = Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, 0).unwrap();
+        let paths = |top| {
+            collect(&temp.cache, Some("/a".into()), 1, top, Some(time))
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.path)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(paths(10), vec!["/a/b/c/d/old", "/a/small"]);
+        assert_eq!(paths(1), vec!["/a/b/c/d/old"]);
+    }
+
+    fn example_report() -> Vec<ReportEntry> {
+        let mk_entry =
+            |path: &str, is_dir, max_size, unique_size, metadata| ReportEntry {
+                path: path.into(),
+                is_dir,
+                max_size,
+                max_size_snapshot: "aaaaaaaaaa".into(),
+                first_seen: Utc.with_ymd_and_hms(2023, 1, 2, 3, 4, 5).unwrap(),
+                first_seen_snapshot: "bbbbbbbbbb".into(),
+                last_seen: Utc.with_ymd_and_hms(2024, 6, 7, 8, 9, 10).unwrap(),
+                last_seen_snapshot: "cccccccccc".into(),
+                unique_size,
+                metadata,
+            };
+        let file_metadata = FileMetadata {
+            file_type: Some(FileType::File),
+            mtime: Some(Utc.with_ymd_and_hms(2020, 3, 4, 5, 6, 7).unwrap()),
+            mode: Some(0o644),
+            uid: Some(1000),
+            gid: Some(100),
+            inode: Some(42),
+            links: Some(1),
+        };
+        vec![
+            mk_entry(
+                "/home/user",
+                true,
+                3 * 1024 * 1024,
+                Some(1024),
+                FileMetadata::default(),
+            ),
+            mk_entry("/home/user/a\tb", false, 1000, None, file_metadata),
+        ]
+    }
+
+    fn write_to_string(format: ReportFormat) -> String {
+        let mut out = Vec::new();
+        write(&mut out, format, &example_report()).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn write_table_test() {
+        assert_eq!(
+            write_to_string(ReportFormat::Table),
+            "       SIZE      UNIQUE  MAX IN   FIRST SEEN            LAST SEEN             MODIFIED    PATH\n\
+             \x20     3 MiB       1 KiB  aaaaaaa  2023-01-02 (bbbbbbb)  2024-06-07 (ccccccc)  -           /home/user/\n\
+             \x20    1000 B           -  aaaaaaa  2023-01-02 (bbbbbbb)  2024-06-07 (ccccccc)  2020-03-04  /home/user/a\\tb\n"
+        );
+    }
+
+    #[test]
+    fn write_tsv_test() {
+        assert_eq!(
+            write_to_string(ReportFormat::Tsv),
+            "path\tis_dir\tmax_size\tmax_size_snapshot\tfirst_seen\tfirst_seen_snapshot\tlast_seen\tlast_seen_snapshot\tunique_size\tfile_type\tmtime\tmode\tuid\tgid\tinode\tlinks\n\
+             /home/user\ttrue\t3145728\taaaaaaaaaa\t2023-01-02T03:04:05+00:00\tbbbbbbbbbb\t2024-06-07T08:09:10+00:00\tcccccccccc\t1024\t\t\t\t\t\t\t\n\
+             /home/user/a\\tb\tfalse\t1000\taaaaaaaaaa\t2023-01-02T03:04:05+00:00\tbbbbbbbbbb\t2024-06-07T08:09:10+00:00\tcccccccccc\t\tfile\t2020-03-04T05:06:07+00:00\t420\t1000\t100\t42\t1\n"
+        );
+    }
+
+    #[test]
+    fn write_json_test() {
+        let json: serde_json::Value =
+            serde_json::from_str(&write_to_string(ReportFormat::Json)).unwrap();
+        assert_eq!(json[0]["path"], "/home/user");
+        assert_eq!(json[0]["max_size"], 3145728);
+        assert_eq!(json[0]["unique_size"], 1024);
+        assert_eq!(json[1]["path"], "/home/user/a\tb");
+        assert_eq!(json[1]["unique_size"], serde_json::Value::Null);
+        assert_eq!(json[1]["first_seen"], "2023-01-02T03:04:05Z");
+        assert_eq!(json[0]["file_type"], serde_json::Value::Null);
+        assert_eq!(json[1]["file_type"], "file");
+        assert_eq!(json[1]["mtime"], "2020-03-04T05:06:07Z");
+        assert_eq!(json[1]["uid"], 1000);
+    }
+}
@@ -3,7 +3,7 @@ use core::str;
 use std::os::unix::process::CommandExt;
 use std::{
     borrow::Cow,
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     ffi::OsStr,
     fmt::{self, Display, Formatter},
     io::{self, BufRead, BufReader, Lines, Read, Write},
@@ -15,9 +15,9 @@ use std::{
 
 use camino::Utf8PathBuf;
 use chrono::{DateTime, Utc};
-use log::info;
+use log::{info, warn};
 use scopeguard::defer;
-use serde::{de::DeserializeOwned, Deserialize};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
 use serde_json::Value;
 use thiserror::Error;
 
@@ -96,7 +96,7 @@ pub struct Restic {
     no_cache: bool,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Eq, PartialEq)]
 pub enum Repository {
     /// A repository string (restic: --repo)
     Repo(String),
@@ -104,7 +104,7 @@ pub enum Repository {
     File(String),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Eq, PartialEq)]
 pub enum Password {
     /// A plain string (restic: RESTIC_PASSWORD env variable)
     Plain(String),
@@ -131,22 +131,77 @@ impl Restic {
         self.run_greedy_command(["snapshots"])
     }
 
+    /// Fetches tree objects from the repository, one restic process each.
+    /// The processes are all started before waiting for any of them,
+    /// so they load the repository index at the same time.
+    pub fn trees(&self, ids: &[String]) -> Result<Vec<Tree>, Error> {
+        let children = ids
+            .iter()
+            .map(|id| self.run_command(["cat", "blob", id]))
+            .collect::<Result<Vec<_>, _>>()?;
+        // Wait for all of them even if one fails
+        let trees = children
+            .into_iter()
+            .map(|child| {
+                wait_greedy_command(child, |s| {
+                    serde_json::from_str(s).map_err(|e| e.into())
+                })
+            })
+            .collect::<Vec<_>>();
+        trees.into_iter().collect()
+    }
+
+    /// Returns the size in the repository of every data blob.
+    /// This is the size after compression and encryption,
+    /// which is what would be freed by removing the blob.
+    pub fn blob_sizes(&self) -> Result<HashMap<String, usize>, Error> {
+        #[derive(Deserialize)]
+        struct Index {
+            #[serde(default, deserialize_with = "null_as_default")]
+            packs: Vec<Pack>,
+        }
+        #[derive(Deserialize)]
+        struct Pack {
+            #[serde(default, deserialize_with = "null_as_default")]
+            blobs: Vec<Blob>,
+        }
+        #[derive(Deserialize)]
+        struct Blob {
+            id: String,
+            #[serde(rename = "type")]
+            blob_type: String,
+            length: usize,
+        }
+
+        // restic list only outputs one id per line even with --json
+        let index_ids: Vec<String> =
+            self.run_greedy_command_with(["list", "index"], |s| {
+                Ok(s.lines()
+                    .map(|line| line.trim().trim_matches('"').to_string())
+                    .filter(|id| !id.is_empty())
+                    .collect())
+            })?;
+        let mut sizes = HashMap::new();
+        for index_id in index_ids {
+            let index: Index =
+                self.run_greedy_command(["cat", "index", &index_id])?;
+            for blob in index.packs.into_iter().flat_map(|p| p.blobs) {
+                if blob.blob_type == "data" {
+                    sizes.insert(blob.id, blob.length);
+                }
+            }
+        }
+        Ok(sizes)
+    }
+
     pub fn ls(
         &self,
         snapshot: &str,
     ) -> Result<impl Iterator<Item = Result<File, Error>> + 'static, LaunchError>
     {
-        fn parse_file(mut v: Value) -> Option<File> {
-            let mut m = mem::take(v.as_object_mut()?);
-            Some(File {
-                path: Utf8PathBuf::from(m.remove("path")?.as_str()?),
-                size: m.remove("size")?.as_u64()? as usize,
-            })
-        }
-
         Ok(self
             .run_lazy_command(["ls", snapshot])?
-            .filter_map(|r| r.map(parse_file).transpose()))
+            .filter_map(|r| r.map(parse_ls_file).transpose()))
     }
 
     // This is a trait object because of
@@ -170,31 +225,24 @@ impl Restic {
     where
         T: DeserializeOwned,
         A: AsRef<OsStr>,
+    {
+        self.run_greedy_command_with(args, |s| {
+            serde_json::from_str(s).map_err(|e| e.into())
+        })
+    }
+
+    /// Like `run_greedy_command` but parsing the output with `parse`,
+    /// for the commands that do not output JSON.
+    fn run_greedy_command_with<T, A>(
+        &self,
+        args: impl IntoIterator<Item = A>,
+        parse: impl FnOnce(&str) -> Result<T, ErrorKind>,
+    ) -> Result<T, Error>
+    where
+        A: AsRef<OsStr>,
     {
         let child = self.run_command(args)?;
-        let id = child.id();
-        defer! { info!("finished pid {}", id); }
-        let output = child.wait_with_output().map_err(|e| Error {
-            kind: ErrorKind::Run(RunError::Io(e)),
-            stderr: None,
-        })?;
-        let r_value: Result<T, ErrorKind> = if output.status.success() {
-            match str::from_utf8(&output.stdout) {
-                Ok(s) => serde_json::from_str(s).map_err(|e| e.into()),
-                Err(e) => Err(e.into()),
-            }
-        } else {
-            Err(ErrorKind::Run(RunError::Exit(output.status.code())))
-        };
-        match r_value {
-            Err(kind) => Err(Error {
-                kind,
-                stderr: Some(
-                    String::from_utf8_lossy(&output.stderr).into_owned(),
-                ),
-            }),
-            Ok(value) => Ok(value),
-        }
+        wait_greedy_command(child, parse)
     }
 
     fn run_command<A: AsRef<OsStr>>(
@@ -254,6 +302,35 @@ impl Restic {
     }
 }
 
+/// Waits for a command started by `Restic::run_command` and parses
+/// all of its output with `parse`.
+fn wait_greedy_command<T>(
+    child: Child,
+    parse: impl FnOnce(&str) -> Result<T, ErrorKind>,
+) -> Result<T, Error> {
+    let id = child.id();
+    defer! { info!("finished pid {}", id); }
+    let output = child.wait_with_output().map_err(|e| Error {
+        kind: ErrorKind::Run(RunError::Io(e)),
+        stderr: None,
+    })?;
+    let r_value: Result<T, ErrorKind> = if output.status.success() {
+        match str::from_utf8(&output.stdout) {
+            Ok(s) => parse(s),
+            Err(e) => Err(e.into()),
+        }
+    } else {
+        Err(ErrorKind::Run(RunError::Exit(output.status.code())))
+    };
+    match r_value {
+        Err(kind) => Err(Error {
+            kind,
+            stderr: Some(String::from_utf8_lossy(&output.stderr).into_owned()),
+        }),
+        Ok(value) => Ok(value),
+    }
+}
+
 struct Iter<T> {
     child: Child,
     lines: Lines<BufReader<ChildStdout>>,
@@ -354,10 +431,227 @@ pub struct Snapshot {
     pub program_version: Option<String>,
 }
 
+/// A restic tree object, i.e. the listing of a single directory.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Tree {
+    // restic writes `null` for empty directories
+    #[serde(default, deserialize_with = "null_as_default")]
+    pub nodes: Vec<Node>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Node {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub node_type: String,
+    #[serde(default)]
+    pub size: usize,
+    /// Only present for directories
+    #[serde(default)]
+    pub subtree: Option<String>,
+    /// The data blobs of a file
+    #[serde(default, deserialize_with = "null_as_default")]
+    pub content: Vec<String>,
+    #[serde(flatten)]
+    pub metadata: FileMetadata,
+}
+
+impl Node {
+    /// The file at `path` that this node is, like `Restic::ls` gives.
+    pub fn into_file(self, path: Utf8PathBuf) -> File {
+        let metadata = FileMetadata {
+            file_type: Some(FileType::parse(&self.node_type)),
+            ..self.metadata
+        };
+        File { path, size: self.size, metadata }
+    }
+}
+
+/// Selects snapshots like the --host, --tag and --path options of restic,
+/// with an additional time window.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SnapshotFilter {
+    /// The snapshot must be from one of these hosts.
+    pub hosts: Vec<String>,
+    /// The snapshot must have all the tags in at least one of these lists.
+    /// An empty tag matches snapshots without any tags.
+    pub tags: Vec<Vec<String>>,
+    /// The snapshot must include all of these paths.
+    pub paths: Vec<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+impl SnapshotFilter {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn matches(&self, snapshot: &Snapshot) -> bool {
+        let host_matches = self.hosts.is_empty()
+            || snapshot
+                .hostname
+                .as_ref()
+                .is_some_and(|h| self.hosts.contains(h));
+        let tags_match = self.tags.is_empty()
+            || self.tags.iter().any(|tags| {
+                tags.iter().all(|tag| {
+                    if tag.is_empty() {
+                        snapshot.tags.is_empty()
+                    } else {
+                        snapshot.tags.contains(tag)
+                    }
+                })
+            });
+        let paths_match =
+            self.paths.iter().all(|path| snapshot.paths.contains(path));
+        let time_matches =
+            self.since.is_none_or(|since| since <= snapshot.time)
+                && self.until.is_none_or(|until| snapshot.time <= until);
+        host_matches && tags_match && paths_match && time_matches
+    }
+}
+
+/// A line of `restic ls --json`.
+/// The directories are left out, their sizes come from their files.
+/// The other nodes without a size (symlinks, devices...) have size 0.
+fn parse_ls_file(mut v: Value) -> Option<File> {
+    let mut m = mem::take(v.as_object_mut()?);
+    let file_type = FileType::parse(m.remove("type")?.as_str()?);
+    if file_type == FileType::Dir {
+        return None;
+    }
+    let path = Utf8PathBuf::from(m.remove("path")?.as_str()?);
+    let size = match m.remove("size") {
+        Some(size) => size.as_u64()? as usize,
+        None => 0,
+    };
+    // The fields are parsed one by one (see `lenient`),
+    // so this can only fail if restic changes a lot.
+    let metadata = match FileMetadata::deserialize(Value::Object(m)) {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            warn!("ignoring the metadata of {path}: {e}");
+            FileMetadata::default()
+        }
+    };
+    Some(File {
+        path,
+        size,
+        metadata: FileMetadata { file_type: Some(file_type), ..metadata },
+    })
+}
+
+/// `None` if the value is missing or can't be parsed,
+/// instead of failing for the whole node.
+fn lenient<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned,
+{
+    Ok(T::deserialize(Value::deserialize(deserializer)?).ok())
+}
+
+fn null_as_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct File {
     pub path: Utf8PathBuf,
     pub size: usize,
+    pub metadata: FileMetadata,
+}
+
+/// What restic knows about a file besides its size.
+/// Anything can be missing, e.g. restic only has the inode and the number
+/// of links on some platforms and the snapshots cached by older versions
+/// of redu have no metadata at all.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct FileMetadata {
+    /// Comes from the `type` of the node
+    #[serde(skip_deserializing)]
+    pub file_type: Option<FileType>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub mtime: Option<DateTime<Utc>>,
+    /// Go's `os.FileMode`: the permissions in the low bits,
+    /// the type in the high ones
+    #[serde(default, deserialize_with = "lenient")]
+    pub mode: Option<u32>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub uid: Option<u32>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub gid: Option<u32>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub inode: Option<u64>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub links: Option<u64>,
+}
+
+impl FileMetadata {
+    /// Like `ls -l`, e.g. `rwxr-xr-x`.
+    pub fn permissions(&self) -> Option<String> {
+        let mode = self.mode?;
+        Some(
+            "rwxrwxrwx"
+                .chars()
+                .enumerate()
+                .map(|(i, c)| if mode & (1 << (8 - i)) != 0 { c } else { '-' })
+                .collect(),
+        )
+    }
+}
+
+/// The node types of restic, except for the directories
+/// this is what a `File` is.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileType {
+    File,
+    Dir,
+    Symlink,
+    Dev,
+    Chardev,
+    Fifo,
+    Socket,
+    Irregular,
+    /// A type that this version of redu doesn't know about
+    Other,
+}
+
+impl FileType {
+    pub fn parse(s: &str) -> Self {
+        match s {
+            "file" => FileType::File,
+            "dir" => FileType::Dir,
+            "symlink" => FileType::Symlink,
+            "dev" => FileType::Dev,
+            "chardev" => FileType::Chardev,
+            "fifo" => FileType::Fifo,
+            "socket" => FileType::Socket,
+            "irregular" => FileType::Irregular,
+            _ => FileType::Other,
+        }
+    }
+
+    /// The inverse of `parse`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FileType::File => "file",
+            FileType::Dir => "dir",
+            FileType::Symlink => "symlink",
+            FileType::Dev => "dev",
+            FileType::Chardev => "chardev",
+            FileType::Fifo => "fifo",
+            FileType::Socket => "socket",
+            FileType::Irregular => "irregular",
+            FileType::Other => "other",
+        }
+    }
 }
 
 pub fn escape_for_exclude(path: &str) -> Cow<'_, str> {
@@ -424,9 +718,288 @@ pub fn escape_for_exclude(path: &str) -> Cow<'_, str> {
     }
 }
 
+/// Like `escape_for_exclude` but for `--iexclude-file`.
+/// restic lowercases the patterns and the paths before matching them
+/// so this lowercases the path first to keep the escapes intact.
+pub fn escape_for_iexclude(path: &str) -> String {
+    escape_for_exclude(&path.to_lowercase()).into_owned()
+}
+
+/// The inverse of `escape_for_exclude`: the path that the pattern matches
+/// if it only matches a single path, `None` if it has any wildcards.
+pub fn unescape_exclude(pattern: &str) -> Option<String> {
+    let chars = pattern.chars().collect::<Vec<_>>();
+    let mut path = String::with_capacity(pattern.len());
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i..] {
+            ['*' | '?', ..] => return None,
+            ['\\', c, ..] => {
+                path.push(c);
+                i += 2;
+            }
+            ['\\'] => return None,
+            // The inverse range of a single character
+            ['[', '^', char::MIN, '-', lo, hi, '-', char::MAX, ']', ..]
+                if (lo as u32) + 2 == hi as u32 =>
+            {
+                path.push(char::from_u32(lo as u32 + 1)?);
+                i += 9;
+            }
+            ['[', c, ']', ..] if c != '^' && c != '\\' => {
+                path.push(c);
+                i += 3;
+            }
+            ['[', ..] => return None,
+            [c, ..] => {
+                path.push(c);
+                i += 1;
+            }
+            [] => unreachable!(),
+        }
+    }
+    Some(path)
+}
+
+/// The patterns in an exclude file, with the same rules as
+/// `restic backup --exclude-file`: blank lines and lines starting with `#`
+/// are skipped, `$VAR` and `${VAR}` are replaced by `getenv`
+/// (nothing if unset) and `$$` is a `$`.
+/// Returns `(line number, pattern)`, starting at 1.
+pub fn parse_exclude_file(
+    contents: &str,
+    getenv: impl Fn(&str) -> Option<String>,
+) -> Vec<(usize, String)> {
+    contents
+        .lines()
+        .enumerate()
+        .filter_map(|(index, line)| {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                None
+            } else {
+                Some((index + 1, expand_vars(line, &getenv)))
+            }
+        })
+        .collect()
+}
+
+/// Like Go's `os.Expand`, which is what restic uses.
+fn expand_vars(s: &str, getenv: impl Fn(&str) -> Option<String>) -> String {
+    fn is_special(c: char) -> bool {
+        "*#$@!?-".contains(c) || c.is_ascii_digit()
+    }
+    fn is_name(c: char) -> bool {
+        c == '_' || c.is_ascii_alphanumeric()
+    }
+    let lookup = |name: &str| match name {
+        "$" => "$".to_string(),
+        name => getenv(name).unwrap_or_default(),
+    };
+
+    let mut expanded = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(index) = rest.find('$') {
+        expanded.push_str(&rest[..index]);
+        let after = &rest[index + 1..];
+        let (name, width) = if let Some(braced) = after.strip_prefix('{') {
+            match braced.find('}') {
+                // Bad syntax, "${}" is dropped
+                Some(0) => ("", 2),
+                Some(end) => (&braced[..end], end + 2),
+                // Bad syntax, "${" is dropped
+                None => ("", 1),
+            }
+        } else if after.starts_with(is_special) {
+            (&after[..1], 1)
+        } else {
+            let end = after.find(|c| !is_name(c)).unwrap_or(after.len());
+            (&after[..end], end)
+        };
+        if name.is_empty() && width == 0 {
+            // Not followed by a name, it stays
+            expanded.push('$');
+        } else if !name.is_empty() {
+            expanded.push_str(&lookup(name));
+        }
+        rest = &after[width..];
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
 #[cfg(test)]
 mod test {
-    use super::escape_for_exclude;
+    use std::collections::HashSet;
+
+    use chrono::{TimeZone, Utc};
+
+    use super::{
+        escape_for_exclude, escape_for_iexclude, parse_exclude_file,
+        parse_ls_file, unescape_exclude, File, FileMetadata, FileType,
+        Snapshot, SnapshotFilter, Tree,
+    };
+
+    #[test]
+    fn node_into_file_test() {
+        let tree: Tree = serde_json::from_str(
+            r#"{"nodes": [
+                {"name": "a", "type": "symlink", "mode": 134218239,
+                 "mtime": "2024-01-02T03:04:05.5+01:00", "uid": 1000,
+                 "gid": 100, "inode": 42, "links": 1,
+                 "linktarget": "b", "content": null},
+                {"name": "c", "type": "file", "size": 3, "content": ["x"]}
+            ]}"#,
+        )
+        .unwrap();
+        let files = tree
+            .nodes
+            .into_iter()
+            .map(|node| {
+                let path = format!("/d/{}", node.name).into();
+                node.into_file(path)
+            })
+            .collect::<Vec<_>>();
+        let metadata = FileMetadata {
+            file_type: Some(FileType::Symlink),
+            mtime: Some(
+                Utc.with_ymd_and_hms(2024, 1, 2, 2, 4, 5).unwrap()
+                    + chrono::Duration::milliseconds(500),
+            ),
+            mode: Some(134218239),
+            uid: Some(1000),
+            gid: Some(100),
+            inode: Some(42),
+            links: Some(1),
+        };
+        assert_eq!(metadata.permissions().as_deref(), Some("rwxrwxrwx"));
+        assert_eq!(
+            files,
+            vec![
+                File { path: "/d/a".into(), size: 0, metadata },
+                File {
+                    path: "/d/c".into(),
+                    size: 3,
+                    metadata: FileMetadata {
+                        file_type: Some(FileType::File),
+                        ..FileMetadata::default()
+                    },
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_ls_file_test() {
+        let parse =
+            |json: &str| parse_ls_file(serde_json::from_str(json).unwrap());
+        assert_eq!(parse(r#"{"type": "dir", "path": "/d"}"#), None);
+        // The fields that don't parse are left out, not the whole metadata
+        assert_eq!(
+            parse(
+                r#"{"type": "file", "path": "/d/a", "size": 3,
+                    "mtime": "yesterday", "uid": -1, "gid": 100,
+                    "mode": 420, "inode": "42", "links": 1}"#
+            ),
+            Some(File {
+                path: "/d/a".into(),
+                size: 3,
+                metadata: FileMetadata {
+                    file_type: Some(FileType::File),
+                    mode: Some(420),
+                    gid: Some(100),
+                    links: Some(1),
+                    ..FileMetadata::default()
+                },
+            })
+        );
+        let tree: Tree = serde_json::from_str(
+            r#"{"nodes": [{"name": "a", "type": "file", "uid": -1,
+                           "mtime": "2024-01-02T03:04:05Z"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            tree.nodes[0].metadata,
+            FileMetadata {
+                mtime: Some(Utc.with_ymd_and_hms(2024, 1, 2, 3, 4, 5).unwrap()),
+                ..FileMetadata::default()
+            }
+        );
+    }
+
+    #[test]
+    fn snapshot_filter_test() {
+        let snapshot = Snapshot {
+            id: "foo".to_string(),
+            time: Utc.with_ymd_and_hms(2024, 4, 12, 12, 0, 0).unwrap(),
+            parent: None,
+            tree: "sometree".to_string(),
+            paths: HashSet::from(["/home".to_string(), "/etc".to_string()]),
+            hostname: Some("foo.com".to_string()),
+            username: None,
+            uid: None,
+            gid: None,
+            excludes: HashSet::new(),
+            tags: HashSet::from(["a".to_string(), "b".to_string()]),
+            original_id: None,
+            program_version: None,
+        };
+        let matches = |filter: SnapshotFilter| filter.matches(&snapshot);
+        let strings = |xs: &[&str]| {
+            xs.iter().map(ToString::to_string).collect::<Vec<_>>()
+        };
+
+        assert!(matches(SnapshotFilter::default()));
+
+        assert!(matches(SnapshotFilter {
+            hosts: strings(&["bar.com", "foo.com"]),
+            ..Default::default()
+        }));
+        assert!(!matches(SnapshotFilter {
+            hosts: strings(&["bar.com"]),
+            ..Default::default()
+        }));
+
+        assert!(matches(SnapshotFilter {
+            tags: vec![strings(&["a", "b"])],
+            ..Default::default()
+        }));
+        assert!(!matches(SnapshotFilter {
+            tags: vec![strings(&["a", "c"])],
+            ..Default::default()
+        }));
+        assert!(matches(SnapshotFilter {
+            tags: vec![strings(&["a", "c"]), strings(&["b"])],
+            ..Default::default()
+        }));
+        assert!(!matches(SnapshotFilter {
+            tags: vec![strings(&[""])],
+            ..Default::default()
+        }));
+
+        assert!(matches(SnapshotFilter {
+            paths: strings(&["/home", "/etc"]),
+            ..Default::default()
+        }));
+        assert!(!matches(SnapshotFilter {
+            paths: strings(&["/home", "/var"]),
+            ..Default::default()
+        }));
+
+        assert!(matches(SnapshotFilter {
+            since: Some(Utc.with_ymd_and_hms(2024, 4, 12, 12, 0, 0).unwrap()),
+            until: Some(Utc.with_ymd_and_hms(2024, 4, 12, 12, 0, 0).unwrap()),
+            ..Default::default()
+        }));
+        assert!(!matches(SnapshotFilter {
+            since: Some(Utc.with_ymd_and_hms(2024, 4, 13, 0, 0, 0).unwrap()),
+            ..Default::default()
+        }));
+        assert!(!matches(SnapshotFilter {
+            until: Some(Utc.with_ymd_and_hms(2024, 4, 12, 0, 0, 0).unwrap()),
+            ..Default::default()
+        }));
+    }
 
     #[cfg(not(target_os = "windows"))]
     #[test]
@@ -437,6 +1010,60 @@ mod test {
         );
     }
 
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn escape_for_iexclude_test() {
+        assert_eq!(
+            escape_for_iexclude("/Home/Foo* BAR?[\\G]\n"),
+            "/home/foo[*] bar[?][[]\\\\g][^\0-\u{0009}\u{000B}-\u{10FFFF}]"
+        );
+        // The escapes are already lowercase
+        let escaped = escape_for_iexclude("/A[B]*\r");
+        assert_eq!(escaped.to_lowercase(), escaped);
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn unescape_exclude_test() {
+        for path in ["/home/user", "foo* bar?[somethin\\g]]]\r\n", "/a/[^b]"] {
+            assert_eq!(
+                unescape_exclude(&escape_for_exclude(path)).as_deref(),
+                Some(path)
+            );
+        }
+        assert_eq!(unescape_exclude("/home/*/.cache"), None);
+        assert_eq!(unescape_exclude("/home/[ab]"), None);
+        assert_eq!(unescape_exclude("/x/file?"), None);
+        assert_eq!(unescape_exclude("/x/a\\*"), Some("/x/a*".into()));
+    }
+
+    #[test]
+    fn parse_exclude_file_test() {
+        let getenv = |name: &str| match name {
+            "HOME" => Some("/home/user".to_string()),
+            "X" => Some("x".to_string()),
+            _ => None,
+        };
+        let contents = "# comment\n\
+                        \n\
+                        \x20 $HOME/.cache  \n\
+                        ${HOME}/a$X.b\n\
+                        /price$$\n\
+                        /$UNSET/a\n\
+                        /a$/b${}c${\n\
+                        \x20 # indented comment\n";
+        assert_eq!(
+            parse_exclude_file(contents, getenv),
+            vec![
+                (3, "/home/user/.cache".to_string()),
+                (4, "/home/user/ax.b".to_string()),
+                (5, "/price$".to_string()),
+                (6, "//a".to_string()),
+                (7, "/a$/bc".to_string()),
+            ]
+        );
+    }
+
     #[cfg(target_os = "windows")]
     #[test]
     fn escape_for_exclude_test() {
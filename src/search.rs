@@ -0,0 +1,127 @@
+use std::io::Write;
+
+use anyhow::Context;
+use camino::Utf8PathBuf;
+use redu::{
+    cache::Cache,
+    glob::{is_glob, Glob},
+};
+use serde::Serialize;
+
+use crate::{args::ReportFormat, ui::escape_name};
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct SearchResult {
+    pub path: Utf8PathBuf,
+    pub is_dir: bool,
+    pub size: usize,
+}
+
+/// Returns the `top` largest entries whose name is (or matches) `pattern`,
+/// largest first.
+pub fn collect(
+    cache: &Cache,
+    pattern: &str,
+    top: usize,
+) -> anyhow::Result<Vec<SearchResult>> {
+    if is_glob(pattern) {
+        Glob::new(pattern)
+            .with_context(|| format!("invalid pattern {pattern:?}"))?;
+    }
+    Ok(cache
+        .search(pattern, top)?
+        .into_iter()
+        .map(|hit| SearchResult {
+            path: hit.path,
+            is_dir: hit.is_dir,
+            size: hit.size,
+        })
+        .collect())
+}
+
+pub fn write(
+    out: &mut impl Write,
+    format: ReportFormat,
+    hits: &[SearchResult],
+) -> anyhow::Result<()> {
+    match format {
+        ReportFormat::Table => {
+            writeln!(out, "{:>11}  PATH", "SIZE")?;
+            for hit in hits {
+                let mut path = escape_name(hit.path.as_str()).into_owned();
+                if hit.is_dir && !path.ends_with('/') {
+                    path.push('/');
+                }
+                writeln!(
+                    out,
+                    "{:>11}  {path}",
+                    humansize::format_size(hit.size, humansize::BINARY)
+                )?;
+            }
+        }
+        ReportFormat::Tsv => {
+            writeln!(out, "path\tis_dir\tsize")?;
+            for hit in hits {
+                writeln!(
+                    out,
+                    "{}\t{}\t{}",
+                    escape_name(hit.path.as_str()),
+                    hit.is_dir,
+                    hit.size
+                )?;
+            }
+        }
+        ReportFormat::Json => {
+            serde_json::to_writer_pretty(&mut *out, hits)?;
+            writeln!(out)?;
+        }
+    }
+    Ok(())
+}
+
+/// Tests //////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example_results() -> Vec<SearchResult> {
+        vec![
+            SearchResult {
+                path: "/home/user/node_modules".into(),
+                is_dir: true,
+                size: 3 * 1024 * 1024,
+            },
+            SearchResult {
+                path: "/srv/node_modules\tx".into(),
+                is_dir: false,
+                size: 10,
+            },
+        ]
+    }
+
+    fn write_to_string(format: ReportFormat) -> String {
+        let mut out = Vec::new();
+        write(&mut out, format, &example_results()).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn write_table_test() {
+        assert_eq!(
+            write_to_string(ReportFormat::Table),
+            "       SIZE  PATH\n\
+             \x20     3 MiB  /home/user/node_modules/\n\
+             \x20      10 B  /srv/node_modules\\tx\n"
+        );
+    }
+
+    #[test]
+    fn write_json_test() {
+        let json: serde_json::Value =
+            serde_json::from_str(&write_to_string(ReportFormat::Json)).unwrap();
+        assert_eq!(json[0]["path"], "/home/user/node_modules");
+        assert_eq!(json[0]["is_dir"], true);
+        assert_eq!(json[0]["size"], 3145728);
+        assert_eq!(json[1]["size"], 10);
+    }
+}
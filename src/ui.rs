@@ -1,11 +1,12 @@
 use std::{
     borrow::Cow,
-    cmp::{max, min},
-    collections::HashSet,
+    cmp::{max, min, Reverse},
+    collections::{HashMap, HashSet},
     iter,
 };
 
 use camino::Utf8PathBuf;
+use chrono::Local;
 use ratatui::{
     buffer::Buffer,
     layout::{Constraint, Direction, Layout, Position, Rect, Size},
@@ -17,11 +18,17 @@ use ratatui::{
         WidgetRef, Wrap,
     },
 };
-use redu::cache::EntryDetails;
+use redu::{
+    cache::{DiffEntry, EntryDetails, MarkEntry, MarksSummary, SearchHit},
+    glob::{is_glob, ExcludePattern, Glob},
+    restic::Snapshot,
+};
 use unicode_segmentation::UnicodeSegmentation;
 
 use crate::{
     cache::{Entry, PathId},
+    diff::{format_delta, format_o_size},
+    keys::KeyLabels,
     util::snapshot_short_id,
 };
 
@@ -37,17 +44,66 @@ pub enum Event {
     Enter,
     Exit,
     Mark,
+    MarkPattern,
     Unmark,
     UnmarkAll,
     Quit,
     Generate,
+    /// Generate the rewrite script instead of the exclude list
+    GenerateScript,
+    PickSnapshot,
+    PickDiff,
+    Filter,
+    NextMatch,
+    PrevMatch,
+    InputChar(char),
+    InputBackspace,
+    Aggregate,
+    Search,
+    SummarizeMarks,
+    ListMarks,
+    ImportMarks,
+    /// Change the order of a list
+    Sort,
+    /// Show or hide the help overlay
+    Help,
     Entries {
         /// `entries` is expected to be sorted by size, largest first.
         path_id: Option<PathId>,
         entries: Vec<Entry>,
     },
+    DiffEntries {
+        /// `entries` is expected to be sorted by absolute delta, largest first.
+        path_id: Option<PathId>,
+        entries: Vec<DiffEntry>,
+    },
     EntryDetails(EntryDetails),
-    Marks(Vec<Utf8PathBuf>),
+    Marks {
+        paths: Vec<Utf8PathBuf>,
+        patterns: Vec<String>,
+    },
+    Snapshots(Vec<Snapshot>),
+    SearchResults {
+        pattern: String,
+        /// Largest first
+        hits: Vec<SearchHit>,
+    },
+    MarksSummary(MarksSummary),
+    MarkEntries(Vec<MarkEntry>),
+    /// The marks after an import, with a report to show.
+    MarksImported {
+        lines: Vec<String>,
+        paths: Vec<Utf8PathBuf>,
+        patterns: Vec<String>,
+    },
+    /// Go to a directory somewhere else with one of its entries selected.
+    Jump {
+        /// `entries` is expected to be sorted by size, largest first.
+        path_id: Option<PathId>,
+        path: Utf8PathBuf,
+        entries: Vec<Entry>,
+        selected: PathId,
+    },
 }
 
 #[derive(Debug)]
@@ -55,13 +111,47 @@ pub enum Action {
     Nothing,
     Render,
     Quit,
-    Generate(Vec<Utf8PathBuf>),
+    Generate {
+        paths: Vec<Utf8PathBuf>,
+        patterns: Vec<String>,
+        script: bool,
+    },
     GetParentEntries(PathId),
     GetEntries(Option<PathId>),
     GetEntryDetails(PathId),
     UpsertMark(Utf8PathBuf),
     DeleteMark(Utf8PathBuf),
+    UpsertPatternMark(String),
+    DeletePatternMarks(Vec<String>),
     DeleteAllMarks,
+    GetSnapshots,
+    /// Show only this snapshot, `None` goes back to the aggregate view.
+    /// Must be answered with the entries of the current path.
+    SelectSnapshot(Option<String>),
+    /// Compare two snapshots, until the next `SelectSnapshot`.
+    /// Must be answered with the diff entries of the current path
+    /// and from then on `GetEntries` and `GetParentEntries` as well.
+    SelectDiff {
+        old: String,
+        new: String,
+    },
+    /// Must be answered with `SearchResults`.
+    Search(String),
+    /// Must be answered with `MarksSummary`.
+    GetMarksSummary,
+    /// Import a restic exclude file as marks.
+    /// Must be answered with `MarksImported`.
+    ImportMarks(Utf8PathBuf),
+    /// Must be answered with `MarkEntries`.
+    GetMarkEntries,
+    /// Go to the parent directory of `path_id` with it selected,
+    /// leaving the diff view if needed.
+    /// Must be answered with `Jump`.
+    Jump {
+        path_id: PathId,
+        parent_id: Option<PathId>,
+        path: Utf8PathBuf,
+    },
 }
 
 pub struct App {
@@ -69,12 +159,175 @@ pub struct App {
     path: Utf8PathBuf,
     entries: Vec<Entry>,
     marks: HashSet<Utf8PathBuf>,
+    /// Restic exclude patterns, sorted
+    pattern_marks: Vec<(String, ExcludePattern)>,
     list_size: Size,
     selected: usize,
     offset: usize,
-    footer_extra: Vec<Span<'static>>,
+    keys: KeyLabels,
+    /// The snapshot being shown, `None` is the aggregate of all of them.
+    snapshot: Option<Snapshot>,
+    diff: Option<DiffView>,
     details_drawer: Option<DetailsDrawer>,
     confirm_dialog: Option<ConfirmDialog>,
+    snapshot_picker: Option<SnapshotPicker>,
+    text_dialog: Option<TextDialog>,
+    marks_list: Option<MarksList>,
+    /// What the next snapshot picker is for
+    pending_pick: PickPurpose,
+    filter: Option<EntryFilter>,
+    input: Option<TextInput>,
+    search_results: Option<SearchResults>,
+}
+
+/// Filters the entries of the current directory with `pattern`.
+/// n/N show all the entries again and jump between the ones that match.
+struct EntryFilter {
+    pattern: String,
+    /// Are we still typing the pattern?
+    editing: bool,
+    /// Are only the matches in `App::entries`?
+    narrowed: bool,
+    /// All the entries of the current directory
+    unfiltered: Vec<Entry>,
+}
+
+impl EntryFilter {
+    /// A substring or, if it has special characters, a glob.
+    /// It's case insensitive if the pattern is all lowercase.
+    /// `None` if the pattern is not a valid glob.
+    fn matcher(&self) -> Option<impl Fn(&str) -> bool + '_> {
+        fn normalize(s: &str, ignore_case: bool) -> Cow<'_, str> {
+            if ignore_case {
+                Cow::Owned(s.to_lowercase())
+            } else {
+                Cow::Borrowed(s)
+            }
+        }
+        let ignore_case = !self.pattern.chars().any(char::is_uppercase);
+        let pattern = normalize(&self.pattern, ignore_case);
+        let glob = if is_glob(&pattern) {
+            Some(Glob::new(&pattern).ok()?)
+        } else {
+            None
+        };
+        Some(move |name: &str| {
+            let name = normalize(name, ignore_case);
+            match &glob {
+                Some(glob) => glob.matches(&name),
+                None => name.contains(pattern.as_ref()),
+            }
+        })
+    }
+
+    fn apply(&self) -> Vec<Entry> {
+        match self.matcher() {
+            None => Vec::new(),
+            Some(matcher) => self
+                .unfiltered
+                .iter()
+                .filter(|e| matcher(&e.component))
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// What goes in `App::entries`.
+    fn entries(&self) -> Vec<Entry> {
+        if self.narrowed {
+            self.apply()
+        } else {
+            self.unfiltered.clone()
+        }
+    }
+
+    fn count_matches(&self) -> usize {
+        match self.matcher() {
+            None => 0,
+            Some(matcher) => {
+                self.unfiltered.iter().filter(|e| matcher(&e.component)).count()
+            }
+        }
+    }
+}
+
+/// The index of the first entry after `from` (before it if `!forward`)
+/// that `is_match`, wrapping around.
+/// Without `from` it starts from the first (last) entry.
+fn find_match(
+    len: usize,
+    from: Option<usize>,
+    forward: bool,
+    is_match: impl Fn(usize) -> bool,
+) -> Option<usize> {
+    (0..len)
+        .map(|i| match (from, forward) {
+            (Some(from), true) => (from + 1 + i) % len,
+            (Some(from), false) => (from + len - 1 - i) % len,
+            (None, true) => i,
+            (None, false) => len - 1 - i,
+        })
+        .find(|&i| is_match(i))
+}
+
+/// A line of text being typed in the footer.
+struct TextInput {
+    purpose: InputPurpose,
+    text: String,
+}
+
+#[derive(Clone, Copy)]
+enum InputPurpose {
+    /// A name or glob to look for in the whole repository
+    Search,
+    /// A restic exclude pattern to mark
+    PatternMark,
+    /// A restic exclude file to import as marks
+    ImportFile,
+}
+
+impl TextInput {
+    fn prompt(&self) -> &'static str {
+        match self.purpose {
+            InputPurpose::Search => "Search (name or glob): ",
+            InputPurpose::PatternMark => {
+                "Mark pattern (e.g. **/node_modules): "
+            }
+            InputPurpose::ImportFile => "Import exclude file: ",
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        match self.purpose {
+            InputPurpose::Search => {
+                !is_glob(&self.text) || Glob::new(&self.text).is_ok()
+            }
+            InputPurpose::PatternMark => {
+                ExcludePattern::new(&self.text).is_ok()
+            }
+            InputPurpose::ImportFile => true,
+        }
+    }
+}
+
+/// Patterns that don't parse (which can't be entered anyway) are left out.
+fn parse_pattern_marks(patterns: Vec<String>) -> Vec<(String, ExcludePattern)> {
+    patterns
+        .into_iter()
+        .filter_map(|p| {
+            ExcludePattern::new(&p).ok().map(|pattern| (p, pattern))
+        })
+        .collect()
+}
+
+/// Two snapshots being compared.
+/// The entries' size is the absolute delta so that they are sorted
+/// and get size bars like in the normal view.
+struct DiffView {
+    old: Snapshot,
+    new: Snapshot,
+    /// Old and new sizes of the current entries
+    sizes: HashMap<PathId, (Option<usize>, Option<usize>)>,
 }
 
 impl App {
@@ -85,7 +338,8 @@ impl App {
         path: Utf8PathBuf,
         entries: Vec<Entry>,
         marks: Vec<Utf8PathBuf>,
-        footer_extra: Vec<Span<'static>>,
+        pattern_marks: Vec<String>,
+        keys: KeyLabels,
     ) -> Self {
         let list_size = compute_list_size(screen);
         App {
@@ -93,15 +347,35 @@ impl App {
             path,
             entries,
             marks: HashSet::from_iter(marks),
+            pattern_marks: parse_pattern_marks(pattern_marks),
             list_size,
             selected: 0,
             offset: 0,
-            footer_extra,
+            keys,
+            snapshot: None,
+            diff: None,
             details_drawer: None,
             confirm_dialog: None,
+            snapshot_picker: None,
+            text_dialog: None,
+            marks_list: None,
+            pending_pick: PickPurpose::Browse,
+            filter: None,
+            input: None,
+            search_results: None,
         }
     }
 
+    pub fn path_id(&self) -> Option<PathId> {
+        self.path_id
+    }
+
+    /// Is there a text input that should get all the keys?
+    pub fn is_editing(&self) -> bool {
+        self.input.is_some()
+            || self.filter.as_ref().is_some_and(|filter| filter.editing)
+    }
+
     pub fn update(&mut self, event: Event) -> Action {
         log::debug!("received {:?}", event);
         use Event::*;
@@ -111,6 +385,8 @@ impl App {
                 if let Some(ref mut confirm_dialog) = self.confirm_dialog {
                     confirm_dialog.yes_selected = false;
                     Action::Render
+                } else if self.has_dialog() {
+                    Action::Nothing
                 } else {
                     self.left()
                 }
@@ -119,6 +395,8 @@ impl App {
                 if let Some(ref mut confirm_dialog) = self.confirm_dialog {
                     confirm_dialog.yes_selected = true;
                     Action::Render
+                } else if self.has_dialog() {
+                    Action::Nothing
                 } else {
                     self.right()
                 }
@@ -132,28 +410,72 @@ impl App {
                 self.move_selection(self.list_size.height as isize, false)
             }
             Enter => {
-                if let Some(confirm_dialog) = self.confirm_dialog.take() {
+                if let Some(input) = self.input.take() {
+                    self.submit_input(input)
+                } else if let Some(filter) =
+                    self.filter.as_mut().filter(|f| f.editing)
+                {
+                    filter.editing = false;
+                    Action::Render
+                } else if let Some(confirm_dialog) = self.confirm_dialog.take()
+                {
                     if confirm_dialog.yes_selected {
                         confirm_dialog.action
                     } else {
                         Action::Render
                     }
-                } else if self.confirm_dialog.is_none() {
-                    Action::GetEntryDetails(self.entries[self.selected].path_id)
+                } else if let Some(picker) = self.snapshot_picker.take() {
+                    self.pick_snapshot(picker)
+                } else if let Some(results) = self.search_results.take() {
+                    match results.hits.into_iter().nth(results.selected) {
+                        Some(hit) => Action::Jump {
+                            path_id: hit.path_id,
+                            parent_id: hit.parent_id,
+                            path: hit.path,
+                        },
+                        None => Action::Render,
+                    }
+                } else if self.text_dialog.take().is_some() {
+                    Action::Render
+                } else if let Some(marks_list) = self.marks_list.take() {
+                    match marks_list.items.into_iter().nth(marks_list.selected)
+                    {
+                        Some(MarksListItem::Path(MarkEntry {
+                            path,
+                            path_id: Some(path_id),
+                            parent_id,
+                            ..
+                        })) => Action::Jump { path_id, parent_id, path },
+                        _ => Action::Render,
+                    }
+                } else if let Some(entry) = self.entries.get(self.selected) {
+                    Action::GetEntryDetails(entry.path_id)
                 } else {
                     Action::Nothing
                 }
             }
             Exit => {
-                if self.confirm_dialog.take().is_some()
+                if self.input.take().is_some() {
+                    Action::Render
+                } else if self.is_editing() {
+                    self.clear_filter()
+                } else if self.confirm_dialog.take().is_some()
+                    || self.snapshot_picker.take().is_some()
+                    || self.search_results.take().is_some()
+                    || self.text_dialog.take().is_some()
+                    || self.marks_list.take().is_some()
                     || self.details_drawer.take().is_some()
                 {
                     Action::Render
+                } else if self.filter.is_some() {
+                    self.clear_filter()
                 } else {
                     Action::Nothing
                 }
             }
             Mark => self.mark_selection(),
+            MarkPattern => self.open_input(InputPurpose::PatternMark),
+            ImportMarks => self.open_input(InputPurpose::ImportFile),
             Unmark => self.unmark_selection(),
             UnmarkAll => {
                 if self.confirm_dialog.is_none() {
@@ -170,14 +492,139 @@ impl App {
                     Action::Nothing
                 }
             }
+            Help => {
+                if self.text_dialog.as_ref().is_some_and(|d| d.title == "Help")
+                {
+                    self.text_dialog = None;
+                    Action::Render
+                } else if self.has_dialog() {
+                    Action::Nothing
+                } else {
+                    self.text_dialog = Some(TextDialog {
+                        title: "Help",
+                        lines: help_lines(&self.mode(), &self.keys),
+                        selected: 0,
+                    });
+                    Action::Render
+                }
+            }
             Quit => Action::Quit,
-            Generate => self.generate(),
-            Entries { path_id, entries } => self.set_entries(path_id, entries),
+            Generate => self.generate(false),
+            GenerateScript => self.generate(true),
+            Filter => {
+                if self.has_dialog() {
+                    Action::Nothing
+                } else if let Some(filter) = &mut self.filter {
+                    filter.editing = true;
+                    filter.narrowed = true;
+                    let entries = filter.apply();
+                    self.replace_entries(entries)
+                } else {
+                    self.filter = Some(EntryFilter {
+                        pattern: String::new(),
+                        editing: true,
+                        narrowed: true,
+                        unfiltered: self.entries.clone(),
+                    });
+                    Action::Render
+                }
+            }
+            NextMatch => self.jump_to_match(true),
+            PrevMatch => self.jump_to_match(false),
+            InputChar(c) => self.edit_input(|pattern| pattern.push(c)),
+            InputBackspace => self.edit_input(|pattern| {
+                pattern.pop();
+            }),
+            Search => self.open_input(InputPurpose::Search),
+            PickSnapshot => self.request_snapshots(PickPurpose::Browse),
+            PickDiff => self.request_snapshots(PickPurpose::DiffOld),
+            SummarizeMarks => {
+                if self.has_dialog() {
+                    Action::Nothing
+                } else {
+                    Action::GetMarksSummary
+                }
+            }
+            ListMarks => {
+                if self.has_dialog() {
+                    Action::Nothing
+                } else {
+                    Action::GetMarkEntries
+                }
+            }
+            Sort => match &mut self.marks_list {
+                Some(marks_list) => {
+                    marks_list.by_size = !marks_list.by_size;
+                    marks_list.sort();
+                    Action::Render
+                }
+                None => Action::Nothing,
+            },
+            Aggregate => {
+                if (self.snapshot.is_some() || self.diff.is_some())
+                    && self.confirm_dialog.is_none()
+                {
+                    self.select_snapshot(None)
+                } else {
+                    Action::Nothing
+                }
+            }
+            Entries { path_id, entries } => {
+                self.diff = None;
+                self.set_entries(path_id, entries)
+            }
+            DiffEntries { path_id, entries } => {
+                self.set_diff_entries(path_id, entries)
+            }
             EntryDetails(details) => {
                 self.details_drawer = Some(DetailsDrawer { details });
                 Action::Render
             }
-            Marks(new_marks) => self.set_marks(new_marks),
+            Marks { paths, patterns } => self.set_marks(paths, patterns),
+            Snapshots(snapshots) => self.open_snapshot_picker(snapshots),
+            MarkEntries(entries) => {
+                let mut marks_list =
+                    MarksList {
+                        items: entries
+                            .into_iter()
+                            .map(MarksListItem::Path)
+                            .chain(self.pattern_marks.iter().map(|(p, _)| {
+                                MarksListItem::Pattern(p.clone())
+                            }))
+                            .collect(),
+                        selected: 0,
+                        by_size: true,
+                        keys: self.keys.clone(),
+                    };
+                marks_list.sort();
+                self.marks_list = Some(marks_list);
+                Action::Render
+            }
+            MarksSummary(summary) => {
+                self.text_dialog = Some(TextDialog {
+                    title: "Marks summary",
+                    lines: marks_summary_lines(&summary),
+                    selected: 0,
+                });
+                Action::Render
+            }
+            MarksImported { lines, paths, patterns } => {
+                self.text_dialog =
+                    Some(TextDialog { title: "Import", lines, selected: 0 });
+                self.set_marks(paths, patterns)
+            }
+            SearchResults { pattern, hits } => {
+                self.search_results = Some(self::SearchResults {
+                    pattern,
+                    hits,
+                    selected: 0,
+                    keys: self.keys.clone(),
+                });
+                Action::Render
+            }
+            Jump { path_id, path, entries, selected } => {
+                self.jump(path_id, path, entries, selected)
+            }
         }
     }
 
@@ -206,17 +653,52 @@ impl App {
     }
 
     fn move_selection(&mut self, delta: isize, wrap: bool) -> Action {
+        if let Some(ref mut marks_list) = self.marks_list {
+            if !marks_list.items.is_empty() {
+                marks_list.selected = move_index(
+                    marks_list.selected,
+                    marks_list.items.len(),
+                    delta,
+                    wrap,
+                );
+            }
+            return Action::Render;
+        }
+        if let Some(ref mut text_dialog) = self.text_dialog {
+            text_dialog.selected = move_index(
+                text_dialog.selected,
+                text_dialog.lines.len(),
+                delta,
+                wrap,
+            );
+            return Action::Render;
+        }
+        if let Some(ref mut results) = self.search_results {
+            if !results.hits.is_empty() {
+                results.selected = move_index(
+                    results.selected,
+                    results.hits.len(),
+                    delta,
+                    wrap,
+                );
+            }
+            return Action::Render;
+        }
+        if let Some(ref mut picker) = self.snapshot_picker {
+            picker.selected = move_index(
+                picker.selected,
+                picker.snapshots.len(),
+                delta,
+                wrap,
+            );
+            return Action::Render;
+        }
         if self.entries.is_empty() {
             return Action::Nothing;
         }
 
-        let selected = self.selected as isize;
-        let len = self.entries.len() as isize;
-        self.selected = if wrap {
-            (selected + delta).rem_euclid(len)
-        } else {
-            max(0, min(len - 1, selected + delta))
-        } as usize;
+        self.selected =
+            move_index(self.selected, self.entries.len(), delta, wrap);
         self.fix_offset();
 
         if self.details_drawer.is_some() {
@@ -230,14 +712,54 @@ impl App {
         self.selected_entry().map(Action::UpsertMark).unwrap_or(Action::Nothing)
     }
 
+    /// Removes the mark of the selection or, if it doesn't have one,
+    /// the pattern marks that match it.
     fn unmark_selection(&mut self) -> Action {
-        self.selected_entry().map(Action::DeleteMark).unwrap_or(Action::Nothing)
+        if let Some(marks_list) = &mut self.marks_list {
+            if marks_list.items.is_empty() {
+                return Action::Nothing;
+            }
+            let item = marks_list.items.remove(marks_list.selected);
+            marks_list.selected = min(
+                marks_list.selected,
+                marks_list.items.len().saturating_sub(1),
+            );
+            return match item {
+                MarksListItem::Path(entry) => Action::DeleteMark(entry.path),
+                MarksListItem::Pattern(pattern) => {
+                    Action::DeletePatternMarks(vec![pattern])
+                }
+            };
+        }
+        let Some(path) = self.selected_entry() else {
+            return Action::Nothing;
+        };
+        if self.marks.contains(&path) {
+            return Action::DeleteMark(path);
+        }
+        let patterns = self.matching_patterns(&path);
+        if patterns.is_empty() {
+            Action::Nothing
+        } else {
+            Action::DeletePatternMarks(patterns)
+        }
+    }
+
+    fn generate(&self, script: bool) -> Action {
+        let mut paths = self.marks.iter().map(Clone::clone).collect::<Vec<_>>();
+        paths.sort_unstable();
+        let patterns =
+            self.pattern_marks.iter().map(|(p, _)| p.clone()).collect();
+        Action::Generate { paths, patterns, script }
     }
 
-    fn generate(&self) -> Action {
-        let mut lines = self.marks.iter().map(Clone::clone).collect::<Vec<_>>();
-        lines.sort_unstable();
-        Action::Generate(lines)
+    /// The pattern marks that match exactly `path`.
+    fn matching_patterns(&self, path: &Utf8PathBuf) -> Vec<String> {
+        self.pattern_marks
+            .iter()
+            .filter(|(_, pattern)| pattern.matches_exactly(path.as_str()))
+            .map(|(p, _)| p.clone())
+            .collect()
     }
 
     fn set_entries(
@@ -245,17 +767,34 @@ impl App {
         path_id: Option<PathId>,
         entries: Vec<Entry>,
     ) -> Action {
-        // See if any of the new entries matches the current directory
-        // and pre-select it. This means that we went up to the parent dir.
-        self.selected = entries
-            .iter()
-            .enumerate()
-            .find(|(_, e)| Some(e.path_id) == self.path_id)
-            .map(|(i, _)| i)
-            .unwrap_or(0);
-        self.offset = 0;
-        self.path_id = path_id;
-        {
+        // The filter only stays if we are in the same directory
+        let entries = match &mut self.filter {
+            Some(filter) if path_id == self.path_id => {
+                filter.unfiltered = entries;
+                filter.entries()
+            }
+            _ => {
+                self.filter = None;
+                entries
+            }
+        };
+        if path_id == self.path_id {
+            // Same directory (e.g. another snapshot),
+            // try to keep the same entry selected.
+            let selected = self.entries.get(self.selected).map(|e| e.path_id);
+            self.selected = entries
+                .iter()
+                .position(|e| Some(e.path_id) == selected)
+                .unwrap_or(0);
+        } else {
+            // See if any of the new entries matches the current directory
+            // and pre-select it. This means that we went up to the parent dir.
+            self.selected = entries
+                .iter()
+                .enumerate()
+                .find(|(_, e)| Some(e.path_id) == self.path_id)
+                .map(|(i, _)| i)
+                .unwrap_or(0);
             // Check if the new path_id matches any of the old entries.
             // If we find one this means that we are going down into that entry.
             if let Some(e) =
@@ -266,21 +805,288 @@ impl App {
                 self.path.pop();
             }
         }
+        self.offset = 0;
+        self.path_id = path_id;
         self.entries = entries;
         self.fix_offset();
+        self.refresh_details()
+    }
 
+    /// Like `set_entries` but for a directory that is not a parent or a
+    /// child of the current one.
+    fn jump(
+        &mut self,
+        path_id: Option<PathId>,
+        path: Utf8PathBuf,
+        entries: Vec<Entry>,
+        selected: PathId,
+    ) -> Action {
+        if self.diff.take().is_some() {
+            self.snapshot = None;
+        }
+        self.filter = None;
+        self.selected =
+            entries.iter().position(|e| e.path_id == selected).unwrap_or(0);
+        self.offset = 0;
+        self.path_id = path_id;
+        self.path = path;
+        self.entries = entries;
+        self.fix_offset();
+        self.refresh_details()
+    }
+
+    /// Update the details drawer (if open) to the selected entry.
+    fn refresh_details(&mut self) -> Action {
         if self.details_drawer.is_some() {
-            Action::GetEntryDetails(self.entries[self.selected].path_id)
+            if let Some(entry) = self.entries.get(self.selected) {
+                return Action::GetEntryDetails(entry.path_id);
+            }
+            self.details_drawer = None;
+        }
+        Action::Render
+    }
+
+    fn open_input(&mut self, purpose: InputPurpose) -> Action {
+        if self.has_dialog() {
+            Action::Nothing
         } else {
+            self.input = Some(TextInput { purpose, text: String::new() });
+            Action::Render
+        }
+    }
+
+    /// Send the typed text to whichever input is open.
+    fn edit_input(&mut self, f: impl FnOnce(&mut String)) -> Action {
+        if let Some(input) = &mut self.input {
+            f(&mut input.text);
             Action::Render
+        } else {
+            self.edit_filter(f)
         }
     }
 
-    fn set_marks(&mut self, new_marks: Vec<Utf8PathBuf>) -> Action {
-        self.marks = HashSet::from_iter(new_marks);
+    fn submit_input(&mut self, input: TextInput) -> Action {
+        if input.text.is_empty() {
+            Action::Render
+        } else if !input.is_valid() {
+            // Let it be fixed
+            self.input = Some(input);
+            Action::Nothing
+        } else {
+            match input.purpose {
+                InputPurpose::Search => Action::Search(input.text),
+                InputPurpose::PatternMark => {
+                    Action::UpsertPatternMark(input.text)
+                }
+                InputPurpose::ImportFile => {
+                    Action::ImportMarks(input.text.into())
+                }
+            }
+        }
+    }
+
+    fn edit_filter(&mut self, f: impl FnOnce(&mut String)) -> Action {
+        let Some(filter) = self.filter.as_mut().filter(|f| f.editing) else {
+            return Action::Nothing;
+        };
+        f(&mut filter.pattern);
+        let entries = filter.apply();
+        self.replace_entries(entries)
+    }
+
+    /// Shows all the entries of the directory and selects the next
+    /// (or previous) one after the selection that matches the filter.
+    fn jump_to_match(&mut self, forward: bool) -> Action {
+        if self.has_dialog() {
+            return Action::Nothing;
+        }
+        let Some(filter) = &mut self.filter else {
+            return Action::Nothing;
+        };
+        let found = {
+            let Some(matcher) = filter.matcher() else {
+                return Action::Nothing;
+            };
+            let selected = self.entries.get(self.selected).map(|e| e.path_id);
+            let from = filter
+                .unfiltered
+                .iter()
+                .position(|e| Some(e.path_id) == selected);
+            find_match(filter.unfiltered.len(), from, forward, |i| {
+                matcher(&filter.unfiltered[i].component)
+            })
+        };
+        let Some(index) = found else {
+            return Action::Nothing;
+        };
+        filter.narrowed = false;
+        self.entries = filter.unfiltered.clone();
+        self.selected = index;
+        self.fix_offset();
+        self.refresh_details()
+    }
+
+    fn clear_filter(&mut self) -> Action {
+        match self.filter.take() {
+            None => Action::Nothing,
+            Some(filter) => self.replace_entries(filter.unfiltered),
+        }
+    }
+
+    /// Change the entries of the current directory,
+    /// keeping the same entry selected if it's still there.
+    fn replace_entries(&mut self, entries: Vec<Entry>) -> Action {
+        let selected = self.entries.get(self.selected).map(|e| e.path_id);
+        self.selected = entries
+            .iter()
+            .position(|e| Some(e.path_id) == selected)
+            .unwrap_or(0);
+        self.entries = entries;
+        self.fix_offset();
+        self.refresh_details()
+    }
+
+    fn set_marks(
+        &mut self,
+        paths: Vec<Utf8PathBuf>,
+        patterns: Vec<String>,
+    ) -> Action {
+        self.marks = HashSet::from_iter(paths);
+        self.pattern_marks = parse_pattern_marks(patterns);
         Action::Render
     }
 
+    fn set_diff_entries(
+        &mut self,
+        path_id: Option<PathId>,
+        entries: Vec<DiffEntry>,
+    ) -> Action {
+        let Some(diff) = &mut self.diff else {
+            return Action::Nothing;
+        };
+        diff.sizes = entries
+            .iter()
+            .map(|e| (e.path_id, (e.old_size, e.new_size)))
+            .collect();
+        let entries = entries
+            .into_iter()
+            .map(|e| Entry {
+                path_id: e.path_id,
+                size: e.delta().unsigned_abs(),
+                component: e.component,
+                is_dir: e.is_dir,
+                unique_size: None,
+            })
+            .collect();
+        self.set_entries(path_id, entries)
+    }
+
+    /// What the sizes being shown are of, for the help.
+    fn mode(&self) -> String {
+        let describe = |snapshot: &Snapshot| {
+            format!(
+                "{} ({})",
+                snapshot_short_id(&snapshot.id),
+                snapshot.time.with_timezone(&Local).date_naive(),
+            )
+        };
+        if let Some(diff) = &self.diff {
+            format!(
+                "Comparing snapshot {} with {}",
+                describe(&diff.old),
+                describe(&diff.new)
+            )
+        } else if let Some(snapshot) = &self.snapshot {
+            format!("Browsing snapshot {}", describe(snapshot))
+        } else {
+            "Browsing all snapshots: each size is the largest one in any \
+             snapshot"
+                .into()
+        }
+    }
+
+    /// Is there a dialog that takes the keys?
+    fn has_dialog(&self) -> bool {
+        self.confirm_dialog.is_some()
+            || self.snapshot_picker.is_some()
+            || self.search_results.is_some()
+            || self.text_dialog.is_some()
+            || self.marks_list.is_some()
+    }
+
+    fn request_snapshots(&mut self, purpose: PickPurpose) -> Action {
+        if !self.has_dialog() {
+            self.pending_pick = purpose;
+            Action::GetSnapshots
+        } else {
+            Action::Nothing
+        }
+    }
+
+    fn open_snapshot_picker(&mut self, mut snapshots: Vec<Snapshot>) -> Action {
+        if snapshots.is_empty() {
+            return Action::Nothing;
+        }
+        snapshots.sort_unstable_by_key(|s| Reverse(s.time));
+        let current = match self.pending_pick {
+            PickPurpose::Browse => self.snapshot.as_ref(),
+            _ => self.diff.as_ref().map(|diff| &diff.old),
+        };
+        let selected = current
+            .and_then(|current| {
+                snapshots.iter().position(|s| s.id == current.id)
+            })
+            .unwrap_or(0);
+        self.snapshot_picker = Some(SnapshotPicker {
+            snapshots,
+            selected,
+            purpose: std::mem::replace(
+                &mut self.pending_pick,
+                PickPurpose::Browse,
+            ),
+            keys: self.keys.clone(),
+        });
+        Action::Render
+    }
+
+    fn pick_snapshot(&mut self, picker: SnapshotPicker) -> Action {
+        let SnapshotPicker { snapshots, selected, purpose, keys } = picker;
+        let snapshot = snapshots[selected].clone();
+        match purpose {
+            PickPurpose::Browse => self.select_snapshot(Some(snapshot)),
+            PickPurpose::DiffOld => {
+                // Now pick the new one, the next one in time is a good guess
+                self.snapshot_picker = Some(SnapshotPicker {
+                    snapshots,
+                    selected: selected.saturating_sub(1),
+                    purpose: PickPurpose::DiffNew(Box::new(snapshot)),
+                    keys,
+                });
+                Action::Render
+            }
+            PickPurpose::DiffNew(old) => {
+                let action = Action::SelectDiff {
+                    old: old.id.clone(),
+                    new: snapshot.id.clone(),
+                };
+                self.snapshot = None;
+                self.diff = Some(DiffView {
+                    old: *old,
+                    new: snapshot,
+                    sizes: HashMap::new(),
+                });
+                action
+            }
+        }
+    }
+
+    fn select_snapshot(&mut self, snapshot: Option<Snapshot>) -> Action {
+        let id = snapshot.as_ref().map(|s| s.id.clone());
+        self.snapshot = snapshot;
+        self.diff = None;
+        Action::SelectSnapshot(id)
+    }
+
     /// Adjust offset to make sure the selected item is visible.
     fn fix_offset(&mut self) {
         let offset = self.offset as isize;
@@ -312,6 +1118,16 @@ impl App {
     }
 }
 
+fn move_index(index: usize, len: usize, delta: isize, wrap: bool) -> usize {
+    let index = index as isize;
+    let len = len as isize;
+    if wrap {
+        (index + delta).rem_euclid(len) as usize
+    } else {
+        max(0, min(len - 1, index + delta)) as usize
+    }
+}
+
 fn compute_list_size(area: Size) -> Size {
     let (_, list, _) = compute_layout((Position::new(0, 0), area).into());
     list.as_size()
@@ -364,6 +1180,8 @@ impl WidgetRef for App {
             // Table
             const MIN_WIDTH_SHOW_SIZEBAR: u16 = 50;
             let show_sizebar = table_area.width >= MIN_WIDTH_SHOW_SIZEBAR;
+            let show_unique_size = self.diff.is_none()
+                && self.entries.iter().any(|e| e.unique_size.is_some());
             let mut rows: Vec<Row> = Vec::with_capacity(self.entries.len());
             let mut entries = self.entries.iter();
             if let Some(first) = entries.next() {
@@ -374,11 +1192,29 @@ impl WidgetRef for App {
                     .skip(self.offset)
                 {
                     let selected = index == self.selected;
-                    let mut spans = Vec::with_capacity(4);
+                    let mut spans = Vec::with_capacity(6);
+                    let path = self.full_path(entry);
                     spans.push(render_mark(
-                        self.marks.contains(&self.full_path(entry)),
+                        self.marks.contains(&path),
+                        self.pattern_marks.iter().any(|(_, pattern)| {
+                            pattern.matches_exactly(path.as_str())
+                        }),
                     ));
-                    spans.push(render_size(entry.size));
+                    if let Some(diff) = &self.diff {
+                        let (old_size, new_size) = diff
+                            .sizes
+                            .get(&entry.path_id)
+                            .copied()
+                            .unwrap_or_default();
+                        spans.push(render_delta(old_size, new_size));
+                        spans.push(render_o_size(old_size));
+                        spans.push(render_o_size(new_size));
+                    } else {
+                        spans.push(render_size(entry.size));
+                    }
+                    if show_unique_size {
+                        spans.push(render_unique_size(entry.unique_size));
+                    }
                     if show_sizebar {
                         spans.push(render_sizebar(
                             entry.size as f64 / largest_size,
@@ -405,9 +1241,16 @@ impl WidgetRef for App {
                     }));
                 }
             }
-            let mut constraints = Vec::with_capacity(4);
+            let mut constraints = Vec::with_capacity(6);
             constraints.push(Constraint::Min(MARK_LEN));
+            if self.diff.is_some() {
+                constraints.push(Constraint::Min(DELTA_LEN));
+                constraints.push(Constraint::Min(SIZE_LEN));
+            }
             constraints.push(Constraint::Min(SIZE_LEN));
+            if show_unique_size {
+                constraints.push(Constraint::Min(SIZE_LEN));
+            }
             if show_sizebar {
                 constraints.push(Constraint::Min(SIZEBAR_LEN));
             }
@@ -417,22 +1260,144 @@ impl WidgetRef for App {
 
         {
             // Footer
-            let spans = vec![
-                Span::from(format!(" Marks: {}", self.marks.len())),
+            if let Some(input) = &self.input {
+                let mut spans: Vec<Span> = vec![
+                    input.prompt().bold(),
+                    input.text.clone().into(),
+                    "█".into(),
+                ];
+                if !input.is_valid() {
+                    spans.push("  (invalid pattern)".red());
+                }
+                Paragraph::new(Line::from(spans))
+                    .on_light_blue()
+                    .render_ref(footer_area, buf);
+                return self.render_dialogs(area, table_area, buf);
+            }
+            if let Some(filter) = self.filter.as_ref().filter(|f| f.editing) {
+                let mut spans: Vec<Span> =
+                    vec!["/".bold(), filter.pattern.clone().into(), "█".into()];
+                if filter.matcher().is_none() {
+                    spans.push("  (invalid pattern)".red());
+                } else {
+                    spans.push(
+                        format!("  ({} matches)", self.entries.len()).into(),
+                    );
+                }
+                Paragraph::new(Line::from(spans))
+                    .on_light_blue()
+                    .render_ref(footer_area, buf);
+                return self.render_dialogs(area, table_area, buf);
+            }
+            let mut spans = vec![
+                Span::from(match self.pattern_marks.len() {
+                    0 => format!(" Marks: {}", self.marks.len()),
+                    n => format!(" Marks: {} + {n} patterns", self.marks.len()),
+                }),
                 Span::from("  |  "),
-            ]
-            .into_iter()
-            .chain(self.footer_extra.clone())
-            .collect::<Vec<_>>();
+            ];
+            if let Some(filter) = &self.filter {
+                spans.push(
+                    format!(
+                        "Filter: {} ({} of {})",
+                        filter.pattern,
+                        filter.count_matches(),
+                        filter.unfiltered.len()
+                    )
+                    .black()
+                    .on_yellow(),
+                );
+                spans.push("  ".into());
+                spans.extend(key_spans(
+                    &self.keys,
+                    &[
+                        ("next_match", "Next"),
+                        ("prev_match", "Prev"),
+                        ("exit", "Clear"),
+                    ],
+                ));
+                spans.push("  |  ".into());
+            }
+            if let Some(snapshot) = &self.snapshot {
+                spans.push(
+                    format!(
+                        "Snapshot: {} ({})",
+                        snapshot_short_id(&snapshot.id),
+                        snapshot.time.with_timezone(&Local).date_naive(),
+                    )
+                    .black()
+                    .on_yellow(),
+                );
+                spans.push("  ".into());
+                spans.extend(key_spans(
+                    &self.keys,
+                    &[("aggregate", "AllSnapshots")],
+                ));
+                spans.push("  |  ".into());
+            }
+            if let Some(diff) = &self.diff {
+                let describe = |snapshot: &Snapshot| {
+                    format!(
+                        "{} ({})",
+                        snapshot_short_id(&snapshot.id),
+                        snapshot.time.with_timezone(&Local).date_naive(),
+                    )
+                };
+                spans.push(
+                    format!(
+                        "Diff: {} -> {}",
+                        describe(&diff.old),
+                        describe(&diff.new)
+                    )
+                    .black()
+                    .on_yellow(),
+                );
+                spans.push("  ".into());
+                spans.extend(key_spans(
+                    &self.keys,
+                    &[("aggregate", "AllSnapshots")],
+                ));
+                spans.push("  |  ".into());
+            }
+            spans.extend(key_spans(&self.keys, FOOTER));
             Paragraph::new(Line::from(spans))
                 .on_light_blue()
                 .render_ref(footer_area, buf);
         }
 
+        self.render_dialogs(area, table_area, buf);
+    }
+}
+
+impl App {
+    fn render_dialogs(&self, area: Rect, table_area: Rect, buf: &mut Buffer) {
         if let Some(details_dialog) = &self.details_drawer {
             details_dialog.render_ref(table_area, buf);
         }
 
+        if let Some(snapshot_picker) = &self.snapshot_picker {
+            snapshot_picker.render_ref(area, buf);
+        }
+
+        if let Some(search_results) = &self.search_results {
+            search_results.render_ref(area, buf);
+        }
+
+        if let Some(marks_list) = &self.marks_list {
+            marks_list.render_ref(area, buf);
+        }
+
+        if let Some(text_dialog) = &self.text_dialog {
+            render_list_dialog(
+                text_dialog.title,
+                &key_hint(&self.keys, &[("exit", "Close")]),
+                &text_dialog.lines,
+                Some(text_dialog.selected),
+                area,
+                buf,
+            );
+        }
+
         if let Some(confirm_dialog) = &self.confirm_dialog {
             confirm_dialog.render_ref(area, buf);
         }
@@ -441,8 +1406,13 @@ impl WidgetRef for App {
 
 const MARK_LEN: u16 = 1;
 
-fn render_mark(is_marked: bool) -> Span<'static> {
-    Span::raw(if is_marked { "*" } else { " " })
+/// `*` for a marked path, `~` for a path matched by a pattern mark.
+fn render_mark(is_marked: bool, matches_pattern: bool) -> Span<'static> {
+    Span::raw(match (is_marked, matches_pattern) {
+        (true, _) => "*",
+        (false, true) => "~",
+        (false, false) => " ",
+    })
 }
 
 const SIZE_LEN: u16 = 11;
@@ -454,6 +1424,33 @@ fn render_size(size: usize) -> Span<'static> {
     ))
 }
 
+fn render_unique_size(size: Option<usize>) -> Span<'static> {
+    match size {
+        Some(size) => render_size(size).dark_gray(),
+        None => Span::raw(format!("{:>11}", "-")).dark_gray(),
+    }
+}
+
+const DELTA_LEN: u16 = 12;
+
+/// Growth in red, shrinkage in green.
+fn render_delta(
+    old_size: Option<usize>,
+    new_size: Option<usize>,
+) -> Span<'static> {
+    let delta = new_size.unwrap_or(0) as isize - old_size.unwrap_or(0) as isize;
+    let span = Span::raw(format!("{:>12}", format_delta(delta)));
+    if delta > 0 {
+        span.red()
+    } else {
+        span.green()
+    }
+}
+
+fn render_o_size(size: Option<usize>) -> Span<'static> {
+    Span::raw(format!("{:>11}", format_o_size(size))).dark_gray()
+}
+
 const SIZEBAR_LEN: u16 = 16;
 
 fn render_sizebar(relative_size: f64) -> Span<'static> {
@@ -504,7 +1501,7 @@ fn render_name(
     }
 }
 
-fn escape_name(name: &str) -> Cow<'_, str> {
+pub fn escape_name(name: &str) -> Cow<'_, str> {
     match name.find(char::is_control) {
         None => Cow::Borrowed(name),
         Some(index) => {
@@ -552,18 +1549,7 @@ struct DetailsDrawer {
 
 impl WidgetRef for DetailsDrawer {
     fn render_ref(&self, area: Rect, buf: &mut Buffer) {
-        let details = &self.details;
-        let text = format!(
-            "max size: {} ({})\n\
-             first seen: {} ({})\n\
-             last seen: {} ({})\n",
-            humansize::format_size(details.max_size, humansize::BINARY),
-            snapshot_short_id(&details.max_size_snapshot_hash),
-            details.first_seen.date_naive(),
-            snapshot_short_id(&details.first_seen_snapshot_hash),
-            details.last_seen.date_naive(),
-            snapshot_short_id(&details.last_seen_snapshot_hash),
-        );
+        let text = details_text(&self.details);
         let paragraph = Paragraph::new(text).wrap(Wrap { trim: false });
         let padding = Padding { left: 2, right: 2, top: 0, bottom: 0 };
         let horiz_padding = padding.left + padding.right;
@@ -592,6 +1578,56 @@ impl WidgetRef for DetailsDrawer {
     }
 }
 
+/// The metadata comes from the last snapshot that has the entry,
+/// only what is known is shown.
+fn details_text(details: &EntryDetails) -> String {
+    let mut text = format!(
+        "max size: {} ({})\n\
+         first seen: {} ({})\n\
+         last seen: {} ({})\n",
+        humansize::format_size(details.max_size, humansize::BINARY),
+        snapshot_short_id(&details.max_size_snapshot_hash),
+        details.first_seen.date_naive(),
+        snapshot_short_id(&details.first_seen_snapshot_hash),
+        details.last_seen.date_naive(),
+        snapshot_short_id(&details.last_seen_snapshot_hash),
+    );
+    if let Some(unique_size) = details.unique_size {
+        text.push_str(&format!(
+            "unique size: {}\n",
+            humansize::format_size(unique_size, humansize::BINARY)
+        ));
+    }
+    let metadata = &details.metadata;
+    if let Some(file_type) = metadata.file_type {
+        text.push_str(&format!("type: {}\n", file_type.as_str()));
+    }
+    if let Some(mtime) = metadata.mtime {
+        text.push_str(&format!(
+            "modified: {}\n",
+            mtime.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S")
+        ));
+    }
+    if metadata.uid.is_some() || metadata.gid.is_some() {
+        let id = |id: Option<u32>| id.map_or("?".into(), |id| id.to_string());
+        text.push_str(&format!(
+            "owner: {}:{}\n",
+            id(metadata.uid),
+            id(metadata.gid)
+        ));
+    }
+    if let Some(permissions) = metadata.permissions() {
+        text.push_str(&format!("permissions: {permissions}\n"));
+    }
+    if let Some(inode) = metadata.inode {
+        text.push_str(&format!("inode: {inode}\n"));
+    }
+    if let Some(links) = metadata.links {
+        text.push_str(&format!("links: {links}\n"));
+    }
+    text
+}
+
 /// ConfirmDialog //////////////////////////////////////////////////////////////
 struct ConfirmDialog {
     text: String,
@@ -660,7 +1696,387 @@ impl WidgetRef for ConfirmDialog {
     }
 }
 
+/// SnapshotPicker /////////////////////////////////////////////////////////////
+struct SnapshotPicker {
+    /// Sorted by time, latest first.
+    snapshots: Vec<Snapshot>,
+    selected: usize,
+    purpose: PickPurpose,
+    keys: KeyLabels,
+}
+
+enum PickPurpose {
+    Browse,
+    /// The old snapshot of a diff
+    DiffOld,
+    /// The new snapshot of a diff with this old one
+    DiffNew(Box<Snapshot>),
+}
+
+impl WidgetRef for SnapshotPicker {
+    fn render_ref(&self, area: Rect, buf: &mut Buffer) {
+        let lines =
+            self.snapshots.iter().map(snapshot_line).collect::<Vec<_>>();
+        let title = match self.purpose {
+            PickPurpose::Browse => "Snapshots",
+            PickPurpose::DiffOld => "Diff: pick the old snapshot",
+            PickPurpose::DiffNew(_) => "Diff: pick the new snapshot",
+        };
+        render_list_dialog(
+            title,
+            &key_hint(&self.keys, &[("enter", "Select"), ("exit", "Cancel")]),
+            &lines,
+            Some(self.selected),
+            area,
+            buf,
+        );
+    }
+}
+
+/// One line with the time, id, host, tags and paths of the snapshot.
+fn snapshot_line(snapshot: &Snapshot) -> String {
+    let mut tags = snapshot.tags.iter().map(String::as_str).collect::<Vec<_>>();
+    tags.sort_unstable();
+    let mut paths =
+        snapshot.paths.iter().map(String::as_str).collect::<Vec<_>>();
+    paths.sort_unstable();
+    let mut line = format!(
+        "{}  {}  {}",
+        snapshot.time.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S"),
+        snapshot_short_id(&snapshot.id),
+        snapshot.hostname.as_deref().unwrap_or("-"),
+    );
+    if !tags.is_empty() {
+        line.push_str(&format!("  [{}]", tags.join(",")));
+    }
+    line.push_str("  ");
+    line.push_str(&escape_name(&paths.join(" ")));
+    line
+}
+
+/// SearchResults //////////////////////////////////////////////////////////////
+struct SearchResults {
+    pattern: String,
+    /// Largest first
+    hits: Vec<SearchHit>,
+    selected: usize,
+    keys: KeyLabels,
+}
+
+impl WidgetRef for SearchResults {
+    fn render_ref(&self, area: Rect, buf: &mut Buffer) {
+        let title =
+            format!("Search: {} ({} found)", self.pattern, self.hits.len());
+        if self.hits.is_empty() {
+            let lines = ["Nothing found".to_string()];
+            return render_list_dialog(
+                &title,
+                &key_hint(&self.keys, &[("exit", "Close")]),
+                &lines,
+                None,
+                area,
+                buf,
+            );
+        }
+        let lines = self
+            .hits
+            .iter()
+            .map(|hit| {
+                let mut path = escape_name(hit.path.as_str()).into_owned();
+                if hit.is_dir && !path.ends_with('/') {
+                    path.push('/');
+                }
+                format!(
+                    "{:>11}  {path}",
+                    humansize::format_size(hit.size, humansize::BINARY)
+                )
+            })
+            .collect::<Vec<_>>();
+        render_list_dialog(
+            &title,
+            &key_hint(&self.keys, &[("enter", "GoTo"), ("exit", "Close")]),
+            &lines,
+            Some(self.selected),
+            area,
+            buf,
+        );
+    }
+}
+
+/// MarksList //////////////////////////////////////////////////////////////////
+struct MarksList {
+    items: Vec<MarksListItem>,
+    selected: usize,
+    /// Largest first, otherwise by path
+    by_size: bool,
+    keys: KeyLabels,
+}
+
+enum MarksListItem {
+    Path(MarkEntry),
+    Pattern(String),
+}
+
+impl MarksListItem {
+    fn text(&self) -> &str {
+        match self {
+            MarksListItem::Path(entry) => entry.path.as_str(),
+            MarksListItem::Pattern(pattern) => pattern,
+        }
+    }
+
+    fn size(&self) -> Option<usize> {
+        match self {
+            MarksListItem::Path(entry) => entry.size,
+            MarksListItem::Pattern(_) => None,
+        }
+    }
+}
+
+impl MarksList {
+    /// Keeps the same item selected.
+    fn sort(&mut self) {
+        let mut items = std::mem::take(&mut self.items)
+            .into_iter()
+            .enumerate()
+            .collect::<Vec<_>>();
+        if self.by_size {
+            // The ones without a size go last
+            items.sort_by(|(_, a), (_, b)| {
+                (Reverse(a.size()), a.text())
+                    .cmp(&(Reverse(b.size()), b.text()))
+            });
+        } else {
+            items.sort_by(|(_, a), (_, b)| a.text().cmp(b.text()));
+        }
+        self.selected =
+            items.iter().position(|(i, _)| *i == self.selected).unwrap_or(0);
+        self.items = items.into_iter().map(|(_, item)| item).collect();
+    }
+
+    fn lines(&self) -> Vec<String> {
+        self.items
+            .iter()
+            .map(|item| match item {
+                MarksListItem::Path(entry) => match entry.size {
+                    Some(size) => format!(
+                        "{:>11}  * {}",
+                        humansize::format_size(size, humansize::BINARY),
+                        escape_name(entry.path.as_str())
+                    ),
+                    None => format!(
+                        "{:>11}  * {}  (not in any snapshot)",
+                        "-",
+                        escape_name(entry.path.as_str())
+                    ),
+                },
+                MarksListItem::Pattern(pattern) => {
+                    format!("{:>11}  ~ {pattern}  (pattern)", "-")
+                }
+            })
+            .collect()
+    }
+}
+
+impl WidgetRef for MarksList {
+    fn render_ref(&self, area: Rect, buf: &mut Buffer) {
+        let title = format!(
+            "Marks ({}, by {})",
+            self.items.len(),
+            if self.by_size { "size" } else { "path" }
+        );
+        if self.items.is_empty() {
+            let lines = ["There are no marks".to_string()];
+            return render_list_dialog(
+                &title,
+                &key_hint(&self.keys, &[("exit", "Close")]),
+                &lines,
+                None,
+                area,
+                buf,
+            );
+        }
+        render_list_dialog(
+            &title,
+            &key_hint(
+                &self.keys,
+                &[
+                    ("enter", "GoTo"),
+                    ("unmark", "Unmark"),
+                    ("sort", "Order"),
+                    ("exit", "Close"),
+                ],
+            ),
+            &self.lines(),
+            Some(self.selected),
+            area,
+            buf,
+        );
+    }
+}
+
+/// TextDialog /////////////////////////////////////////////////////////////////
+struct TextDialog {
+    title: &'static str,
+    lines: Vec<String>,
+    /// Highlighted to scroll through the lines
+    selected: usize,
+}
+
+/// How much is removed from each snapshot and then by each mark,
+/// largest first.
+fn marks_summary_lines(summary: &MarksSummary) -> Vec<String> {
+    if summary.marks.is_empty() {
+        return vec!["There are no marks".into()];
+    }
+    let human_size = |size| humansize::format_size(size, humansize::BINARY);
+    let mut lines =
+        vec!["Removed from each snapshot (nested marks count once):".into()];
+    for (snapshot, size) in &summary.snapshots {
+        lines.push(format!(
+            "{:>11}  {}  {}",
+            human_size(*size),
+            snapshot.time.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S"),
+            snapshot_short_id(&snapshot.id),
+        ));
+    }
+    let total: usize = summary.snapshots.iter().map(|(_, size)| size).sum();
+    lines.push(format!(
+        "{:>11}  in total (before deduplication)",
+        human_size(total)
+    ));
+    lines.push("".into());
+    lines.push(format!(
+        "{:>11}  {:>11}  {:>9}  MARK",
+        "LARGEST", "TOTAL", "SNAPSHOTS"
+    ));
+    let mut marks = summary.marks.iter().collect::<Vec<_>>();
+    marks.sort_by_key(|mark| Reverse(mark.max_size));
+    for mark in marks {
+        let (symbol, mark_text) = if mark.is_pattern {
+            ("~", Cow::Borrowed(mark.mark.as_str()))
+        } else {
+            ("*", escape_name(&mark.mark))
+        };
+        lines.push(format!(
+            "{:>11}  {:>11}  {:>9}  {symbol} {mark_text}",
+            human_size(mark.max_size),
+            human_size(mark.total_size),
+            format!("{}/{}", mark.snapshots, summary.snapshots.len()),
+        ));
+    }
+    lines
+}
+
+/// The current mode, what is shown on screen and the keybindings.
+fn help_lines(mode: &str, keys: &KeyLabels) -> Vec<String> {
+    let mut lines = vec![
+        mode.to_string(),
+        "".into(),
+        "Columns:".into(),
+        "  *      marked".into(),
+        "  ~      matched by a pattern mark".into(),
+        "  size   the largest size of the item in any of the snapshots shown"
+            .into(),
+        "  size   in gray (--unique-sizes), the space freed by removing the \
+         item from every snapshot, - if unknown"
+            .into(),
+        "  bar    the size relative to the largest item in this directory"
+            .into(),
+        "  diff   the change (growth in red, shrinkage in green), then the \
+         old and new sizes"
+            .into(),
+        "".into(),
+        "Keys:".into(),
+    ];
+    lines.extend(keys.help_lines().iter().map(|line| format!("  {line}")));
+    lines
+}
+
 /// Misc //////////////////////////////////////////////////////////////////////
+/// The actions in the footer with their labels.
+const FOOTER: &[(&str, &str)] = &[
+    ("enter", "Details"),
+    ("mark", "Mark"),
+    ("mark_pattern", "MarkPattern"),
+    ("unmark", "Unmark"),
+    ("unmark_all", "ClearAllMarks"),
+    ("list_marks", "ListMarks"),
+    ("summarize_marks", "MarksSummary"),
+    ("pick_snapshot", "Snapshot"),
+    ("pick_diff", "Diff"),
+    ("filter", "Filter"),
+    ("search", "Find"),
+    ("generate", "Generate"),
+    ("generate_script", "Script"),
+    ("help", "Help"),
+    ("quit", "Quit"),
+];
+
+/// `key:Label` for each of the actions that are bound to a key.
+fn key_spans(keys: &KeyLabels, actions: &[(&str, &str)]) -> Vec<Span<'static>> {
+    let mut spans = Vec::new();
+    for (action, label) in actions {
+        if let Some(key) = keys.get(action) {
+            if !spans.is_empty() {
+                spans.push("  ".into());
+            }
+            spans.push(key.to_string().bold());
+            spans.push(format!(":{label}").into());
+        }
+    }
+    spans
+}
+
+/// The same as `key_spans` as a string for the border of a dialog.
+fn key_hint(keys: &KeyLabels, actions: &[(&str, &str)]) -> String {
+    let hint = key_spans(keys, actions)
+        .iter()
+        .map(|span| span.content.as_ref())
+        .collect::<String>();
+    format!(" {hint} ")
+}
+
+/// A bordered list of lines, scrolled to keep the `selected` one visible.
+fn render_list_dialog(
+    title: &str,
+    help: &str,
+    lines: &[String],
+    selected: Option<usize>,
+    area: Rect,
+    buf: &mut Buffer,
+) {
+    let padding = Padding { left: 1, right: 1, top: 0, bottom: 0 };
+    let width = lines
+        .iter()
+        .map(|l| grapheme_len(l))
+        .chain([grapheme_len(title), grapheme_len(help)])
+        .max()
+        .unwrap_or(0);
+    let dialog_area = dialog(
+        padding,
+        min(width as u16, area.width.saturating_sub(8)),
+        min(lines.len() as u16, area.height.saturating_sub(6)),
+        area,
+    );
+    let block =
+        Block::bordered().title(title).title_bottom(help).padding(padding);
+    let inner_area = block.inner(dialog_area);
+    let height = inner_area.height as usize;
+    let offset = selected.map(|s| (s + 1).saturating_sub(height)).unwrap_or(0);
+    let rows = lines.iter().enumerate().skip(offset).map(|(index, line)| {
+        let line = shorten_to(line, inner_area.width as usize).into_owned();
+        Row::new([line]).style(if Some(index) == selected {
+            Style::new().black().on_white()
+        } else {
+            Style::new()
+        })
+    });
+    Clear.render(dialog_area, buf);
+    block.render(dialog_area, buf);
+    Table::new(rows, [Constraint::Percentage(100)]).render_ref(inner_area, buf);
+}
+
 fn dialog(
     padding: Padding,
     max_inner_width: u16,
@@ -693,6 +2109,8 @@ fn grapheme_len(s: &str) -> usize {
 /// Tests //////////////////////////////////////////////////////////////////////
 #[cfg(test)]
 mod tests {
+    use redu::restic::{FileMetadata, FileType};
+
     use super::{shorten_to, *};
 
     #[test]
@@ -724,6 +2142,182 @@ mod tests {
         );
     }
 
+    #[test]
+    fn entry_filter_matcher_test() {
+        fn matches(pattern: &str, name: &str) -> Option<bool> {
+            let filter = EntryFilter {
+                pattern: pattern.into(),
+                editing: false,
+                narrowed: true,
+                unfiltered: vec![],
+            };
+            filter.matcher().map(|matcher| matcher(name))
+        }
+        assert_eq!(matches("", "anything"), Some(true));
+        assert_eq!(matches("mod", "node_modules"), Some(true));
+        assert_eq!(matches("MOD", "node_modules"), Some(false));
+        assert_eq!(matches("node", "Node_Modules"), Some(true));
+        assert_eq!(matches("*.iso", "Debian.ISO"), Some(true));
+        assert_eq!(matches("*.ISO", "debian.iso"), Some(false));
+        assert_eq!(matches("*.iso", "debian.iso.part"), Some(false));
+        assert_eq!(matches("[abc", "a"), None);
+    }
+
+    #[test]
+    fn find_match_test() {
+        let is_match = |i: usize| [false, true, false, true, false][i];
+        assert_eq!(find_match(5, Some(1), true, is_match), Some(3));
+        assert_eq!(find_match(5, Some(3), true, is_match), Some(1));
+        assert_eq!(find_match(5, Some(3), false, is_match), Some(1));
+        assert_eq!(find_match(5, Some(1), false, is_match), Some(3));
+        assert_eq!(find_match(5, Some(0), false, is_match), Some(3));
+        assert_eq!(find_match(5, None, true, is_match), Some(1));
+        assert_eq!(find_match(5, None, false, is_match), Some(3));
+        assert_eq!(find_match(5, Some(2), true, |i| i == 2), Some(2));
+        assert_eq!(find_match(5, Some(2), true, |_| false), None);
+        assert_eq!(find_match(0, None, true, |_| true), None);
+    }
+
+    #[test]
+    fn generate_test() {
+        let mut app = App::new(
+            Size::new(80, 24),
+            None,
+            Utf8PathBuf::new(),
+            vec![],
+            vec!["/b".into(), "/a*".into()],
+            vec!["**/node_modules".into(), "/home/*/.cache".into()],
+            KeyLabels::default(),
+        );
+        // The path marks get escaped later, the patterns are used as they are
+        let Action::Generate { paths, patterns, script } =
+            app.update(Event::Generate)
+        else {
+            panic!("expected Generate");
+        };
+        assert_eq!(paths, vec![Utf8PathBuf::from("/a*"), "/b".into()]);
+        assert_eq!(patterns, vec!["**/node_modules", "/home/*/.cache"]);
+        assert!(!script);
+        assert!(matches!(
+            app.update(Event::GenerateScript),
+            Action::Generate { script: true, .. }
+        ));
+    }
+
+    #[test]
+    fn marks_summary_lines_test() {
+        use redu::cache::MarkSummary;
+
+        let snapshot = Snapshot {
+            id: "0123456789abcdef".into(),
+            time: chrono::Utc::now(),
+            parent: None,
+            tree: "tree".into(),
+            paths: HashSet::new(),
+            hostname: None,
+            username: None,
+            uid: None,
+            gid: None,
+            excludes: HashSet::new(),
+            tags: HashSet::new(),
+            original_id: None,
+            program_version: None,
+        };
+        let mark = |mark: &str, is_pattern, max_size| MarkSummary {
+            mark: mark.into(),
+            is_pattern,
+            snapshots: 1,
+            max_size,
+            total_size: max_size,
+        };
+        let summary = MarksSummary {
+            marks: vec![mark("/a\tb", false, 10), mark("**/x", true, 2048)],
+            snapshots: vec![(snapshot, 2058)],
+        };
+        let lines = marks_summary_lines(&summary);
+        assert_eq!(lines.len(), 7);
+        assert!(lines[1].starts_with("   2.01 KiB  "));
+        assert!(lines[1].ends_with("  0123456"));
+        assert_eq!(
+            &lines[2..],
+            [
+                "   2.01 KiB  in total (before deduplication)",
+                "",
+                "    LARGEST        TOTAL  SNAPSHOTS  MARK",
+                "      2 KiB        2 KiB        1/1  ~ **/x",
+                "       10 B         10 B        1/1  * /a\\tb",
+            ]
+        );
+        assert_eq!(
+            marks_summary_lines(&MarksSummary {
+                marks: vec![],
+                snapshots: vec![]
+            }),
+            vec!["There are no marks"]
+        );
+    }
+
+    #[test]
+    fn help_lines_test() {
+        let lines = help_lines("Browsing all snapshots", &KeyLabels::default());
+        assert_eq!(lines[0], "Browsing all snapshots");
+        assert!(lines.contains(&"  ?: Show this help".to_string()));
+        assert_eq!(lines.last().unwrap(), "  q: Quit");
+    }
+
+    #[test]
+    fn marks_list_sort_test() {
+        let path = |path: &str, size| {
+            MarksListItem::Path(MarkEntry {
+                path: path.into(),
+                path_id: None,
+                parent_id: None,
+                size,
+            })
+        };
+        let mut marks_list = MarksList {
+            items: vec![
+                path("/b", Some(10)),
+                path("/gone", None),
+                path("/a", Some(2048)),
+                MarksListItem::Pattern("**/x".into()),
+            ],
+            selected: 0,
+            by_size: true,
+            keys: KeyLabels::default(),
+        };
+        marks_list.sort();
+        assert_eq!(
+            marks_list.lines(),
+            [
+                "      2 KiB  * /a",
+                "       10 B  * /b",
+                "          -  ~ **/x  (pattern)",
+                "          -  * /gone  (not in any snapshot)",
+            ]
+        );
+        assert_eq!(marks_list.selected, 1);
+
+        marks_list.by_size = false;
+        marks_list.sort();
+        let texts =
+            marks_list.items.iter().map(|i| i.text()).collect::<Vec<_>>();
+        assert_eq!(texts, ["**/x", "/a", "/b", "/gone"]);
+        assert_eq!(marks_list.selected, 2);
+    }
+
+    #[test]
+    fn text_input_is_valid_test() {
+        let input =
+            |purpose, text: &str| TextInput { purpose, text: text.into() };
+        assert!(input(InputPurpose::Search, "node_modules").is_valid());
+        assert!(input(InputPurpose::Search, "*.iso").is_valid());
+        assert!(!input(InputPurpose::Search, "[a").is_valid());
+        assert!(input(InputPurpose::PatternMark, "**/node_modules").is_valid());
+        assert!(!input(InputPurpose::PatternMark, "/").is_valid());
+        assert!(!input(InputPurpose::PatternMark, "/home/[a").is_valid());
+    }
+
     #[test]
     fn shorten_to_test() {
         let s = "123456789";
@@ -736,4 +2330,42 @@ mod tests {
         assert_eq!(shorten_to(s, 8), Cow::Owned::<str>("12...789".to_owned()));
         assert_eq!(shorten_to(s, 9), Cow::Borrowed(s));
     }
+
+    #[test]
+    fn details_text_test() {
+        let time = chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let mut details = EntryDetails {
+            max_size: 2048,
+            max_size_snapshot_hash: "aaaaaaaaaaaa".into(),
+            first_seen: time,
+            first_seen_snapshot_hash: "bbbbbbbbbbbb".into(),
+            last_seen: time,
+            last_seen_snapshot_hash: "cccccccccccc".into(),
+            unique_size: None,
+            metadata: FileMetadata::default(),
+        };
+        assert_eq!(
+            details_text(&details),
+            "max size: 2 KiB (aaaaaaa)\n\
+             first seen: 2023-11-14 (bbbbbbb)\n\
+             last seen: 2023-11-14 (ccccccc)\n"
+        );
+
+        details.metadata = FileMetadata {
+            file_type: Some(FileType::Symlink),
+            mode: Some(0o754),
+            uid: Some(1000),
+            inode: Some(42),
+            links: Some(1),
+            ..FileMetadata::default()
+        };
+        assert!(details_text(&details).ends_with(
+            "last seen: 2023-11-14 (ccccccc)\n\
+             type: symlink\n\
+             owner: 1000:?\n\
+             permissions: rwxr-xr--\n\
+             inode: 42\n\
+             links: 1\n"
+        ));
+    }
 }
@@ -1,3 +1,66 @@
+use anyhow::{anyhow, bail};
+use redu::restic::Snapshot;
+
 pub fn snapshot_short_id(id: &str) -> String {
     id.chars().take(7).collect::<String>()
 }
+
+/// Finds a snapshot by (a prefix of) its id, like restic does.
+/// `latest` is the most recent one.
+pub fn resolve_snapshot<'a>(
+    snapshots: &'a [Snapshot],
+    id: &str,
+) -> anyhow::Result<&'a Snapshot> {
+    if id == "latest" {
+        return snapshots
+            .iter()
+            .max_by_key(|snapshot| snapshot.time)
+            .ok_or_else(|| anyhow!("there are no snapshots"));
+    }
+    let mut matches =
+        snapshots.iter().filter(|snapshot| snapshot.id.starts_with(id));
+    match (matches.next(), matches.next()) {
+        (Some(snapshot), None) => Ok(snapshot),
+        (None, _) => bail!("no snapshot matches {id:?}"),
+        (Some(_), Some(_)) => bail!("more than one snapshot matches {id:?}"),
+    }
+}
+
+/// Tests //////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+
+    #[test]
+    fn resolve_snapshot_test() {
+        let mk_snapshot = |id: &str, year| Snapshot {
+            id: id.into(),
+            time: Utc.with_ymd_and_hms(year, 1, 1, 0, 0, 0).unwrap(),
+            parent: None,
+            tree: "tree".into(),
+            paths: Default::default(),
+            hostname: None,
+            username: None,
+            uid: None,
+            gid: None,
+            excludes: Default::default(),
+            tags: Default::default(),
+            original_id: None,
+            program_version: None,
+        };
+        let snapshots = [
+            mk_snapshot("abc123", 2023),
+            mk_snapshot("abd456", 2024),
+            mk_snapshot("ffe789", 2022),
+        ];
+        let id = |id| resolve_snapshot(&snapshots, id).map(|s| s.id.as_str());
+        assert_eq!(id("latest").unwrap(), "abd456");
+        assert_eq!(id("abc").unwrap(), "abc123");
+        assert_eq!(id("ffe789").unwrap(), "ffe789");
+        assert!(id("ab").is_err());
+        assert!(id("0").is_err());
+        assert!(resolve_snapshot(&[], "latest").is_err());
+    }
+}